@@ -22,9 +22,16 @@ then
       parameter="$parameter --proxy-user $USERNAME"
 fi
 
+# pass the password by env so it does not show up in ps
 if [ ! -z "$PASSWORD" ]
 then
-      parameter="$parameter --proxy-pass $PASSWORD"
+      export PROXY_PASS="$PASSWORD"
 fi
 
-socks5-forwarder $parameter
+if [ ! -z "$MODE" ]
+then
+      parameter="$parameter --mode $MODE"
+fi
+
+# extra arguments, e.g. `check --live` as a health check
+socks5-forwarder $parameter "$@"
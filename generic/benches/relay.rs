@@ -0,0 +1,127 @@
+//! Relay throughput of the tokio backend in each mode and of the io_uring backend.
+//!
+//! Run with `cargo bench --bench relay --features io-uring`, the io_uring backend is
+//! skipped without the feature.
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use socks5_forwarder::{Forwarder, RelayMode};
+
+const CONNECTIONS: usize = 8;
+const BYTES_PER_CONNECTION: usize = 128 * 1024 * 1024;
+const CHUNK: usize = 64 * 1024;
+
+fn main() {
+    let target = sink().expect("start sink failed");
+
+    for mode in [RelayMode::Userspace, RelayMode::Splice].iter() {
+        let listen = free_addr();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let forwarder = {
+            let _guard = runtime.enter();
+            Forwarder::builder()
+                .listen(listen.to_string())
+                .target(target.to_string())
+                .mode(*mode)
+                .build()
+                .expect("build forwarder failed")
+        };
+        let running = forwarder.clone();
+        runtime.spawn(async move { running.run().await });
+
+        report(&format!("tokio {}", mode), measure(listen));
+        forwarder.shutdown();
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    {
+        let listen = free_addr();
+        let forwarder = Forwarder::builder()
+            .listen(listen.to_string())
+            .target(target.to_string())
+            .build_uring()
+            .expect("build forwarder failed");
+        let running = forwarder.clone();
+        let handle = thread::spawn(move || running.run(0));
+
+        report("io_uring", measure(listen));
+        forwarder.shutdown();
+        let _ = handle.join();
+    }
+}
+
+/// Target which reads everything and closes after EOF.
+fn sink() -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || {
+        for conn in listener.incoming() {
+            let mut conn = match conn {
+                Ok(conn) => conn,
+                Err(_) => continue,
+            };
+            thread::spawn(move || {
+                let _ = io::copy(&mut conn, &mut io::sink());
+            });
+        }
+    });
+    Ok(addr)
+}
+
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .expect("pick a free port failed")
+}
+
+/// Push data through the forwarder on several connections, return the total time.
+fn measure(listen: SocketAddr) -> Duration {
+    let start = Instant::now();
+    let clients: Vec<_> = (0..CONNECTIONS)
+        .map(|_| thread::spawn(move || send(listen)))
+        .collect();
+    for client in clients {
+        client.join().unwrap().expect("relay failed");
+    }
+    start.elapsed()
+}
+
+fn send(listen: SocketAddr) -> io::Result<()> {
+    let mut conn = connect(listen)?;
+    let chunk = vec![0x5a; CHUNK];
+    for _ in 0..BYTES_PER_CONNECTION / CHUNK {
+        conn.write_all(&chunk)?;
+    }
+    conn.shutdown(Shutdown::Write)?;
+    // wait for the forwarder to pass the close of the sink back
+    let mut buf = [0; 1];
+    while conn.read(&mut buf)? > 0 {}
+    Ok(())
+}
+
+/// Connect, retrying while the forwarder is still starting.
+fn connect(addr: SocketAddr) -> io::Result<TcpStream> {
+    let mut attempts = 0;
+    loop {
+        match TcpStream::connect(addr) {
+            Ok(conn) => return Ok(conn),
+            Err(e) if attempts >= 50 => return Err(e),
+            Err(_) => {
+                attempts += 1;
+                thread::sleep(Duration::from_millis(20));
+            }
+        }
+    }
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let bytes = (CONNECTIONS * BYTES_PER_CONNECTION) as f64;
+    println!(
+        "{:<16} {:>8.2?} {:>8.2} Gbit/s",
+        name,
+        elapsed,
+        bytes * 8.0 / elapsed.as_secs_f64() / 1e9
+    );
+}
@@ -1,20 +1,21 @@
-use std::env;
-use std::path::{Path, PathBuf};
+fn main() {
+    #[cfg(feature = "ebpf")]
+    build_probes();
+}
 
-use cargo_bpf_lib as cargo_bpf;
+#[cfg(feature = "ebpf")]
+fn build_probes() {
+    use std::env;
+    use std::path::{Path, PathBuf};
+
+    use cargo_bpf_lib as cargo_bpf;
 
-fn main() {
     let cargo = PathBuf::from(env::var("CARGO").unwrap());
     let target = PathBuf::from(env::var("OUT_DIR").unwrap());
     let probes = Path::new("../probe");
 
-    cargo_bpf::build(
-        &cargo,
-        probes,
-        &target.join("target"),
-        Vec::new(),
-    )
-    .expect("couldn't compile probes");
+    cargo_bpf::build(&cargo, probes, &target.join("target"), Vec::new())
+        .expect("couldn't compile probes");
 
     cargo_bpf::probe_files(probes)
         .expect("couldn't list probe files")
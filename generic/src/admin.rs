@@ -0,0 +1,188 @@
+//! Admin HTTP API to inspect and control a running forwarder.
+//!
+//! Each request gets a JSON response and the connection is closed after it:
+//! - `GET /connections` lists live connections.
+//! - `DELETE /connections/{id}` closes a connection.
+//! - `GET /listeners` lists listen addresses and whether they are paused.
+//! - `POST /listeners/{addr}/pause` and `POST /listeners/{addr}/resume`.
+//! - `GET /config` dumps the settings, without secrets.
+//! - `GET /stats` returns totals since start, like fallback counts.
+use std::time::Duration;
+
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use crate::forwarder::Forwarder;
+
+const MAX_HEAD: usize = 8 * 1024;
+const MAX_HEADERS: usize = 32;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    async fn bind(addr: &str) -> anyhow::Result<Self> {
+        #[cfg(unix)]
+        if let Some(path) = addr.strip_prefix("unix:") {
+            use std::os::unix::fs::FileTypeExt;
+
+            // a socket left by a previous run would fail the bind
+            if let Ok(meta) = std::fs::symlink_metadata(path) {
+                if meta.file_type().is_socket() {
+                    std::fs::remove_file(path)?;
+                }
+            }
+            return Ok(Listener::Unix(tokio::net::UnixListener::bind(path)?));
+        }
+        Ok(Listener::Tcp(TcpListener::bind(addr).await?))
+    }
+
+    async fn accept(&self) -> std::io::Result<Box<dyn Stream>> {
+        match self {
+            Listener::Tcp(listener) => Ok(Box::new(listener.accept().await?.0)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => Ok(Box::new(listener.accept().await?.0)),
+        }
+    }
+}
+
+impl<R> Forwarder<R>
+where
+    R: Send + Sync + 'static,
+{
+    /// Serve the admin API at a TCP address, or a unix socket given as `unix:/path`,
+    /// until the forwarder shuts down.
+    ///
+    /// There is no authentication, so keep it on localhost or a protected unix socket.
+    pub async fn serve_admin(&self, addr: &str) -> anyhow::Result<()> {
+        let listener = Listener::bind(addr).await?;
+        tracing::info!("Admin API listening at {}", addr);
+        let mut shutdown = self.shutdown_signal();
+        loop {
+            if *shutdown.borrow() {
+                return Ok(());
+            }
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(mut conn) => {
+                        let forwarder = self.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = forwarder.handle_admin(&mut conn).await {
+                                tracing::warn!("Admin request failed: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => tracing::error!("Admin accept error: {}", e),
+                },
+                _ = shutdown.changed() => {}
+            }
+        }
+    }
+
+    async fn handle_admin(&self, conn: &mut Box<dyn Stream>) -> anyhow::Result<()> {
+        let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request(conn))
+            .await
+            .map_err(|_| anyhow::anyhow!("request timed out"))??;
+        let (status, body) = match request {
+            Some((method, path)) => self.route(&method, &path),
+            None => error("400 Bad Request", "bad request"),
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        conn.write_all(response.as_bytes()).await?;
+        conn.shutdown().await?;
+        Ok(())
+    }
+
+    fn route(&self, method: &str, path: &str) -> (&'static str, String) {
+        let path = path.split('?').next().unwrap_or_default();
+        let segments: Vec<_> = path.trim_matches('/').split('/').collect();
+        match (method, segments.as_slice()) {
+            ("GET", ["connections"]) => ok(&self.connections()),
+            ("DELETE", ["connections", id]) => match id.parse() {
+                Ok(id) if self.close_connection(id) => ok(&Closed { closed: id }),
+                _ => error("404 Not Found", "connection not found"),
+            },
+            ("GET", ["listeners"]) => ok(&self.listeners()),
+            ("POST", ["listeners", addr, action @ ("pause" | "resume")]) => {
+                let addr = percent_decode_str(addr).decode_utf8_lossy();
+                let found = if *action == "pause" {
+                    self.pause(&addr)
+                } else {
+                    self.resume(&addr)
+                };
+                if found {
+                    ok(&self.listeners())
+                } else {
+                    error("404 Not Found", "listener not found")
+                }
+            }
+            ("GET", ["config"]) => ok(self.config()),
+            ("GET", ["stats"]) => ok(&self.stats()),
+            _ => error("404 Not Found", "no such route"),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Closed {
+    closed: u64,
+}
+
+#[derive(Serialize)]
+struct Error<'a> {
+    error: &'a str,
+}
+
+fn ok<T: Serialize + ?Sized>(value: &T) -> (&'static str, String) {
+    match serde_json::to_string(value) {
+        Ok(body) => ("200 OK", body),
+        Err(e) => error("500 Internal Server Error", &e.to_string()),
+    }
+}
+
+fn error(status: &'static str, message: &str) -> (&'static str, String) {
+    let body = serde_json::to_string(&Error { error: message }).unwrap_or_default();
+    (status, body)
+}
+
+/// Method and path of the request, `None` if it is malformed. Bodies are ignored.
+async fn read_request(conn: &mut Box<dyn Stream>) -> anyhow::Result<Option<(String, String)>> {
+    let mut buf = Vec::with_capacity(1024);
+    loop {
+        if buf.len() >= MAX_HEAD {
+            return Ok(None);
+        }
+        let mut chunk = [0; 1024];
+        let n = conn.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("connection closed before the request head");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+        return match req.parse(&buf) {
+            Ok(httparse::Status::Complete(_)) => Ok(Some((
+                req.method.unwrap_or_default().to_string(),
+                req.path.unwrap_or_default().to_string(),
+            ))),
+            Ok(httparse::Status::Partial) => continue,
+            Err(_) => Ok(None),
+        };
+    }
+}
@@ -0,0 +1,317 @@
+//! In-process benchmark of a relay: a loopback listener forwards to a local target
+//! which sinks, sources or echoes data, driven from plain threads so the relay has
+//! its runtime to itself.
+use std::fmt::{self, Display};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CHUNK: usize = 64 * 1024;
+/// Size of each latency request and its echo.
+const REQUEST: usize = 64;
+
+/// First byte of each connection, telling the target what to do.
+const SINK: u8 = b'u';
+const SOURCE: u8 = b'd';
+const ECHO: u8 = b'e';
+
+/// Options of [`ForwarderBuilder::bench`](crate::ForwarderBuilder::bench).
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Parallel connections of each phase.
+    pub connections: usize,
+    /// Bytes sent, then received, on each connection.
+    pub bytes: usize,
+    /// Connections opened and closed in total to measure the setup rate.
+    pub setups: usize,
+    /// Round trips of a small request on each connection to measure latency.
+    pub requests: usize,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        Self {
+            connections: 8,
+            bytes: 64 * 1024 * 1024,
+            setups: 2000,
+            requests: 1000,
+        }
+    }
+}
+
+/// Results of one relay implementation.
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    pub name: String,
+    pub connections: usize,
+    /// Bytes moved in each direction, over all connections.
+    pub bytes: u64,
+    pub upload: Duration,
+    pub download: Duration,
+    pub setups: usize,
+    pub setup: Duration,
+    /// Round trip of each request, sorted.
+    pub latencies: Vec<Duration>,
+}
+
+impl BenchReport {
+    /// Column names matching the rows printed by `Display`.
+    pub fn header() -> String {
+        format!(
+            "{:<12} {:>5} {:>12} {:>12} {:>12} {:>10} {:>10}",
+            "relay", "conns", "upload", "download", "setup", "p50", "p99"
+        )
+    }
+
+    /// Bytes per second from client to target.
+    pub fn upload_rate(&self) -> f64 {
+        self.bytes as f64 / self.upload.as_secs_f64()
+    }
+
+    /// Bytes per second from target to client.
+    pub fn download_rate(&self) -> f64 {
+        self.bytes as f64 / self.download.as_secs_f64()
+    }
+
+    /// Connections set up per second.
+    pub fn setup_rate(&self) -> f64 {
+        self.setups as f64 / self.setup.as_secs_f64()
+    }
+
+    /// Latency at the percentile, from 0 to 100.
+    pub fn latency(&self, percentile: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::default();
+        }
+        let rank = (percentile / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+    }
+}
+
+impl Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mib = |rate: f64| format!("{:.1}MiB/s", rate / (1024.0 * 1024.0));
+        let us = |latency: Duration| format!("{:.1}us", latency.as_secs_f64() * 1e6);
+        write!(
+            f,
+            "{:<12} {:>5} {:>12} {:>12} {:>12} {:>10} {:>10}",
+            self.name,
+            self.connections,
+            mib(self.upload_rate()),
+            mib(self.download_rate()),
+            format!("{:.0}/s", self.setup_rate()),
+            us(self.latency(50.0)),
+            us(self.latency(99.0)),
+        )
+    }
+}
+
+/// Target of the benchmark, it stops accepting when dropped.
+pub(crate) struct Target {
+    addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Target {
+    pub(crate) fn start() -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = stopped.clone();
+        thread::spawn(move || {
+            for conn in listener.incoming() {
+                if stop.load(Ordering::Relaxed) {
+                    return;
+                }
+                if let Ok(conn) = conn {
+                    thread::spawn(move || serve(conn));
+                }
+            }
+        });
+        Ok(Self { addr, stopped })
+    }
+
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for Target {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        // wake up the accept loop
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+fn serve(mut conn: TcpStream) -> io::Result<()> {
+    conn.set_nodelay(true)?;
+    let mut kind = [0; 1];
+    conn.read_exact(&mut kind)?;
+    match kind[0] {
+        SINK => {
+            io::copy(&mut conn, &mut io::sink())?;
+        }
+        SOURCE => {
+            let mut len = [0; 8];
+            conn.read_exact(&mut len)?;
+            write_bytes(&mut conn, u64::from_be_bytes(len) as usize)?;
+        }
+        ECHO => {
+            let mut buf = [0; CHUNK];
+            loop {
+                let n = conn.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                conn.write_all(&buf[..n])?;
+            }
+        }
+        _ => {}
+    }
+    conn.shutdown(Shutdown::Write)
+}
+
+/// Drive the relay listening on `listen` and forwarding to a [`Target`].
+pub(crate) fn measure(
+    name: String,
+    listen: SocketAddr,
+    options: &BenchOptions,
+) -> anyhow::Result<BenchReport> {
+    let connections = options.connections.max(1);
+    drop(connect(listen)?);
+
+    let (bytes, setups, requests) = (options.bytes, options.setups, options.requests);
+
+    tracing::info!("Bench {}: upload", name);
+    let (upload, _) = parallel(connections, move |_| upload(listen, bytes))?;
+    tracing::info!("Bench {}: download", name);
+    let (download, _) = parallel(connections, move |_| download(listen, bytes))?;
+    tracing::info!("Bench {}: setup", name);
+    let (setup, _) = parallel(connections, move |i| {
+        // spread the setups over the connections
+        let count = setups / connections + usize::from(i < setups % connections);
+        (0..count).try_for_each(|_| setup(listen))
+    })?;
+    tracing::info!("Bench {}: latency", name);
+    let (_, latencies) = parallel(connections, move |_| latency(listen, requests))?;
+    let mut latencies: Vec<_> = latencies.into_iter().flatten().collect();
+    latencies.sort();
+
+    Ok(BenchReport {
+        name,
+        connections,
+        bytes: (connections * bytes) as u64,
+        upload,
+        download,
+        setups,
+        setup,
+        latencies,
+    })
+}
+
+/// Run `f` on `n` threads at once, returns the time until all finished.
+fn parallel<T, F>(n: usize, f: F) -> anyhow::Result<(Duration, Vec<T>)>
+where
+    T: Send + 'static,
+    F: Fn(usize) -> io::Result<T> + Send + Sync + 'static,
+{
+    let f = Arc::new(f);
+    let barrier = Arc::new(Barrier::new(n + 1));
+    let threads: Vec<_> = (0..n)
+        .map(|i| {
+            let (f, barrier) = (f.clone(), barrier.clone());
+            thread::spawn(move || {
+                barrier.wait();
+                f(i)
+            })
+        })
+        .collect();
+    barrier.wait();
+    let start = Instant::now();
+    let mut results = Vec::with_capacity(n);
+    for thread in threads {
+        let result = thread
+            .join()
+            .map_err(|_| anyhow::anyhow!("bench thread panicked"))?;
+        results.push(result?);
+    }
+    Ok((start.elapsed(), results))
+}
+
+fn upload(listen: SocketAddr, bytes: usize) -> io::Result<()> {
+    let mut conn = connect(listen)?;
+    conn.write_all(&[SINK])?;
+    write_bytes(&mut conn, bytes)?;
+    conn.shutdown(Shutdown::Write)?;
+    // wait for the close of the target to be passed back
+    let mut buf = [0; 1];
+    while conn.read(&mut buf)? > 0 {}
+    Ok(())
+}
+
+fn download(listen: SocketAddr, bytes: usize) -> io::Result<()> {
+    let mut conn = connect(listen)?;
+    conn.write_all(&[SOURCE])?;
+    conn.write_all(&(bytes as u64).to_be_bytes())?;
+    let received = io::copy(&mut conn, &mut io::sink())?;
+    if received != bytes as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("received {} of {} bytes", received, bytes),
+        ));
+    }
+    Ok(())
+}
+
+/// Connect and wait for the first byte relayed back, so the relay has dialed the target.
+fn setup(listen: SocketAddr) -> io::Result<()> {
+    let mut conn = TcpStream::connect(listen)?;
+    conn.set_nodelay(true)?;
+    conn.write_all(&[ECHO, 0])?;
+    conn.read_exact(&mut [0; 1])
+}
+
+fn latency(listen: SocketAddr, requests: usize) -> io::Result<Vec<Duration>> {
+    let mut conn = connect(listen)?;
+    conn.set_nodelay(true)?;
+    conn.write_all(&[ECHO])?;
+    let request = [0x5a; REQUEST];
+    let mut reply = [0; REQUEST];
+    let mut latencies = Vec::with_capacity(requests);
+    for _ in 0..requests {
+        let start = Instant::now();
+        conn.write_all(&request)?;
+        conn.read_exact(&mut reply)?;
+        latencies.push(start.elapsed());
+    }
+    Ok(latencies)
+}
+
+fn write_bytes(conn: &mut TcpStream, mut bytes: usize) -> io::Result<()> {
+    let chunk = [0x5a; CHUNK];
+    while bytes > 0 {
+        let n = bytes.min(CHUNK);
+        conn.write_all(&chunk[..n])?;
+        bytes -= n;
+    }
+    Ok(())
+}
+
+/// Connect, retrying while the relay is still starting.
+fn connect(addr: SocketAddr) -> io::Result<TcpStream> {
+    let mut attempts = 0;
+    loop {
+        match TcpStream::connect(addr) {
+            Ok(conn) => return Ok(conn),
+            Err(e) if attempts >= 50 => return Err(e),
+            Err(_) => {
+                attempts += 1;
+                thread::sleep(Duration::from_millis(20));
+            }
+        }
+    }
+}
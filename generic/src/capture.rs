@@ -0,0 +1,388 @@
+//! Record relayed streams into pcap files with synthesized TCP/IP framing, so they
+//! can be opened in Wireshark.
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::net::Cidr;
+
+/// Raw IPv4 or IPv6 packets without link layer.
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+const GLOBAL_HEADER_LEN: u64 = 24;
+/// Payload of one synthesized segment, small enough for an IPv4 packet.
+const MAX_SEGMENT: usize = 32 * 1024;
+/// Packets queued for the writer, more are dropped so the relay never waits for the disk.
+const QUEUE_SIZE: usize = 4096;
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+/// pcap capture settings.
+///
+/// Captured connections are relayed in userspace whatever the relay mode, since
+/// splice and eBPF never see the bytes.
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureConfig {
+    /// File to write, rotated files get `.1`, `.2`... before the extension.
+    pub path: PathBuf,
+    /// Only capture connections from these client networks, all when empty.
+    pub clients: Vec<Cidr>,
+    /// Only capture connections accepted on these listen addresses, all when empty.
+    pub listeners: Vec<String>,
+    /// Start a new file once one would grow past the size in bytes, never when `None`.
+    pub max_file_size: Option<u64>,
+}
+
+impl CaptureConfig {
+    /// Capture all connections into one file.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            clients: Vec::new(),
+            listeners: Vec::new(),
+            max_file_size: None,
+        }
+    }
+}
+
+/// Capture file shared by all connections, written by a dedicated thread.
+pub(crate) struct Capture {
+    config: CaptureConfig,
+    tx: SyncSender<Vec<u8>>,
+    dropped: AtomicU64,
+}
+
+impl Capture {
+    /// Create the first file and start the writer.
+    pub(crate) fn new(config: CaptureConfig) -> anyhow::Result<Self> {
+        if let Some(max) = config.max_file_size {
+            if max <= GLOBAL_HEADER_LEN {
+                anyhow::bail!("capture file size must be larger than the pcap header");
+            }
+        }
+        let writer = Writer::create(config.path.clone(), config.max_file_size)?;
+        let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+        std::thread::Builder::new()
+            .name("capture".to_string())
+            .spawn(move || writer.run(rx))?;
+        Ok(Self {
+            config,
+            tx,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    pub(crate) fn selects(&self, listen: &str, client: IpAddr) -> bool {
+        (self.config.clients.is_empty() || self.config.clients.iter().any(|c| c.contains(client)))
+            && (self.config.listeners.is_empty()
+                || self.config.listeners.iter().any(|l| l == listen))
+    }
+
+    /// Start the flow of a connection between the client and the server it is relayed to.
+    pub(crate) fn open(self: &Arc<Self>, client: SocketAddr, server: SocketAddr) -> Flow {
+        let (client, server) = match (client, server) {
+            (SocketAddr::V4(_), SocketAddr::V4(_)) => (client, server),
+            // one family per flow, IPv4 ends become mapped addresses
+            _ => (to_v6(client), to_v6(server)),
+        };
+        let (client_isn, server_isn) = (rand::random::<u32>(), rand::random::<u32>());
+        let flow = Flow {
+            capture: self.clone(),
+            client,
+            server,
+            state: Mutex::new(State {
+                seq: [client_isn.wrapping_add(1), server_isn.wrapping_add(1)],
+                fin: [false, false],
+            }),
+        };
+        flow.packet(Side::Client, SYN, client_isn, 0, &[]);
+        flow.packet(
+            Side::Server,
+            SYN | ACK,
+            server_isn,
+            client_isn.wrapping_add(1),
+            &[],
+        );
+        flow.packet(
+            Side::Client,
+            ACK,
+            client_isn.wrapping_add(1),
+            server_isn.wrapping_add(1),
+            &[],
+        );
+        flow
+    }
+
+    fn send(&self, record: Vec<u8>) {
+        if self.tx.try_send(record).is_err() && self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+            tracing::warn!("Capture can not keep up, dropping packets");
+        }
+    }
+}
+
+fn to_v6(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) => SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+        v6 => v6,
+    }
+}
+
+/// Sender of a segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Side {
+    Client,
+    Server,
+}
+
+impl Side {
+    fn index(self) -> usize {
+        match self {
+            Side::Client => 0,
+            Side::Server => 1,
+        }
+    }
+}
+
+struct State {
+    /// Next sequence number sent by the client and the server.
+    seq: [u32; 2],
+    fin: [bool; 2],
+}
+
+/// Synthesized TCP connection of one relayed connection, reset if dropped before
+/// both sides finished.
+pub(crate) struct Flow {
+    capture: Arc<Capture>,
+    client: SocketAddr,
+    server: SocketAddr,
+    state: Mutex<State>,
+}
+
+impl Flow {
+    pub(crate) fn data(&self, from: Side, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        for chunk in data.chunks(MAX_SEGMENT) {
+            let (seq, ack) = (state.seq[from.index()], state.seq[1 - from.index()]);
+            self.packet(from, PSH | ACK, seq, ack, chunk);
+            state.seq[from.index()] = seq.wrapping_add(chunk.len() as u32);
+        }
+    }
+
+    pub(crate) fn fin(&self, from: Side) {
+        let mut state = self.state.lock().unwrap();
+        if state.fin[from.index()] {
+            return;
+        }
+        let (seq, ack) = (state.seq[from.index()], state.seq[1 - from.index()]);
+        self.packet(from, FIN | ACK, seq, ack, &[]);
+        state.seq[from.index()] = seq.wrapping_add(1);
+        state.fin[from.index()] = true;
+    }
+
+    fn packet(&self, from: Side, flags: u8, seq: u32, ack: u32, payload: &[u8]) {
+        let (src, dst) = match from {
+            Side::Client => (self.client, self.server),
+            Side::Server => (self.server, self.client),
+        };
+        let packet = ip_packet(src, dst, &tcp_segment(src, dst, flags, seq, ack, payload));
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut record = Vec::with_capacity(16 + packet.len());
+        record.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&now.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&packet);
+        self.capture.send(record);
+    }
+}
+
+impl Drop for Flow {
+    fn drop(&mut self) {
+        let state = self.state.lock().unwrap();
+        if !state.fin.iter().all(|fin| *fin) {
+            let (seq, ack) = (state.seq[0], state.seq[1]);
+            self.packet(Side::Client, RST | ACK, seq, ack, &[]);
+        }
+    }
+}
+
+fn tcp_segment(
+    src: SocketAddr,
+    dst: SocketAddr,
+    flags: u8,
+    seq: u32,
+    ack: u32,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut segment = Vec::with_capacity(20 + payload.len());
+    segment.extend_from_slice(&src.port().to_be_bytes());
+    segment.extend_from_slice(&dst.port().to_be_bytes());
+    segment.extend_from_slice(&seq.to_be_bytes());
+    segment.extend_from_slice(&ack.to_be_bytes());
+    segment.push(5 << 4);
+    segment.push(flags);
+    segment.extend_from_slice(&u16::MAX.to_be_bytes());
+    segment.extend_from_slice(&[0, 0, 0, 0]);
+    segment.extend_from_slice(payload);
+
+    let len = segment.len() as u32;
+    let mut sum = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            checksum_add(checksum_add(0, &s.octets()), &d.octets()) + 6 + len
+        }
+        (IpAddr::V6(s), IpAddr::V6(d)) => {
+            checksum_add(checksum_add(0, &s.octets()), &d.octets())
+                + 6
+                + (len >> 16)
+                + (len & 0xffff)
+        }
+        _ => unreachable!("flow ends are of one family"),
+    };
+    sum = checksum_add(sum, &segment);
+    segment[16..18].copy_from_slice(&checksum_finish(sum).to_be_bytes());
+    segment
+}
+
+fn ip_packet(src: SocketAddr, dst: SocketAddr, segment: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(40 + segment.len());
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&((20 + segment.len()) as u16).to_be_bytes());
+            // id, don't fragment, ttl 64, tcp, checksum
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+            packet.extend_from_slice(&s.octets());
+            packet.extend_from_slice(&d.octets());
+            let sum = checksum_finish(checksum_add(0, &packet));
+            packet[10..12].copy_from_slice(&sum.to_be_bytes());
+        }
+        (IpAddr::V6(s), IpAddr::V6(d)) => {
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+            // tcp, hop limit 64
+            packet.extend_from_slice(&[6, 64]);
+            packet.extend_from_slice(&s.octets());
+            packet.extend_from_slice(&d.octets());
+        }
+        _ => unreachable!("flow ends are of one family"),
+    }
+    packet.extend_from_slice(segment);
+    packet
+}
+
+/// Add to the internet checksum, only the last data added may be of odd length.
+fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    if let [last] = words.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    // fold early so long payloads never overflow
+    (sum & 0xffff) + (sum >> 16)
+}
+
+fn checksum_finish(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+struct Writer {
+    path: PathBuf,
+    max_size: Option<u64>,
+    index: u32,
+    file: BufWriter<File>,
+    written: u64,
+}
+
+impl Writer {
+    fn create(path: PathBuf, max_size: Option<u64>) -> io::Result<Self> {
+        let file = open(&path)?;
+        Ok(Self {
+            path,
+            max_size,
+            index: 0,
+            file,
+            written: GLOBAL_HEADER_LEN,
+        })
+    }
+
+    fn run(mut self, rx: Receiver<Vec<u8>>) {
+        while let Ok(record) = rx.recv() {
+            let mut next = Some(record);
+            // write what is queued, then flush before waiting again
+            while let Some(record) = next.take() {
+                if let Err(e) = self.write(&record) {
+                    tracing::error!("Write capture {} failed: {}", self.path.display(), e);
+                    return;
+                }
+                match rx.try_recv() {
+                    Ok(record) => next = Some(record),
+                    Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => {}
+                }
+            }
+            if let Err(e) = self.file.flush() {
+                tracing::error!("Write capture {} failed: {}", self.path.display(), e);
+                return;
+            }
+        }
+    }
+
+    fn write(&mut self, record: &[u8]) -> io::Result<()> {
+        if let Some(max) = self.max_size {
+            if self.written > GLOBAL_HEADER_LEN && self.written + record.len() as u64 > max {
+                self.file.flush()?;
+                self.index += 1;
+                let path = rotated(&self.path, self.index);
+                tracing::info!("Rotate capture to {}", path.display());
+                self.file = open(&path)?;
+                self.written = GLOBAL_HEADER_LEN;
+            }
+        }
+        self.file.write_all(record)?;
+        self.written += record.len() as u64;
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> io::Result<BufWriter<File>> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&0xa1b2c3d4u32.to_le_bytes())?;
+    file.write_all(&2u16.to_le_bytes())?;
+    file.write_all(&4u16.to_le_bytes())?;
+    // timezone offset and timestamp accuracy
+    file.write_all(&[0; 8])?;
+    file.write_all(&SNAPLEN.to_le_bytes())?;
+    file.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+    file.flush()?;
+    Ok(file)
+}
+
+/// `capture.pcap` rotates to `capture.1.pcap`, `capture.2.pcap`...
+fn rotated(path: &Path, index: u32) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, index, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, index),
+    };
+    path.with_file_name(name)
+}
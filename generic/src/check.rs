@@ -0,0 +1,452 @@
+//! Step by step diagnostics of a configuration without serving clients: resolve the
+//! listen addresses, connect the proxy, handshake to the target and exchange a probe,
+//! each timed and reported.
+use std::borrow::Cow;
+use std::fmt::{self, Display};
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_socks::{IntoTargetAddr, TargetAddr};
+
+use crate::connect::{ConnectOptions, Dialer};
+use crate::dns::ResolveMode;
+use crate::mux::{self, MuxConfig};
+use crate::port_range;
+use crate::proxy::{ProxyConfig, ProxyProtocol};
+use crate::relay::Outbound;
+use crate::rules::{Route, RuleSet};
+use crate::socket::BindOptions;
+
+/// Options of [`ForwarderBuilder::check`](crate::ForwarderBuilder::check).
+#[derive(Debug, Clone)]
+pub struct CheckOptions {
+    /// Destination to check, the configured target when `None`.
+    pub target: Option<String>,
+    /// Sent to the target once connected.
+    pub probe: Option<Vec<u8>>,
+    /// Bytes the reply to the probe must contain, any reply or none will do when `None`.
+    pub expect: Option<Vec<u8>>,
+    /// Limit of each step.
+    pub timeout: Duration,
+    /// Also connect to the listen addresses, to health check a running forwarder.
+    pub live: bool,
+}
+
+impl Default for CheckOptions {
+    fn default() -> Self {
+        Self {
+            target: None,
+            probe: None,
+            expect: None,
+            timeout: Duration::from_secs(5),
+            live: false,
+        }
+    }
+}
+
+/// One timed step of a check.
+#[derive(Debug, Clone)]
+pub struct CheckStep {
+    pub name: String,
+    pub elapsed: Duration,
+    /// What was found, or why the step failed.
+    pub result: Result<String, String>,
+}
+
+/// Steps run by a check, it stops at the first failure.
+#[derive(Debug, Clone, Default)]
+pub struct CheckReport {
+    pub steps: Vec<CheckStep>,
+}
+
+impl CheckReport {
+    pub fn passed(&self) -> bool {
+        self.steps.iter().all(|step| step.result.is_ok())
+    }
+
+    async fn step<T, F, D>(
+        &mut self,
+        name: String,
+        timeout: Duration,
+        fut: F,
+        detail: D,
+    ) -> Option<T>
+    where
+        F: Future<Output = anyhow::Result<T>>,
+        D: FnOnce(&T) -> String,
+    {
+        let start = Instant::now();
+        let result = match tokio::time::timeout(timeout, fut).await {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(e)) => Err(format!("{:#}", e)),
+            Err(_) => Err(format!("timed out after {:?}", timeout)),
+        };
+        let (value, result) = match result {
+            Ok(value) => {
+                let detail = detail(&value);
+                (Some(value), Ok(detail))
+            }
+            Err(e) => (None, Err(e)),
+        };
+        self.steps.push(CheckStep {
+            name,
+            elapsed: start.elapsed(),
+            result,
+        });
+        value
+    }
+
+    fn fail(&mut self, name: String, error: String) {
+        self.steps.push(CheckStep {
+            name,
+            elapsed: Duration::default(),
+            result: Err(error),
+        });
+    }
+}
+
+impl Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for step in self.steps.iter() {
+            let ms = step.elapsed.as_secs_f64() * 1000.0;
+            match &step.result {
+                Ok(detail) => writeln!(f, "ok    {:>9.1}ms  {}: {}", ms, step.name, detail)?,
+                Err(e) => writeln!(f, "FAIL  {:>9.1}ms  {}: {}", ms, step.name, e)?,
+            }
+        }
+        if self.passed() {
+            write!(f, "check passed")
+        } else {
+            write!(f, "check failed")
+        }
+    }
+}
+
+/// Settings of the forwarder under check.
+pub(crate) struct Checked<'a> {
+    pub(crate) listen: &'a [String],
+    pub(crate) target: Option<&'a str>,
+    pub(crate) proxy: Option<&'a ProxyConfig>,
+    pub(crate) mux: Option<&'a MuxConfig>,
+    pub(crate) rules: Option<&'a RuleSet>,
+    pub(crate) connect: ConnectOptions,
+}
+
+pub(crate) async fn run(checked: Checked<'_>, options: &CheckOptions) -> CheckReport {
+    let mut report = CheckReport::default();
+    if let Some(Err(e)) = checked.rules.map(RuleSet::check) {
+        report.fail("rules".to_string(), format!("{:#}", e));
+        return report;
+    }
+    if check_listeners(&mut report, checked.listen, options)
+        .await
+        .is_none()
+    {
+        return report;
+    }
+    let dialer = match Dialer::new(checked.connect.clone()) {
+        Ok(dialer) => dialer,
+        Err(e) => {
+            report.fail("outbound options".to_string(), format!("{:#}", e));
+            return report;
+        }
+    };
+
+    let target = match options.target.as_deref() {
+        Some(target) => Some(target.to_string()),
+        None => match checked.target {
+            // the target of the first listen port
+            Some(target) => match port_range::map_target(checked.listen, target) {
+                Ok((target, _)) => Some(target),
+                Err(e) => {
+                    report.fail(format!("target {}", target), format!("{:#}", e));
+                    return report;
+                }
+            },
+            None => None,
+        },
+    };
+    let target = match target.as_deref().map(IntoTargetAddr::into_target_addr) {
+        Some(Ok(target)) => Some(target),
+        Some(Err(e)) => {
+            report.fail("target".to_string(), e.to_string());
+            return report;
+        }
+        None => None,
+    };
+
+    let mut proxy = checked.proxy.map(Cow::Borrowed);
+    let mut bind = BindOptions::default();
+    if let (Some(rules), Some(target)) = (checked.rules, target.as_ref()) {
+        let client = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let (rule, route) = rules.route(target, client, || dialer.resolve(target)).await;
+        let rule = match rule {
+            Some(i) => format!("rule {}", i + 1),
+            None => "no rule".to_string(),
+        };
+        let name = format!("route {}", target);
+        match route {
+            Route::Direct(options) => {
+                report
+                    .steps
+                    .push(matched(name, format!("{} matched, direct", rule)));
+                bind = options;
+            }
+            Route::Proxy(p) => {
+                report.steps.push(matched(
+                    name,
+                    format!("{} matched, proxy {}", rule, p.address),
+                ));
+                proxy = Some(p);
+            }
+            Route::Reject => {
+                report.fail(name, format!("{} matched, rejected", rule));
+                return report;
+            }
+        }
+    }
+
+    let outbound = match (proxy.as_deref(), checked.mux) {
+        (_, Some(mux)) => {
+            // streams are only checked as far as the peer handshake
+            if let Some(mut stream) =
+                check_connect(&mut report, &dialer, "mux peer", &mux.peer, &bind, options).await
+            {
+                report
+                    .step(
+                        "mux handshake".to_string(),
+                        options.timeout,
+                        mux::authenticate(&mut stream, &mux.secret),
+                        |_| "secret accepted".to_string(),
+                    )
+                    .await;
+            }
+            return report;
+        }
+        (Some(proxy), None) => {
+            let address = &proxy.address;
+            let stream =
+                match check_connect(&mut report, &dialer, "proxy", address, &proxy.bind, options)
+                    .await
+                {
+                    Some(stream) => stream,
+                    None => return report,
+                };
+            let mut target = match target {
+                Some(target) => target,
+                None => return report,
+            };
+            if proxy.resolve.unwrap_or(checked.connect.resolve) == ResolveMode::Local {
+                let resolved = report
+                    .step(
+                        format!("resolve target {}", target),
+                        options.timeout,
+                        dialer.resolve(&target),
+                        |addrs| format_addrs(addrs),
+                    )
+                    .await;
+                match resolved {
+                    Some(addrs) => target = TargetAddr::Ip(addrs[0]),
+                    None => return report,
+                }
+            }
+            let protocol = match proxy.protocol {
+                ProxyProtocol::Socks5 => "socks5",
+                ProxyProtocol::Shadowsocks { .. } => "shadowsocks",
+            };
+            report
+                .step(
+                    format!("{} handshake to {}", protocol, target),
+                    options.timeout,
+                    dialer.handshake(proxy, stream, target.clone()),
+                    |_| match proxy.protocol {
+                        ProxyProtocol::Socks5 => "connected".to_string(),
+                        // the server stays silent until data arrives
+                        ProxyProtocol::Shadowsocks { .. } => "request sent".to_string(),
+                    },
+                )
+                .await
+        }
+        (None, None) => match target {
+            Some(target) => {
+                let addrs = report
+                    .step(
+                        format!("resolve target {}", target),
+                        options.timeout,
+                        dialer.resolve(&target),
+                        |addrs| format_addrs(addrs),
+                    )
+                    .await;
+                if addrs.is_none() {
+                    return report;
+                }
+                report
+                    .step(
+                        format!("connect target {}", target),
+                        options.timeout,
+                        dialer.dial(&target, &bind),
+                        peer,
+                    )
+                    .await
+                    .map(Outbound::from)
+            }
+            None => None,
+        },
+    };
+
+    if let (Some(outbound), Some(probe)) = (outbound, options.probe.as_ref()) {
+        report
+            .step(
+                format!("probe {} bytes", probe.len()),
+                options.timeout,
+                exchange(outbound, probe, options),
+                |reply| match reply {
+                    Some(n) => format!("{} bytes received", n),
+                    None => "no reply".to_string(),
+                },
+            )
+            .await;
+    }
+    report
+}
+
+fn matched(name: String, detail: String) -> CheckStep {
+    CheckStep {
+        name,
+        elapsed: Duration::default(),
+        result: Ok(detail),
+    }
+}
+
+async fn check_listeners(
+    report: &mut CheckReport,
+    listen: &[String],
+    options: &CheckOptions,
+) -> Option<()> {
+    for spec in listen {
+        // the first port stands for a range
+        let addr = match port_range::expand(std::slice::from_ref(spec)) {
+            Ok(addrs) => addrs.into_iter().next()?,
+            Err(e) => {
+                report.fail(format!("listen {}", spec), format!("{:#}", e));
+                return None;
+            }
+        };
+        let addrs = report
+            .step(
+                format!("resolve listen {}", addr),
+                options.timeout,
+                async {
+                    Ok(tokio::net::lookup_host(addr.as_str())
+                        .await?
+                        .collect::<Vec<_>>())
+                },
+                |addrs| format_addrs(addrs),
+            )
+            .await?;
+        if options.live {
+            let first = *addrs.first()?;
+            report
+                .step(
+                    format!("connect listener {}", addr),
+                    options.timeout,
+                    async { Ok(TcpStream::connect(first).await?) },
+                    |_| "accepting".to_string(),
+                )
+                .await?;
+        }
+    }
+    Some(())
+}
+
+async fn check_connect(
+    report: &mut CheckReport,
+    dialer: &Dialer,
+    what: &str,
+    addr: &str,
+    bind: &BindOptions,
+    options: &CheckOptions,
+) -> Option<TcpStream> {
+    let target = match addr.into_target_addr() {
+        Ok(target) => target,
+        Err(e) => {
+            report.fail(format!("{} {}", what, addr), e.to_string());
+            return None;
+        }
+    };
+    report
+        .step(
+            format!("resolve {} {}", what, addr),
+            options.timeout,
+            dialer.resolve(&target),
+            |addrs| format_addrs(addrs),
+        )
+        .await?;
+    report
+        .step(
+            format!("connect {} {}", what, addr),
+            options.timeout,
+            dialer.dial(&target, bind),
+            peer,
+        )
+        .await
+}
+
+/// Send the probe and read the reply, returns the bytes received or `None` when
+/// the target stayed silent and nothing is expected.
+async fn exchange(
+    mut outbound: Outbound,
+    probe: &[u8],
+    options: &CheckOptions,
+) -> anyhow::Result<Option<usize>> {
+    outbound.write_all(probe).await?;
+    outbound.flush().await?;
+    let mut reply = Vec::new();
+    let mut buf = [0; 4096];
+    // leave some of the step limit to report a missing reply
+    let read = tokio::time::timeout(options.timeout.mul_f32(0.9), async {
+        loop {
+            let n = outbound.read(&mut buf).await?;
+            reply.extend_from_slice(&buf[..n]);
+            let done = match options.expect.as_ref() {
+                Some(expect) => contains(&reply, expect),
+                None => true,
+            };
+            if n == 0 || done {
+                return Ok::<_, std::io::Error>(());
+            }
+        }
+    })
+    .await;
+    if let Ok(result) = read {
+        result?;
+    }
+    match options.expect.as_ref() {
+        Some(expect) if !contains(&reply, expect) => anyhow::bail!(
+            "reply of {} bytes does not contain the expected {} bytes",
+            reply.len(),
+            expect.len()
+        ),
+        _ if reply.is_empty() => Ok(None),
+        _ => Ok(Some(reply.len())),
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty() || haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+fn format_addrs(addrs: &[SocketAddr]) -> String {
+    let addrs: Vec<_> = addrs.iter().map(ToString::to_string).collect();
+    addrs.join(", ")
+}
+
+fn peer(stream: &TcpStream) -> String {
+    match stream.peer_addr() {
+        Ok(addr) => format!("connected to {}", addr),
+        Err(_) => "connected".to_string(),
+    }
+}
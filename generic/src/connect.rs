@@ -0,0 +1,179 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::net::TcpStream;
+use tokio_socks::tcp::Socks5Stream;
+use tokio_socks::{IntoTargetAddr, TargetAddr};
+
+use crate::dns::{DnsConfig, ResolveMode, Resolver};
+use crate::happy_eyeballs;
+use crate::proxy::{ProxyConfig, ProxyProtocol};
+use crate::relay::Outbound;
+use crate::retry::RetryPolicy;
+use crate::shadowsocks::ShadowsocksStream;
+use crate::socket::{BindOptions, SocketOptions};
+
+/// Options for outbound connections.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectOptions {
+    pub socket: SocketOptions,
+    /// Where the target is resolved when relaying through a proxy.
+    pub resolve: ResolveMode,
+    pub dns: DnsConfig,
+    /// Delay between Happy Eyeballs connection attempts when a host has several addresses.
+    pub attempt_delay: Duration,
+    pub retry: RetryPolicy,
+    pub bind: BindOptions,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            socket: SocketOptions::with_default_keepalive(),
+            resolve: ResolveMode::Remote,
+            dns: DnsConfig::default(),
+            attempt_delay: happy_eyeballs::DEFAULT_ATTEMPT_DELAY,
+            retry: RetryPolicy::default(),
+            bind: BindOptions::default(),
+        }
+    }
+}
+
+/// Resolves and dials outbound connections.
+pub(crate) struct Dialer {
+    options: ConnectOptions,
+    resolver: Resolver,
+}
+
+impl Dialer {
+    pub(crate) fn new(options: ConnectOptions) -> anyhow::Result<Self> {
+        options.bind.check()?;
+        options.socket.check()?;
+        let resolver = Resolver::new(&options.dns)?;
+        Ok(Self { options, resolver })
+    }
+
+    /// Connect to the target without proxy, retried as the policy says.
+    pub(crate) async fn connect<'a>(
+        &self,
+        target: impl IntoTargetAddr<'a>,
+    ) -> anyhow::Result<TcpStream> {
+        self.connect_from(target, &BindOptions::default()).await
+    }
+
+    /// Connect like [`connect`](Self::connect), with the source binding options set
+    /// in `bind` taking the place of the outbound ones.
+    pub(crate) async fn connect_from<'a>(
+        &self,
+        target: impl IntoTargetAddr<'a>,
+        bind: &BindOptions,
+    ) -> anyhow::Result<TcpStream> {
+        let target = target.into_target_addr()?;
+        self.options.retry.run(|| self.dial(&target, bind)).await
+    }
+
+    /// Connect to the target through the proxy, return the stream after handshake.
+    ///
+    /// When the target is resolved locally, its addresses are asked for in turn until
+    /// the proxy connects one. Both connecting the proxy and the handshake are retried
+    /// as the policy says.
+    pub(crate) async fn connect_proxy<'a>(
+        &self,
+        proxy: &ProxyConfig,
+        target: impl IntoTargetAddr<'a>,
+    ) -> anyhow::Result<Outbound> {
+        let target = target.into_target_addr()?;
+        let targets = match proxy.resolve.unwrap_or(self.options.resolve) {
+            ResolveMode::Local => {
+                let addrs = self.resolver.resolve(&target).await?;
+                happy_eyeballs::interleave(&addrs)
+                    .into_iter()
+                    .map(TargetAddr::Ip)
+                    .collect()
+            }
+            ResolveMode::Remote => vec![target],
+        };
+        let proxy_addr = proxy.address.as_str().into_target_addr()?;
+
+        self.options
+            .retry
+            .run(|| self.dial_proxy(proxy, &proxy_addr, &targets))
+            .await
+    }
+
+    pub(crate) async fn resolve(&self, target: &TargetAddr<'_>) -> anyhow::Result<Vec<SocketAddr>> {
+        self.resolver.resolve(target).await
+    }
+
+    /// Connect once, without retry. Options set in `bind` take the place of the
+    /// outbound ones.
+    pub(crate) async fn dial(
+        &self,
+        target: &TargetAddr<'_>,
+        bind: &BindOptions,
+    ) -> anyhow::Result<TcpStream> {
+        let bind = bind.or(&self.options.bind);
+        let mut addrs = self.resolver.resolve(target).await?;
+        addrs.retain(|addr| bind.accepts(addr));
+        if addrs.is_empty() {
+            anyhow::bail!("no address of {} matches the bind address family", target);
+        }
+        let stream = happy_eyeballs::connect(
+            &addrs,
+            self.options.attempt_delay,
+            &bind,
+            &self.options.socket,
+        )
+        .await?;
+        Ok(stream)
+    }
+
+    async fn dial_proxy(
+        &self,
+        proxy: &ProxyConfig,
+        proxy_addr: &TargetAddr<'_>,
+        targets: &[TargetAddr<'_>],
+    ) -> anyhow::Result<Outbound> {
+        let mut last_err = None;
+        for target in targets {
+            // a proxy which can not be reached fails every address
+            let proxy_stream = self.dial(proxy_addr, &proxy.bind).await?;
+            match self.handshake(proxy, proxy_stream, target.clone()).await {
+                Ok(outbound) => return Ok(outbound),
+                Err(e) => {
+                    tracing::debug!("Proxy connect {} failed: {:#}", target, e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| anyhow::anyhow!("no address to connect")))
+    }
+
+    /// Ask the proxy connected by `proxy_stream` for the target.
+    pub(crate) async fn handshake(
+        &self,
+        proxy: &ProxyConfig,
+        proxy_stream: TcpStream,
+        target: TargetAddr<'_>,
+    ) -> anyhow::Result<Outbound> {
+        if let ProxyProtocol::Shadowsocks { cipher, password } = &proxy.protocol {
+            let outbound =
+                ShadowsocksStream::connect(proxy_stream, *cipher, password, &target).await?;
+            return Ok(outbound.into());
+        }
+        let outbound = match proxy.credential.as_ref() {
+            None => Socks5Stream::connect_with_socket(proxy_stream, target).await?,
+            Some(credential) => {
+                Socks5Stream::connect_with_password_and_socket(
+                    proxy_stream,
+                    target,
+                    &credential.username,
+                    credential.password.expose(),
+                )
+                .await?
+            }
+        };
+        Ok(outbound.into_inner().into())
+    }
+}
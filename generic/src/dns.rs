@@ -0,0 +1,123 @@
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio_socks::TargetAddr;
+use trust_dns_resolver::config::{
+    LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts,
+};
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Where the target hostname is resolved when relaying through a proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResolveMode {
+    /// Resolve in this process and send the IP to the proxy.
+    Local,
+    /// Send the hostname to the proxy and let it resolve.
+    Remote,
+}
+
+impl FromStr for ResolveMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => Ok(ResolveMode::Local),
+            "remote" => Ok(ResolveMode::Remote),
+            _ => Err(anyhow::anyhow!("unknown resolve mode {}", s)),
+        }
+    }
+}
+
+impl Display for ResolveMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResolveMode::Local => f.write_str("local"),
+            ResolveMode::Remote => f.write_str("remote"),
+        }
+    }
+}
+
+/// DNS resolver and cache settings.
+#[derive(Debug, Clone, Serialize)]
+pub struct DnsConfig {
+    /// Nameservers to query, the system configuration is used when empty.
+    pub nameservers: Vec<SocketAddr>,
+    /// Max cached lookups, records are kept for their TTL.
+    pub cache_size: usize,
+    /// How long a failed lookup is cached.
+    pub negative_ttl: Duration,
+    /// Lower bound of record TTL.
+    pub min_ttl: Option<Duration>,
+    /// Upper bound of record TTL.
+    pub max_ttl: Option<Duration>,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            nameservers: Vec::new(),
+            cache_size: 1024,
+            negative_ttl: Duration::from_secs(5),
+            min_ttl: None,
+            max_ttl: None,
+        }
+    }
+}
+
+/// Caching resolver shared by all connections of a forwarder.
+#[derive(Clone)]
+pub(crate) struct Resolver {
+    inner: TokioAsyncResolver,
+}
+
+impl Resolver {
+    pub(crate) fn new(config: &DnsConfig) -> anyhow::Result<Self> {
+        let (resolver_config, mut opts) = if config.nameservers.is_empty() {
+            trust_dns_resolver::system_conf::read_system_conf()?
+        } else {
+            let mut resolver_config = ResolverConfig::new();
+            for addr in config.nameservers.iter() {
+                for protocol in [Protocol::Udp, Protocol::Tcp].iter() {
+                    resolver_config.add_name_server(NameServerConfig {
+                        socket_addr: *addr,
+                        protocol: *protocol,
+                        tls_dns_name: None,
+                        trust_nx_responses: true,
+                    });
+                }
+            }
+            (resolver_config, ResolverOpts::default())
+        };
+        opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        opts.cache_size = config.cache_size;
+        opts.negative_min_ttl = Some(config.negative_ttl);
+        opts.negative_max_ttl = Some(config.negative_ttl);
+        opts.positive_min_ttl = config.min_ttl;
+        opts.positive_max_ttl = config.max_ttl;
+
+        let inner = TokioAsyncResolver::tokio(resolver_config, opts)?;
+        Ok(Self { inner })
+    }
+
+    /// Resolve target to socket addresses, IP targets are returned as is.
+    pub(crate) async fn resolve(&self, target: &TargetAddr<'_>) -> anyhow::Result<Vec<SocketAddr>> {
+        let (host, port) = match target {
+            TargetAddr::Ip(addr) => return Ok(vec![*addr]),
+            TargetAddr::Domain(host, port) => (host, *port),
+        };
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+
+        let lookup = self.inner.lookup_ip(host.as_ref()).await?;
+        let addrs: Vec<_> = lookup.iter().map(|ip| SocketAddr::new(ip, port)).collect();
+        if addrs.is_empty() {
+            anyhow::bail!("no address found for {}", host);
+        }
+        Ok(addrs)
+    }
+}
@@ -0,0 +1,166 @@
+//! eBPF sockmap accelerator, established IPv4 connections are redirected in kernel.
+use std::net::SocketAddr::{self, V4};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::prelude::RawFd;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use once_cell::sync::OnceCell;
+use probe::{IdxMapKey, MAPPING_CAPACITY};
+use redbpf::{load::Loader, HashMap, SockMap};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use shared::{BPFOperator, Shared};
+
+mod shared;
+
+pub(crate) type SharedMaps = &'static Mutex<Shared<'static, IdxMapKey>>;
+
+static MAPS: OnceCell<Mutex<Shared<'static, IdxMapKey>>> = OnceCell::new();
+
+/// Check privilege, load the probes and attach them to the sockmap.
+///
+/// The probes are loaded once per process, later calls share the same maps.
+pub(crate) fn load() -> anyhow::Result<SharedMaps> {
+    MAPS.get_or_try_init(|| {
+        if unsafe { libc::geteuid() != 0 } {
+            anyhow::bail!("you must be root to use eBPF");
+        }
+
+        let loaded = Loader::load(include_bytes!(concat!(
+            env!("OUT_DIR"),
+            "/target/bpf/programs/probes/probes.elf"
+        )))
+        .map_err(|e| anyhow!("error loading BPF program: {:?}", e))?;
+        // the maps borrow from it for the rest of the process
+        let loaded_leak = Box::leak(Box::new(loaded));
+        let sockmap = SockMap::new(
+            loaded_leak
+                .map("sockmap")
+                .ok_or_else(|| anyhow!("sockmap not found"))?,
+        )
+        .map_err(|e| anyhow!("error creating sockmap: {:?}", e))?;
+        let idx_map = HashMap::<IdxMapKey, u32>::new(
+            loaded_leak
+                .map("idx_map")
+                .ok_or_else(|| anyhow!("idx map not found"))?,
+        )
+        .map_err(|e| anyhow!("error creating idx map: {:?}", e))?;
+        loaded_leak
+            .stream_parsers()
+            .next()
+            .ok_or_else(|| anyhow!("stream parser not found"))?
+            .attach_sockmap(&sockmap)
+            .map_err(|e| anyhow!("attaching sockmap to stream parsers failed: {:?}", e))?;
+        loaded_leak
+            .stream_verdicts()
+            .next()
+            .ok_or_else(|| anyhow!("stream verdict not found"))?
+            .attach_sockmap(&sockmap)
+            .map_err(|e| anyhow!("attaching sockmap to stream verdicts failed: {:?}", e))?;
+        Ok(Mutex::new(Shared::new(sockmap, idx_map, MAPPING_CAPACITY)))
+    })
+}
+
+/// Relay between two connected sockets with sockmap redirection.
+pub(crate) async fn relay(
+    bpf: SharedMaps,
+    mut inbound: TcpStream,
+    mut outbound: TcpStream,
+) -> anyhow::Result<()> {
+    // get inbound and outbound address and fd
+    let (inbound_fd, inbound_addr) = (inbound.as_raw_fd(), inbound.peer_addr()?);
+    let (outbound_fd, outbound_addr) = (outbound.as_raw_fd(), outbound.local_addr()?);
+
+    let (read_half, write_half) = inbound.split();
+    let in_info = ConnInfo {
+        fd: inbound_fd,
+        addr: inbound_addr,
+        read_half,
+        write_half,
+    };
+
+    let (read_half, write_half) = outbound.split();
+    let out_info = ConnInfo {
+        fd: outbound_fd,
+        addr: outbound_addr,
+        read_half,
+        write_half,
+    };
+
+    bpf_relay(bpf, in_info, out_info).await
+}
+
+struct ConnInfo<R, W> {
+    fd: RawFd,
+    addr: SocketAddr,
+    read_half: R,
+    write_half: W,
+}
+
+async fn bpf_relay<O, IR, IW, OR, OW>(
+    bpf: &Mutex<O>,
+    in_conn_info: ConnInfo<IR, IW>,
+    out_conn_info: ConnInfo<OR, OW>,
+) -> anyhow::Result<()>
+where
+    O: BPFOperator<K = IdxMapKey>,
+    IR: AsyncRead + Unpin,
+    IW: AsyncWrite + Unpin,
+    OR: AsyncRead + Unpin,
+    OW: AsyncWrite + Unpin,
+{
+    // used for delete from idx_map and sockmap
+    let mut inbound_addr_opt = None;
+    let mut outbound_addr_opt = None;
+
+    // add socket and key to idx_map and sockmap for ipv4
+    // Note: Local port is stored in host byte order while remote port is in network byte order.
+    // https://github.com/torvalds/linux/blob/v5.10/include/uapi/linux/bpf.h#L4110
+    if let (V4(in_addr), V4(out_addr)) = (in_conn_info.addr, out_conn_info.addr) {
+        let inbound_addr = IdxMapKey {
+            addr: u32::to_be(u32::from(in_addr.ip().to_owned())),
+            port: u32::to_be(in_addr.port().into()),
+        };
+        let outbound_addr = IdxMapKey {
+            addr: u32::to_be(u32::from(out_addr.ip().to_owned())),
+            port: out_addr.port().into(),
+        };
+        inbound_addr_opt = Some(inbound_addr);
+        outbound_addr_opt = Some(outbound_addr);
+        let mut guard = bpf.lock().unwrap();
+        let _ = guard.add(out_conn_info.fd, inbound_addr);
+        let _ = guard.add(in_conn_info.fd, outbound_addr);
+    }
+
+    // block on copy data
+    // Note: Here we copy bidirectional manually, remove from map ASAP to
+    // avoid outbound port reuse and packet mis-redirected.
+    tracing::info!("Relay started");
+
+    let (mut ri, mut wi) = (in_conn_info.read_half, in_conn_info.write_half);
+    let (mut ro, mut wo) = (out_conn_info.read_half, out_conn_info.write_half);
+    let client_to_server = async {
+        let _ = tokio::io::copy(&mut ri, &mut wo).await;
+        tracing::info!("Relay inbound -> outbound finished");
+        let _ = wo.shutdown().await;
+        if let Some(addr) = inbound_addr_opt {
+            let _ = bpf.lock().unwrap().delete(addr);
+        }
+    };
+
+    let server_to_client = async {
+        let _ = tokio::io::copy(&mut ro, &mut wi).await;
+        tracing::info!("Relay outbound -> inbound finished");
+        let _ = wi.shutdown().await;
+        if let Some(addr) = outbound_addr_opt {
+            let _ = bpf.lock().unwrap().delete(addr);
+        }
+    };
+
+    tokio::join!(client_to_server, server_to_client);
+    tracing::info!("Relay finished");
+
+    Ok::<(), anyhow::Error>(())
+}
@@ -0,0 +1,777 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::{AbortHandle, Abortable, Aborted};
+use serde::Serialize;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tracing::field::Empty;
+use tracing::Instrument;
+
+use crate::bench::{self, BenchOptions, BenchReport};
+use crate::capture::{Capture, CaptureConfig};
+use crate::check::{self, CheckOptions, CheckReport, Checked};
+use crate::connect::{ConnectOptions, Dialer};
+use crate::dns::{DnsConfig, ResolveMode};
+use crate::inbound::{HttpServer, HttpServerConfig, MuxServer, SocksServer, SocksServerConfig};
+use crate::mux::{MuxConfig, MuxPool};
+use crate::port_range;
+use crate::proxy::{Fallback, ProxyConfig, ProxyProtocol, Secret};
+use crate::registry::{self, ConnectionInfo, Registry, Stats};
+use crate::relay::{BoxRelay, DirectRelay, MuxRelay, ProxiedRelay, Relay, RelayMode};
+use crate::retry::RetryPolicy;
+use crate::rules::RuleSet;
+use crate::socket::{BindOptions, Keepalive, ListenOptions, SocketOptions};
+use crate::tap::{Tap, TapConfig};
+use crate::tunnel::{Hub, TunnelRelay, TunnelServerConfig};
+
+/// What the built-in relay expects from clients.
+#[derive(Clone)]
+enum Inbound {
+    /// Plain TCP forwarded to the fixed target.
+    Forward,
+    Socks(SocksServerConfig),
+    Http(HttpServerConfig),
+    /// Mux sessions from peer forwarders knowing the secret.
+    Mux(Secret),
+}
+
+/// Settings a forwarder was built with, secrets are left out.
+#[derive(Debug, Clone, Serialize)]
+pub struct ForwarderConfig {
+    pub listen: Vec<String>,
+    /// `forward`, `socks`, `http`, `mux`, or `custom` for a relay given to
+    /// [`ForwarderBuilder::build_with_relay`].
+    pub inbound: &'static str,
+    /// Whether socks, http or mux clients must authenticate.
+    pub inbound_auth: bool,
+    pub target: Option<String>,
+    /// Address of the upstream proxy.
+    pub proxy: Option<String>,
+    /// `socks5` or `shadowsocks`.
+    pub proxy_protocol: Option<&'static str>,
+    pub proxy_fallback: Option<Fallback>,
+    pub proxy_auth: bool,
+    /// Peer streams are multiplexed to.
+    pub mux: Option<MuxConfig>,
+    /// Number of routing rules, `None` without a rule set.
+    pub rules: Option<usize>,
+    /// Relay mode asked for, the one in use may differ after fallback.
+    pub mode: RelayMode,
+    pub listen_options: ListenOptions,
+    pub connect: ConnectOptions,
+    pub tap: Option<TapConfig>,
+    pub capture: Option<CaptureConfig>,
+    /// Address agents of the reverse tunnel connect to.
+    pub tunnel_listen: Option<String>,
+}
+
+/// State of one listen address.
+#[derive(Debug, Clone, Serialize)]
+pub struct ListenerInfo {
+    pub address: String,
+    pub paused: bool,
+}
+
+/// Builder for [`Forwarder`].
+#[derive(Clone)]
+pub struct ForwarderBuilder {
+    listen_addrs: Vec<String>,
+    inbound: Inbound,
+    target_addr: Option<String>,
+    proxy: Option<ProxyConfig>,
+    options: ConnectOptions,
+    listen_options: ListenOptions,
+    mode: RelayMode,
+    tap: Option<TapConfig>,
+    capture: Option<CaptureConfig>,
+    tunnel: Option<TunnelServerConfig>,
+    mux: Option<MuxConfig>,
+    rules: Option<RuleSet>,
+}
+
+impl Default for ForwarderBuilder {
+    fn default() -> Self {
+        Self {
+            listen_addrs: Vec::new(),
+            inbound: Inbound::Forward,
+            target_addr: None,
+            proxy: None,
+            options: ConnectOptions::default(),
+            listen_options: ListenOptions::default(),
+            mode: RelayMode::Auto,
+            tap: None,
+            capture: None,
+            tunnel: None,
+            mux: None,
+            rules: None,
+        }
+    }
+}
+
+impl ForwarderBuilder {
+    /// Add a listen address, can be called multiple times.
+    ///
+    /// A port range like `0.0.0.0:10000-10100` listens on each port of it.
+    pub fn listen(mut self, addr: impl Into<String>) -> Self {
+        self.listen_addrs.push(addr.into());
+        self
+    }
+
+    /// Target address, like 1.1.1.1:443.
+    ///
+    /// With a port range like `10.0.0.1:20000-20100`, or without port for the same
+    /// ports, each listen port is forwarded to the matching target port. All listen
+    /// addresses must share one port range of the same length then.
+    pub fn target(mut self, addr: impl Into<String>) -> Self {
+        self.target_addr = Some(addr.into());
+        self
+    }
+
+    /// Serve socks clients and connect the destinations they ask for, instead of
+    /// forwarding to a fixed target.
+    pub fn socks_server(mut self, config: SocksServerConfig) -> Self {
+        self.inbound = Inbound::Socks(config);
+        self
+    }
+
+    /// Serve HTTP proxy clients, CONNECT and plain HTTP, instead of forwarding to a
+    /// fixed target.
+    pub fn http_server(mut self, config: HttpServerConfig) -> Self {
+        self.inbound = Inbound::Http(config);
+        self
+    }
+
+    /// Serve mux sessions from peer forwarders sharing the secret and connect the
+    /// targets of their streams, instead of forwarding to a fixed target.
+    pub fn mux_server(mut self, secret: Secret) -> Self {
+        self.inbound = Inbound::Mux(secret);
+        self
+    }
+
+    /// Multiplex outbound streams over a few long-lived connections to a peer
+    /// forwarder running as a mux server, instead of connecting each target.
+    pub fn mux(mut self, config: MuxConfig) -> Self {
+        self.mux = Some(config);
+        self
+    }
+
+    /// Pick direct, one of the rule set's proxies or reject per destination in socks,
+    /// http and mux server mode, replacing the proxy and mux peer.
+    pub fn rules(mut self, rules: RuleSet) -> Self {
+        self.rules = Some(rules);
+        self
+    }
+
+    /// Relay through the socks5 or shadowsocks proxy instead of connecting the target directly.
+    pub fn proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// TCP keepalive for both inbound and outbound connections, `None` to disable.
+    pub fn keepalive(mut self, keepalive: Option<Duration>) -> Self {
+        let keepalive = keepalive.map(Keepalive::new);
+        self.options.socket.keepalive = keepalive;
+        self.listen_options.socket.keepalive = keepalive;
+        self
+    }
+
+    /// Options of listeners and accepted sockets.
+    pub fn listen_options(mut self, options: ListenOptions) -> Self {
+        self.listen_options = options;
+        self
+    }
+
+    /// Options of outbound sockets to the proxy or target.
+    pub fn outbound_options(mut self, options: SocketOptions) -> Self {
+        self.options.socket = options;
+        self
+    }
+
+    /// Resolve the target locally or let the proxy do it, defaults to remote.
+    pub fn resolve(mut self, resolve: ResolveMode) -> Self {
+        self.options.resolve = resolve;
+        self
+    }
+
+    /// Delay between racing connection attempts to different addresses of a host.
+    pub fn attempt_delay(mut self, delay: Duration) -> Self {
+        self.options.attempt_delay = delay;
+        self
+    }
+
+    /// Retry policy for connecting to the proxy or target, no retry by default.
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.options.retry = retry;
+        self
+    }
+
+    /// Source address, interface and fwmark of outbound sockets.
+    pub fn bind(mut self, bind: BindOptions) -> Self {
+        self.options.bind = bind;
+        self
+    }
+
+    /// DNS resolver and cache settings.
+    pub fn dns(mut self, dns: DnsConfig) -> Self {
+        self.options.dns = dns;
+        self
+    }
+
+    /// Relay mode of the built-in relay, defaults to [`RelayMode::Auto`].
+    pub fn mode(mut self, mode: RelayMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Mirror the bytes of selected connections to a tap, those are relayed in userspace.
+    pub fn tap(mut self, config: TapConfig) -> Self {
+        self.tap = Some(config);
+        self
+    }
+
+    /// Record selected connections into pcap files, those are relayed in userspace.
+    pub fn capture(mut self, config: CaptureConfig) -> Self {
+        self.capture = Some(config);
+        self
+    }
+
+    /// Hand clients to reverse tunnel agents instead of connecting a target, see
+    /// [`Agent`](crate::Agent). Agents connect to the tunnel listen address, which
+    /// [`Forwarder::run`] serves along with the listen addresses.
+    pub fn tunnel_server(mut self, config: TunnelServerConfig) -> Self {
+        self.tunnel = Some(config);
+        self
+    }
+
+    /// Build a forwarder with the built-in relay.
+    ///
+    /// eBPF is loaded here when the mode asks for it, and the resolver is created
+    /// so this must be called within a tokio runtime.
+    pub fn build(mut self) -> anyhow::Result<Forwarder<BoxRelay>> {
+        let config = self.config();
+        if let Some(tunnel) = self.tunnel.take() {
+            if !matches!(self.inbound, Inbound::Forward) {
+                anyhow::bail!("a tunnel server can not be a socks, http or mux server");
+            }
+            if self.target_addr.is_some()
+                || self.proxy.is_some()
+                || self.mux.is_some()
+                || self.rules.is_some()
+            {
+                anyhow::bail!(
+                    "target, proxy, mux peer and rules can not be set for a tunnel server"
+                );
+            }
+            let hub = Arc::new(Hub::new(tunnel)?);
+            let relay = Box::new(TunnelRelay::new(hub.clone(), self.mode)?);
+            return self.finish(relay, config, Some(hub));
+        }
+        let inbound = std::mem::replace(&mut self.inbound, Inbound::Forward);
+        let mux = self.mux.take();
+        if mux.is_some() && self.proxy.is_some() {
+            anyhow::bail!("proxy and mux peer can not both be set");
+        }
+        let rules = self.rules.take();
+        if rules.is_some() && matches!(inbound, Inbound::Forward) {
+            anyhow::bail!("rules only apply to socks, http and mux servers");
+        }
+        let relay: BoxRelay = match (inbound, self.target_addr.take(), self.proxy.take()) {
+            (Inbound::Forward, None, _) => anyhow::bail!("target address is required"),
+            (Inbound::Forward, Some(target_addr), proxy) => {
+                let (target_addr, port_map) =
+                    port_range::map_target(&self.listen_addrs, &target_addr)?;
+                match (proxy, mux) {
+                    (_, Some(mux)) => {
+                        let dialer = Arc::new(Dialer::new(self.options.clone())?);
+                        let pool = Arc::new(MuxPool::new(mux, dialer)?);
+                        Box::new(MuxRelay::new(target_addr, pool, self.mode)?.port_map(port_map))
+                    }
+                    (Some(proxy), None) => Box::new(
+                        ProxiedRelay::new(target_addr, proxy, self.options.clone(), self.mode)?
+                            .port_map(port_map),
+                    ),
+                    (None, None) => Box::new(
+                        DirectRelay::new(target_addr, self.options.clone(), self.mode)?
+                            .port_map(port_map),
+                    ),
+                }
+            }
+            (Inbound::Socks(_), Some(_), _)
+            | (Inbound::Http(_), Some(_), _)
+            | (Inbound::Mux(_), Some(_), _) => {
+                anyhow::bail!("target address can not be set for a socks, http or mux server")
+            }
+            (Inbound::Socks(config), None, proxy) => {
+                let server = SocksServer::new(config, proxy, self.options.clone(), self.mode)?;
+                let server = match mux {
+                    Some(mux) => server.mux(mux)?,
+                    None => server,
+                };
+                match rules {
+                    Some(rules) => Box::new(server.rules(rules)?),
+                    None => Box::new(server),
+                }
+            }
+            (Inbound::Http(config), None, proxy) => {
+                let server = HttpServer::new(config, proxy, self.options.clone(), self.mode)?;
+                let server = match mux {
+                    Some(mux) => server.mux(mux)?,
+                    None => server,
+                };
+                match rules {
+                    Some(rules) => Box::new(server.rules(rules)?),
+                    None => Box::new(server),
+                }
+            }
+            (Inbound::Mux(secret), None, proxy) => {
+                let server = MuxServer::new(secret, proxy, self.options.clone(), self.mode)?;
+                let server = match mux {
+                    Some(mux) => server.mux(mux)?,
+                    None => server,
+                };
+                match rules {
+                    Some(rules) => Box::new(server.rules(rules)?),
+                    None => Box::new(server),
+                }
+            }
+        };
+        self.finish(relay, config, None)
+    }
+
+    /// Check the configuration step by step instead of serving clients: resolve the
+    /// listen addresses, connect the proxy, handshake to the target and send the probe.
+    pub async fn check(&self, options: CheckOptions) -> CheckReport {
+        let checked = Checked {
+            listen: &self.listen_addrs,
+            target: self.target_addr.as_deref(),
+            proxy: self.proxy.as_ref(),
+            mux: self.mux.as_ref(),
+            rules: self.rules.as_ref(),
+            connect: self.options.clone(),
+        };
+        check::run(checked, &options).await
+    }
+
+    /// Benchmark the built-in relay in-process: listen on loopback instead and forward
+    /// to a local target sinking, sourcing and echoing data.
+    ///
+    /// The relay runs on a runtime of its own, so this blocks and must not be called
+    /// within a tokio runtime.
+    pub fn bench(self, options: &BenchOptions) -> anyhow::Result<BenchReport> {
+        let name = self.mode.to_string();
+        let (builder, target) = self.bench_target()?;
+        let listen = port_range::expand(&builder.listen_addrs)?[0].parse()?;
+        let runtime = tokio::runtime::Runtime::new()?;
+        let forwarder = {
+            let _guard = runtime.enter();
+            builder.build()?
+        };
+        let running = forwarder.clone();
+        let handle = runtime.spawn(async move { running.run().await });
+        let report = bench::measure(name, listen, options);
+        forwarder.shutdown();
+        runtime.block_on(handle)??;
+        drop(target);
+        report
+    }
+
+    /// Benchmark the io_uring backend like [`bench`](Self::bench).
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub fn bench_uring(
+        self,
+        threads: usize,
+        options: &BenchOptions,
+    ) -> anyhow::Result<BenchReport> {
+        let (builder, target) = self.bench_target()?;
+        let listen = port_range::expand(&builder.listen_addrs)?[0].parse()?;
+        let forwarder = builder.build_uring()?;
+        let running = forwarder.clone();
+        let handle = std::thread::spawn(move || running.run(threads));
+        let report = bench::measure("io_uring".to_string(), listen, options);
+        forwarder.shutdown();
+        handle
+            .join()
+            .map_err(|_| anyhow::anyhow!("io_uring backend panicked"))??;
+        drop(target);
+        report
+    }
+
+    fn bench_target(mut self) -> anyhow::Result<(Self, bench::Target)> {
+        if !matches!(self.inbound, Inbound::Forward) || self.tunnel.is_some() {
+            anyhow::bail!("only forwarding to a fixed target can be benchmarked");
+        }
+        let target = bench::Target::start()?;
+        let listen = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        self.listen_addrs = vec![listen.to_string()];
+        self.target_addr = Some(target.addr().to_string());
+        Ok((self, target))
+    }
+
+    /// Build a forwarder on the io_uring backend, relay mode is ignored.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub fn build_uring(mut self) -> anyhow::Result<crate::uring::UringForwarder> {
+        if !matches!(self.inbound, Inbound::Forward) {
+            anyhow::bail!("the io_uring backend only forwards to a fixed target");
+        }
+        if self.tap.is_some() || self.capture.is_some() {
+            anyhow::bail!("tap and capture are not supported by the io_uring backend");
+        }
+        if self.tunnel.is_some() {
+            anyhow::bail!("the io_uring backend can not be a tunnel server");
+        }
+        if self.mux.is_some() {
+            anyhow::bail!("mux is not supported by the io_uring backend");
+        }
+        if self.rules.is_some() {
+            anyhow::bail!("rules are not supported by the io_uring backend");
+        }
+        if matches!(&self.proxy, Some(p) if p.protocol != ProxyProtocol::Socks5) {
+            anyhow::bail!("only socks5 proxies are supported by the io_uring backend");
+        }
+        if matches!(&self.proxy, Some(p) if p.fallback != Fallback::Off) {
+            anyhow::bail!("proxy fallback is not supported by the io_uring backend");
+        }
+        let target_addr = self
+            .target_addr
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("target address is required"))?;
+        if self.listen_addrs.is_empty() {
+            anyhow::bail!("at least one listen address is required");
+        }
+        let (target_addr, port_map) = port_range::map_target(&self.listen_addrs, &target_addr)?;
+        if port_map.is_some() {
+            anyhow::bail!("target port ranges are not supported by the io_uring backend");
+        }
+        crate::uring::UringForwarder::new(
+            port_range::expand(&self.listen_addrs)?,
+            self.listen_options,
+            target_addr,
+            self.proxy,
+            self.options,
+        )
+    }
+
+    /// Build a forwarder with a custom relay, target, proxy and server settings are ignored.
+    pub fn build_with_relay<R: Relay>(self, relay: R) -> anyhow::Result<Forwarder<R>> {
+        let config = ForwarderConfig {
+            inbound: "custom",
+            inbound_auth: false,
+            target: None,
+            proxy: None,
+            proxy_protocol: None,
+            proxy_fallback: None,
+            proxy_auth: false,
+            mux: None,
+            rules: None,
+            tunnel_listen: None,
+            ..self.config()
+        };
+        self.finish(relay, config, None)
+    }
+
+    fn config(&self) -> ForwarderConfig {
+        let (inbound, inbound_auth) = match &self.inbound {
+            Inbound::Forward => ("forward", false),
+            Inbound::Socks(config) => ("socks", config.credential.is_some()),
+            Inbound::Http(config) => ("http", config.credential.is_some()),
+            Inbound::Mux(_) => ("mux", true),
+        };
+        ForwarderConfig {
+            listen: self.listen_addrs.clone(),
+            inbound,
+            inbound_auth,
+            target: self.target_addr.clone(),
+            proxy: self.proxy.as_ref().map(|p| p.address.clone()),
+            proxy_protocol: self.proxy.as_ref().map(|p| match p.protocol {
+                ProxyProtocol::Socks5 => "socks5",
+                ProxyProtocol::Shadowsocks { .. } => "shadowsocks",
+            }),
+            proxy_fallback: self.proxy.as_ref().map(|p| p.fallback),
+            proxy_auth: matches!(&self.proxy, Some(p) if p.credential.is_some()),
+            mux: self.mux.clone(),
+            rules: self.rules.as_ref().map(|r| r.rules().len()),
+            mode: self.mode,
+            listen_options: self.listen_options.clone(),
+            connect: self.options.clone(),
+            tap: self.tap.clone(),
+            capture: self.capture.clone(),
+            tunnel_listen: self.tunnel.as_ref().map(|t| t.listen.clone()),
+        }
+    }
+
+    fn finish<R: Relay>(
+        self,
+        relay: R,
+        config: ForwarderConfig,
+        tunnel: Option<Arc<Hub>>,
+    ) -> anyhow::Result<Forwarder<R>> {
+        if self.listen_addrs.is_empty() {
+            anyhow::bail!("at least one listen address is required");
+        }
+        self.listen_options.check()?;
+        let listeners = port_range::expand(&self.listen_addrs)?
+            .into_iter()
+            .map(|addr| {
+                let (paused_tx, paused_rx) = watch::channel(false);
+                Listener {
+                    addr,
+                    paused_tx,
+                    paused_rx,
+                }
+            })
+            .collect();
+        let tap = match self.tap {
+            Some(config) => Some(Arc::new(Tap::new(config)?)),
+            None => None,
+        };
+        let capture = match self.capture {
+            Some(config) => Some(Arc::new(Capture::new(config)?)),
+            None => None,
+        };
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        Ok(Forwarder {
+            inner: Arc::new(Inner {
+                listeners,
+                listen_options: self.listen_options,
+                relay,
+                config,
+                registry: Arc::new(Registry::default()),
+                tap,
+                capture,
+                tunnel,
+                shutdown_tx,
+                shutdown_rx,
+            }),
+        })
+    }
+}
+
+/// Accepts connections on all listen addresses and hands them to the relay.
+///
+/// Cloning is cheap and all clones control the same forwarder.
+pub struct Forwarder<R> {
+    inner: Arc<Inner<R>>,
+}
+
+struct Inner<R> {
+    listeners: Vec<Listener>,
+    listen_options: ListenOptions,
+    relay: R,
+    config: ForwarderConfig,
+    registry: Arc<Registry>,
+    tap: Option<Arc<Tap>>,
+    capture: Option<Arc<Capture>>,
+    tunnel: Option<Arc<Hub>>,
+    shutdown_tx: watch::Sender<bool>,
+    shutdown_rx: watch::Receiver<bool>,
+}
+
+struct Listener {
+    addr: String,
+    paused_tx: watch::Sender<bool>,
+    paused_rx: watch::Receiver<bool>,
+}
+
+impl<R> Clone for Forwarder<R> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl Forwarder<BoxRelay> {
+    pub fn builder() -> ForwarderBuilder {
+        ForwarderBuilder::default()
+    }
+}
+
+impl<R> Forwarder<R> {
+    /// Connections being relayed, ordered by id.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.inner.registry.list()
+    }
+
+    /// Totals since the forwarder started.
+    pub fn stats(&self) -> Stats {
+        self.inner.registry.stats()
+    }
+
+    /// Close both sides of a connection, returns false if it is not found.
+    pub fn close_connection(&self, id: u64) -> bool {
+        self.inner.registry.close(id)
+    }
+
+    pub fn listeners(&self) -> Vec<ListenerInfo> {
+        self.inner
+            .listeners
+            .iter()
+            .map(|l| ListenerInfo {
+                address: l.addr.clone(),
+                paused: *l.paused_rx.borrow(),
+            })
+            .collect()
+    }
+
+    /// Stop accepting on the listen address, returns false if it is not found.
+    ///
+    /// The socket stays bound, new clients wait in the backlog until resumed.
+    pub fn pause(&self, addr: &str) -> bool {
+        self.set_paused(addr, true)
+    }
+
+    /// Accept again on a paused listen address, returns false if it is not found.
+    pub fn resume(&self, addr: &str) -> bool {
+        self.set_paused(addr, false)
+    }
+
+    fn set_paused(&self, addr: &str, paused: bool) -> bool {
+        match self.inner.listeners.iter().find(|l| l.addr == addr) {
+            Some(listener) => {
+                if *listener.paused_rx.borrow() != paused {
+                    tracing::info!(
+                        "Listener {} {}",
+                        addr,
+                        if paused { "paused" } else { "resumed" }
+                    );
+                    let _ = listener.paused_tx.send(paused);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn config(&self) -> &ForwarderConfig {
+        &self.inner.config
+    }
+
+    pub(crate) fn shutdown_signal(&self) -> watch::Receiver<bool> {
+        self.inner.shutdown_rx.clone()
+    }
+}
+
+impl<R> Forwarder<R>
+where
+    R: Relay,
+    R::Fut: Send + 'static,
+{
+    /// Serve until [`shutdown`](Self::shutdown) is called or a listener fails.
+    ///
+    /// Relays already started are not interrupted by shutdown.
+    pub async fn run(&self) -> anyhow::Result<()> {
+        self.inner.relay.init_check()?;
+
+        let mut listeners = Vec::with_capacity(self.inner.listeners.len());
+        for listener in self.inner.listeners.iter() {
+            tracing::info!("Listening at {}", listener.addr);
+            listeners.push((
+                listener,
+                self.inner.listen_options.bind(&listener.addr).await?,
+            ));
+        }
+        let tunnel = match &self.inner.tunnel {
+            Some(hub) => {
+                tracing::info!("Tunnel listening at {}", hub.listen_addr());
+                let socket = self.inner.listen_options.bind(hub.listen_addr()).await?;
+                Some(hub.clone().serve(socket, self.inner.shutdown_rx.clone()))
+            }
+            None => None,
+        };
+        let accept = futures::future::try_join_all(
+            listeners
+                .into_iter()
+                .map(|(listener, socket)| self.accept_loop(listener, socket)),
+        );
+        match tunnel {
+            Some(tunnel) => {
+                futures::future::try_join(accept, tunnel).await?;
+            }
+            None => {
+                accept.await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop accepting new connections, `run` returns after that.
+    pub fn shutdown(&self) {
+        let _ = self.inner.shutdown_tx.send(true);
+    }
+
+    async fn accept_loop(&self, listener: &Listener, socket: TcpListener) -> anyhow::Result<()> {
+        let mut shutdown = self.inner.shutdown_rx.clone();
+        let mut paused = listener.paused_rx.clone();
+        loop {
+            if *shutdown.borrow() {
+                tracing::info!("Listener closed");
+                return Ok(());
+            }
+            if *paused.borrow() {
+                tokio::select! {
+                    _ = paused.changed() => {}
+                    _ = shutdown.changed() => {}
+                }
+                continue;
+            }
+            tokio::select! {
+                accepted = socket.accept() => match accepted {
+                    Ok((conn, peer)) => {
+                        let (abort, abort_registration) = AbortHandle::new_pair();
+                        let tap = self.inner.tap.as_ref().filter(|t| t.selects(peer.ip()));
+                        let capture = self
+                            .inner
+                            .capture
+                            .as_ref()
+                            .filter(|c| c.selects(&listener.addr, peer.ip()));
+                        let registered = self.inner.registry.insert(
+                            &listener.addr,
+                            peer,
+                            tap.cloned(),
+                            capture.cloned(),
+                            abort,
+                        );
+                        let connection = registered.connection();
+                        // target and proxy are recorded once the relay picks them
+                        let span = tracing::info_span!(
+                            "conn",
+                            id = connection.id(),
+                            client = %peer,
+                            target = Empty,
+                            proxy = Empty,
+                            fallback = Empty,
+                        );
+                        let _enter = span.enter();
+                        tracing::info!("Accept new incoming connection");
+                        if let Err(e) = self.inner.listen_options.socket.apply(&conn) {
+                            tracing::error!("Set socket options failed: {}", e);
+                            continue;
+                        }
+                        let relay = Abortable::new(
+                            registry::scope(connection, self.inner.relay.relay(conn)),
+                            abort_registration,
+                        );
+                        tokio::spawn(
+                            async move {
+                                match relay.await {
+                                    Ok(Ok(())) => {}
+                                    Ok(Err(e)) => tracing::error!("Relay failed: {}", e),
+                                    Err(Aborted) => tracing::info!("Connection closed"),
+                                }
+                                drop(registered);
+                            }
+                            .instrument(span.clone()),
+                        );
+                    }
+                    Err(e) => {
+                        tracing::error!("Accept error: {}", e);
+                    }
+                },
+                _ = shutdown.changed() => {}
+                _ = paused.changed() => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! Happy Eyeballs (RFC 8305) connection racing.
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use tokio::net::TcpStream;
+
+use crate::socket::{self, BindOptions, SocketOptions};
+
+/// Recommended Connection Attempt Delay of RFC 8305.
+pub(crate) const DEFAULT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connect to the first address that answers.
+///
+/// Addresses are interleaved by family starting with IPv6, a new attempt is started
+/// every `delay` or as soon as the previous one fails, the first success wins and
+/// cancels the others.
+pub(crate) async fn connect(
+    addrs: &[SocketAddr],
+    delay: Duration,
+    bind: &BindOptions,
+    options: &SocketOptions,
+) -> io::Result<TcpStream> {
+    race(addrs, delay, |addr| attempt(addr, bind, options)).await
+}
+
+/// Race `attempt` over the addresses as [`connect`] does.
+async fn race<T, F, Fut>(addrs: &[SocketAddr], delay: Duration, attempt: F) -> io::Result<T>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut candidates = interleave(addrs).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+
+    loop {
+        if attempts.is_empty() {
+            match candidates.next() {
+                Some(addr) => attempts.push(attempt(addr)),
+                None => break,
+            }
+        }
+        let more = candidates.len() > 0;
+        tokio::select! {
+            res = attempts.next() => match res {
+                Some(Ok(stream)) => return Ok(stream),
+                Some(Err(e)) => {
+                    last_err = Some(e);
+                    if let Some(addr) = candidates.next() {
+                        attempts.push(attempt(addr));
+                    }
+                }
+                None => (),
+            },
+            _ = tokio::time::sleep(delay), if more => {
+                if let Some(addr) = candidates.next() {
+                    attempts.push(attempt(addr));
+                }
+            }
+        }
+    }
+
+    Err(last_err
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to connect")))
+}
+
+async fn attempt(
+    addr: SocketAddr,
+    bind: &BindOptions,
+    options: &SocketOptions,
+) -> io::Result<TcpStream> {
+    socket::connect(addr, bind, options).await.map_err(|e| {
+        tracing::debug!("Connect {} failed: {}", addr, e);
+        e
+    })
+}
+
+/// Alternate the address families, starting with IPv6.
+pub(crate) fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.iter().copied().partition(SocketAddr::is_ipv6);
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    let mut result = Vec::with_capacity(addrs.len());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (a, b) => result.extend(a.into_iter().chain(b)),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn interleave_families() {
+        let mixed = addrs(&[
+            "1.0.0.1:80",
+            "1.0.0.2:80",
+            "[::1]:80",
+            "1.0.0.3:80",
+            "[::2]:80",
+        ]);
+        let expected = addrs(&[
+            "[::1]:80",
+            "1.0.0.1:80",
+            "[::2]:80",
+            "1.0.0.2:80",
+            "1.0.0.3:80",
+        ]);
+        assert_eq!(interleave(&mixed), expected);
+        let v4 = addrs(&["1.0.0.1:80", "1.0.0.2:80"]);
+        assert_eq!(interleave(&v4), v4);
+    }
+
+    #[tokio::test]
+    async fn slow_attempt_overtaken() {
+        let start = Instant::now();
+        let winner = race(
+            &addrs(&["1.0.0.1:80", "[::1]:80"]),
+            Duration::from_millis(50),
+            |addr| async move {
+                // the first candidate, IPv6, hangs
+                if addr.is_ipv6() {
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                }
+                Ok(addr)
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(winner, "1.0.0.1:80".parse().unwrap());
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(50) && elapsed < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn all_attempts_fail() {
+        let start = Instant::now();
+        let tried = std::sync::Mutex::new(Vec::new());
+        let err = race(
+            &addrs(&["1.0.0.1:80", "[::1]:80", "1.0.0.2:80"]),
+            Duration::from_secs(10),
+            |addr| {
+                tried.lock().unwrap().push(addr);
+                let kind = if addr.is_ipv6() {
+                    io::ErrorKind::TimedOut
+                } else {
+                    io::ErrorKind::ConnectionRefused
+                };
+                async move { Err::<(), _>(io::Error::from(kind)) }
+            },
+        )
+        .await
+        .unwrap_err();
+        // failures start the next attempt without waiting for the delay
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(tried.lock().unwrap().len(), 3);
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+
+        let err = race(
+            &[],
+            Duration::from_millis(50),
+            |addr| async move { Ok(addr) },
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,293 @@
+//! Server side of an HTTP proxy: CONNECT tunnels and plain HTTP with absolute URIs.
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_socks::TargetAddr;
+use zeroize::Zeroizing;
+
+use super::{handshake, Upstream};
+use crate::connect::ConnectOptions;
+use crate::mux::MuxConfig;
+use crate::proxy::{Credential, ProxyConfig};
+use crate::relay::{Relay, RelayMode};
+use crate::retry::RetryOn;
+use crate::rules::{Rejected, RuleSet};
+
+/// Max size of a request head.
+const MAX_HEAD: usize = 16 * 1024;
+const MAX_HEADERS: usize = 64;
+const PROXY_AUTH_REQUIRED: &str = "407 Proxy Authentication Required";
+
+/// Headers of the client-proxy hop, not passed to the origin.
+const HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// Settings of the inbound HTTP proxy.
+#[derive(Debug, Clone, Default)]
+pub struct HttpServerConfig {
+    /// Username and password clients must send with `Proxy-Authorization: Basic`,
+    /// unrelated to the upstream proxy credential. No auth when `None`.
+    pub credential: Option<Credential>,
+}
+
+/// Relay acting as an HTTP proxy, each requested destination is connected directly
+/// or through the upstream proxy.
+///
+/// Plain HTTP requests are sent with `Connection: close`, so each client connection
+/// carries one request.
+pub struct HttpServer {
+    config: Arc<HttpServerConfig>,
+    upstream: Upstream,
+}
+
+impl HttpServer {
+    pub fn new(
+        config: HttpServerConfig,
+        proxy: Option<ProxyConfig>,
+        options: ConnectOptions,
+        mode: RelayMode,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            config: Arc::new(config),
+            upstream: Upstream::new(proxy, options, mode)?,
+        })
+    }
+
+    /// Open streams over mux sessions to a peer forwarder instead of connecting
+    /// destinations, can not be combined with the upstream proxy.
+    pub fn mux(mut self, config: MuxConfig) -> anyhow::Result<Self> {
+        self.upstream = self.upstream.mux(config)?;
+        Ok(self)
+    }
+
+    /// Pick direct, one of the rule set's proxies or reject per destination,
+    /// instead of the upstream proxy or mux peer.
+    pub fn rules(mut self, rules: RuleSet) -> anyhow::Result<Self> {
+        self.upstream = self.upstream.rules(rules)?;
+        Ok(self)
+    }
+
+    /// The mode actually in use after fallback.
+    pub fn mode(&self) -> RelayMode {
+        self.upstream.transfer.mode()
+    }
+}
+
+impl Relay for HttpServer {
+    type Fut = BoxFuture<'static, anyhow::Result<()>>;
+
+    fn relay(&self, mut inbound: TcpStream) -> Self::Fut {
+        let config = self.config.clone();
+        let upstream = self.upstream.clone();
+
+        Box::pin(async move {
+            let request = handshake(accept(&mut inbound, &config)).await?;
+            tracing::info!("HTTP {} request to {}", request.method, request.target);
+            let client = inbound.peer_addr()?;
+            let mut outbound = match upstream.connect(request.target.clone(), client).await {
+                Ok(outbound) => outbound,
+                Err(e) => {
+                    let status = match RetryOn::classify(&e) {
+                        _ if e.is::<Rejected>() => "403 Forbidden",
+                        Some(RetryOn::Timeout) => "504 Gateway Timeout",
+                        _ => "502 Bad Gateway",
+                    };
+                    let _ = respond(&mut inbound, status, "").await;
+                    return Err(e);
+                }
+            };
+            if request.method == "CONNECT" {
+                inbound
+                    .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                    .await?;
+            }
+            // the rewritten head of plain requests, or bytes sent early after CONNECT
+            outbound.write_all(&request.forward).await?;
+
+            upstream.transfer.relay(inbound, outbound).await
+        })
+    }
+}
+
+struct Request {
+    method: String,
+    target: TargetAddr<'static>,
+    /// Sent to the target before relaying.
+    forward: Vec<u8>,
+}
+
+async fn accept(conn: &mut TcpStream, config: &HttpServerConfig) -> anyhow::Result<Request> {
+    let mut buf = Vec::with_capacity(1024);
+    loop {
+        if buf.len() >= MAX_HEAD {
+            respond(conn, "431 Request Header Fields Too Large", "").await?;
+            anyhow::bail!("request head too large");
+        }
+        let mut chunk = [0; 4096];
+        let n = conn.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("connection closed before the request head");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+        let len = match req.parse(&buf) {
+            Ok(httparse::Status::Complete(len)) => len,
+            Ok(httparse::Status::Partial) => continue,
+            Err(e) => {
+                respond(conn, "400 Bad Request", "").await?;
+                anyhow::bail!("bad http request: {}", e);
+            }
+        };
+        return match parse(&req, config) {
+            Ok(mut request) => {
+                request.forward.extend_from_slice(&buf[len..]);
+                Ok(request)
+            }
+            Err((status, e)) => {
+                let headers = match status {
+                    PROXY_AUTH_REQUIRED => "Proxy-Authenticate: Basic realm=\"proxy\"\r\n",
+                    _ => "",
+                };
+                respond(conn, status, headers).await?;
+                Err(e)
+            }
+        };
+    }
+}
+
+type ParseError = (&'static str, anyhow::Error);
+
+fn parse(req: &httparse::Request, config: &HttpServerConfig) -> Result<Request, ParseError> {
+    let bad_request = |e: anyhow::Error| ("400 Bad Request", e);
+    let method = req.method.unwrap_or_default().to_string();
+    let uri = req.path.unwrap_or_default();
+
+    if let Some(credential) = config.credential.as_ref() {
+        let authorized = req
+            .headers
+            .iter()
+            .filter(|h| h.name.eq_ignore_ascii_case("proxy-authorization"))
+            .any(|h| basic_auth_matches(credential, h.value));
+        if !authorized {
+            return Err((
+                PROXY_AUTH_REQUIRED,
+                anyhow::anyhow!("http proxy auth failed"),
+            ));
+        }
+    }
+
+    if method == "CONNECT" {
+        let target = parse_authority(uri, None).map_err(bad_request)?;
+        return Ok(Request {
+            method,
+            target,
+            forward: Vec::new(),
+        });
+    }
+
+    let rest = match uri.get(..7) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("http://") => &uri[7..],
+        _ => {
+            return Err(bad_request(anyhow::anyhow!(
+                "only CONNECT and absolute http URI are supported"
+            )))
+        }
+    };
+    let (authority, path) = match rest.find(&['/', '?'][..]) {
+        Some(idx) if rest.as_bytes()[idx] == b'?' => (&rest[..idx], format!("/{}", &rest[idx..])),
+        Some(idx) => (&rest[..idx], rest[idx..].to_string()),
+        None => (rest, "/".to_string()),
+    };
+    // drop userinfo, it is not meant for the origin
+    let authority = authority.rsplit('@').next().unwrap_or(authority);
+    let target = parse_authority(authority, Some(80)).map_err(bad_request)?;
+
+    // origin-form request line with the hop-by-hop headers replaced
+    let mut head = format!(
+        "{} {} HTTP/1.{}\r\n",
+        method,
+        path,
+        req.version.unwrap_or(1)
+    )
+    .into_bytes();
+    let mut has_host = false;
+    for header in req.headers.iter() {
+        let name = header.name.to_ascii_lowercase();
+        if HOP_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        has_host |= name == "host";
+        head.extend_from_slice(header.name.as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(header.value);
+        head.extend_from_slice(b"\r\n");
+    }
+    if !has_host {
+        head.extend_from_slice(format!("Host: {}\r\n", authority).as_bytes());
+    }
+    head.extend_from_slice(b"Connection: close\r\n\r\n");
+
+    Ok(Request {
+        method,
+        target,
+        forward: head,
+    })
+}
+
+/// Parse `host:port` or `[v6]:port`, the port may be omitted when there is a default.
+fn parse_authority(s: &str, default_port: Option<u16>) -> anyhow::Result<TargetAddr<'static>> {
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Ok(TargetAddr::Ip(addr));
+    }
+    let (host, port) = match s.rfind(':') {
+        Some(idx) if !s[idx..].contains(']') => (&s[..idx], s[idx + 1..].parse::<u16>()?),
+        _ => match default_port {
+            Some(port) => (s, port),
+            None => anyhow::bail!("port is required in {}", s),
+        },
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        anyhow::bail!("host is required");
+    }
+    match host.parse() {
+        Ok(ip) => Ok(TargetAddr::Ip(SocketAddr::new(ip, port))),
+        Err(_) => Ok(TargetAddr::Domain(Cow::Owned(host.to_string()), port)),
+    }
+}
+
+fn basic_auth_matches(credential: &Credential, value: &[u8]) -> bool {
+    let encoded = match value.get(..6) {
+        Some(scheme) if scheme.eq_ignore_ascii_case(b"basic ") => &value[6..],
+        _ => return false,
+    };
+    let decoded = match base64::decode(encoded) {
+        Ok(decoded) => Zeroizing::new(decoded),
+        Err(_) => return false,
+    };
+    match decoded.iter().position(|&b| b == b':') {
+        Some(idx) => credential.matches(&decoded[..idx], &decoded[idx + 1..]),
+        None => false,
+    }
+}
+
+async fn respond(conn: &mut TcpStream, status: &str, headers: &str) -> std::io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+        status, headers
+    );
+    conn.write_all(response.as_bytes()).await
+}
@@ -0,0 +1,123 @@
+//! Inbound proxy protocols, where the client picks the destination.
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio_socks::TargetAddr;
+
+use crate::connect::{ConnectOptions, Dialer};
+use crate::mux::{MuxConfig, MuxPool};
+use crate::proxy::ProxyConfig;
+use crate::registry;
+use crate::relay::{self, Outbound, RelayMode, Transfer};
+use crate::rules::{Rejected, Route, RuleSet};
+use crate::socket::BindOptions;
+
+mod http;
+mod mux;
+mod socks;
+
+pub use http::{HttpServer, HttpServerConfig};
+pub use mux::MuxServer;
+pub use socks::{SocksServer, SocksServerConfig};
+
+/// Clients must finish the handshake in time, so idle connections do not pile up.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connects requested destinations directly, through the upstream proxy, over
+/// mux sessions to a peer or as the routing rules say.
+#[derive(Clone)]
+struct Upstream {
+    proxy: Option<Arc<ProxyConfig>>,
+    mux: Option<Arc<MuxPool>>,
+    rules: Option<Arc<RuleSet>>,
+    dialer: Arc<Dialer>,
+    transfer: Transfer,
+}
+
+impl Upstream {
+    fn new(
+        proxy: Option<ProxyConfig>,
+        options: ConnectOptions,
+        mode: RelayMode,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            proxy: proxy.map(Arc::new),
+            mux: None,
+            rules: None,
+            dialer: Arc::new(Dialer::new(options)?),
+            transfer: Transfer::new(mode)?,
+        })
+    }
+
+    fn mux(mut self, config: MuxConfig) -> anyhow::Result<Self> {
+        if self.proxy.is_some() {
+            anyhow::bail!("proxy and mux peer can not both be set");
+        }
+        if self.rules.is_some() {
+            anyhow::bail!("rules and mux peer can not both be set");
+        }
+        self.mux = Some(Arc::new(MuxPool::new(config, self.dialer.clone())?));
+        Ok(self)
+    }
+
+    fn rules(mut self, rules: RuleSet) -> anyhow::Result<Self> {
+        if self.proxy.is_some() || self.mux.is_some() {
+            anyhow::bail!("rules replace the proxy and mux peer, declare proxies in the rules");
+        }
+        rules.check()?;
+        self.rules = Some(Arc::new(rules));
+        Ok(self)
+    }
+
+    async fn connect(
+        &self,
+        target: TargetAddr<'static>,
+        client: SocketAddr,
+    ) -> anyhow::Result<Outbound> {
+        if let Some(rules) = self.rules.as_ref() {
+            let (rule, route) = rules
+                .route(&target, client, || self.dialer.resolve(&target))
+                .await;
+            match rule {
+                Some(i) => tracing::info!("Rule {} matched {}", i + 1, target),
+                None => tracing::info!("No rule matched {}", target),
+            }
+            return match route {
+                Route::Direct(bind) => self.connect_direct(target, bind).await,
+                Route::Proxy(proxy) => relay::connect_proxied(&self.dialer, &proxy, target).await,
+                Route::Reject => {
+                    registry::record_route(&target, None);
+                    Err(Rejected.into())
+                }
+            };
+        }
+        if let Some(pool) = self.mux.as_ref() {
+            registry::record_route(&target, Some(pool.peer()));
+            tracing::info!("Open stream to {} via mux peer {}", target, pool.peer());
+            return Ok(pool.open(&target).await?.into());
+        }
+        match self.proxy.as_ref() {
+            Some(proxy) => relay::connect_proxied(&self.dialer, proxy, target).await,
+            None => self.connect_direct(target, BindOptions::default()).await,
+        }
+    }
+
+    async fn connect_direct(
+        &self,
+        target: TargetAddr<'static>,
+        bind: BindOptions,
+    ) -> anyhow::Result<Outbound> {
+        registry::record_route(&target, None);
+        tracing::info!("Connect target {}", target);
+        Ok(self.dialer.connect_from(target, &bind).await?.into())
+    }
+}
+
+async fn handshake<T>(
+    fut: impl std::future::Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, fut)
+        .await
+        .map_err(|_| anyhow::anyhow!("handshake timed out"))?
+}
@@ -0,0 +1,105 @@
+//! Server side of the mux protocol, serving sessions from peer forwarders.
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use tokio::net::TcpStream;
+use tokio_socks::IntoTargetAddr;
+use tracing::field::Empty;
+use tracing::Instrument;
+
+use super::Upstream;
+use crate::connect::ConnectOptions;
+use crate::mux::{self, MuxConfig, MuxStream, Session};
+use crate::proxy::{ProxyConfig, Secret};
+use crate::registry::{self, Counters, Observed};
+use crate::relay::{Relay, RelayMode};
+use crate::rules::RuleSet;
+
+/// Relay serving mux sessions of peers knowing the secret, the target of each stream
+/// is connected directly or through the upstream proxy. Streams are always relayed in
+/// userspace.
+pub struct MuxServer {
+    upstream: Upstream,
+    secret: Arc<Secret>,
+}
+
+impl MuxServer {
+    pub fn new(
+        secret: Secret,
+        proxy: Option<ProxyConfig>,
+        options: ConnectOptions,
+        mode: RelayMode,
+    ) -> anyhow::Result<Self> {
+        crate::tunnel::check_secret(&secret)?;
+        Ok(Self {
+            upstream: Upstream::new(proxy, options, mode)?,
+            secret: Arc::new(secret),
+        })
+    }
+
+    /// Open streams over mux sessions to a further peer instead of connecting targets,
+    /// can not be combined with the upstream proxy.
+    pub fn mux(mut self, config: MuxConfig) -> anyhow::Result<Self> {
+        self.upstream = self.upstream.mux(config)?;
+        Ok(self)
+    }
+
+    /// Pick direct, one of the rule set's proxies or reject per destination,
+    /// instead of the upstream proxy or mux peer.
+    pub fn rules(mut self, rules: RuleSet) -> anyhow::Result<Self> {
+        self.upstream = self.upstream.rules(rules)?;
+        Ok(self)
+    }
+}
+
+impl Relay for MuxServer {
+    type Fut = BoxFuture<'static, anyhow::Result<()>>;
+
+    fn relay(&self, mut inbound: TcpStream) -> Self::Fut {
+        let upstream = self.upstream.clone();
+        let secret = self.secret.clone();
+
+        Box::pin(async move {
+            let client = inbound.peer_addr()?;
+            mux::verify(&mut inbound, &secret).await?;
+            // streams are reset when the session is dropped
+            let (_session, mut streams) = Session::server(inbound)?;
+            tracing::info!("Mux session started");
+            let counters = registry::counters();
+            while let Some((stream, target)) = streams.recv().await {
+                let upstream = upstream.clone();
+                let counters = counters.clone();
+                let span =
+                    tracing::info_span!("stream", target = Empty, proxy = Empty, fallback = Empty);
+                tokio::spawn(
+                    async move {
+                        if let Err(e) =
+                            serve_stream(&upstream, stream, &target, client, &counters).await
+                        {
+                            tracing::error!("Stream failed: {}", e);
+                        }
+                    }
+                    .instrument(span),
+                );
+            }
+            tracing::info!("Mux session finished");
+            Ok(())
+        })
+    }
+}
+
+async fn serve_stream(
+    upstream: &Upstream,
+    mut stream: MuxStream,
+    target: &str,
+    client: SocketAddr,
+    counters: &Arc<Counters>,
+) -> anyhow::Result<()> {
+    let target = target.into_target_addr()?.to_owned();
+    let mut outbound = upstream.connect(target, client).await?;
+    let mut inbound = Observed::new(&mut stream, &counters.sent);
+    let mut outbound = Observed::new(&mut outbound, &counters.received);
+    tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await?;
+    Ok(())
+}
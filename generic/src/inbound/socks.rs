@@ -0,0 +1,299 @@
+//! Server side of SOCKS5 (RFC 1928, RFC 1929) and SOCKS4a, CONNECT only.
+use std::borrow::Cow;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_socks::TargetAddr;
+use zeroize::Zeroizing;
+
+use super::{handshake, Upstream};
+use crate::connect::ConnectOptions;
+use crate::mux::MuxConfig;
+use crate::proxy::{Credential, ProxyConfig};
+use crate::relay::{Relay, RelayMode};
+use crate::retry::RetryOn;
+use crate::rules::{Rejected, RuleSet};
+
+const AUTH_NONE: u8 = 0;
+const AUTH_PASSWORD: u8 = 2;
+const AUTH_NO_ACCEPTABLE: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+
+const REPLY_SUCCEEDED: u8 = 0;
+const REPLY_GENERAL_FAILURE: u8 = 1;
+const REPLY_NOT_ALLOWED: u8 = 2;
+const REPLY_HOST_UNREACHABLE: u8 = 4;
+const REPLY_CONNECTION_REFUSED: u8 = 5;
+const REPLY_TTL_EXPIRED: u8 = 6;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 7;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+const SOCKS4_GRANTED: u8 = 90;
+const SOCKS4_REJECTED: u8 = 91;
+
+/// Settings of the inbound socks server.
+#[derive(Debug, Clone, Default)]
+pub struct SocksServerConfig {
+    /// Username and password clients must authenticate with, unrelated to the
+    /// upstream proxy credential. No auth when `None`.
+    pub credential: Option<Credential>,
+    /// Accept SOCKS4 and SOCKS4a too, they carry no password so they are refused
+    /// when a credential is set.
+    pub socks4: bool,
+}
+
+/// Relay acting as a socks server, each requested destination is connected directly
+/// or through the upstream proxy.
+pub struct SocksServer {
+    config: Arc<SocksServerConfig>,
+    upstream: Upstream,
+}
+
+impl SocksServer {
+    pub fn new(
+        config: SocksServerConfig,
+        proxy: Option<ProxyConfig>,
+        options: ConnectOptions,
+        mode: RelayMode,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            config: Arc::new(config),
+            upstream: Upstream::new(proxy, options, mode)?,
+        })
+    }
+
+    /// Open streams over mux sessions to a peer forwarder instead of connecting
+    /// destinations, can not be combined with the upstream proxy.
+    pub fn mux(mut self, config: MuxConfig) -> anyhow::Result<Self> {
+        self.upstream = self.upstream.mux(config)?;
+        Ok(self)
+    }
+
+    /// Pick direct, one of the rule set's proxies or reject per destination,
+    /// instead of the upstream proxy or mux peer.
+    pub fn rules(mut self, rules: RuleSet) -> anyhow::Result<Self> {
+        self.upstream = self.upstream.rules(rules)?;
+        Ok(self)
+    }
+
+    /// The mode actually in use after fallback.
+    pub fn mode(&self) -> RelayMode {
+        self.upstream.transfer.mode()
+    }
+}
+
+impl Relay for SocksServer {
+    type Fut = BoxFuture<'static, anyhow::Result<()>>;
+
+    fn relay(&self, mut inbound: TcpStream) -> Self::Fut {
+        let config = self.config.clone();
+        let upstream = self.upstream.clone();
+
+        Box::pin(async move {
+            let request = handshake(accept(&mut inbound, &config)).await?;
+            tracing::info!("Socks request to {}", request.target);
+            let client = inbound.peer_addr()?;
+            let outbound = match upstream.connect(request.target.clone(), client).await {
+                Ok(outbound) => outbound,
+                Err(e) => {
+                    let _ = request.reply(&mut inbound, Err(&e)).await;
+                    return Err(e);
+                }
+            };
+            request
+                .reply(&mut inbound, Ok(outbound.local_addr()?))
+                .await?;
+
+            upstream.transfer.relay(inbound, outbound).await
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Version {
+    Socks4,
+    Socks5,
+}
+
+struct Request {
+    version: Version,
+    target: TargetAddr<'static>,
+}
+
+impl Request {
+    async fn reply(
+        &self,
+        conn: &mut TcpStream,
+        result: Result<SocketAddr, &anyhow::Error>,
+    ) -> std::io::Result<()> {
+        match self.version {
+            Version::Socks4 => {
+                let code = match result {
+                    Ok(_) => SOCKS4_GRANTED,
+                    Err(_) => SOCKS4_REJECTED,
+                };
+                conn.write_all(&[0, code, 0, 0, 0, 0, 0, 0]).await
+            }
+            Version::Socks5 => match result {
+                Ok(bound) => socks5_reply(conn, REPLY_SUCCEEDED, bound).await,
+                Err(e) => socks5_reply(conn, reply_code(e), unspecified()).await,
+            },
+        }
+    }
+}
+
+async fn accept(conn: &mut TcpStream, config: &SocksServerConfig) -> anyhow::Result<Request> {
+    match conn.read_u8().await? {
+        5 => accept_socks5(conn, config).await,
+        4 if config.socks4 && config.credential.is_none() => accept_socks4(conn).await,
+        4 => {
+            conn.write_all(&[0, SOCKS4_REJECTED, 0, 0, 0, 0, 0, 0])
+                .await?;
+            anyhow::bail!("socks4 is not allowed")
+        }
+        version => anyhow::bail!("unsupported socks version {}", version),
+    }
+}
+
+async fn accept_socks5(
+    conn: &mut TcpStream,
+    config: &SocksServerConfig,
+) -> anyhow::Result<Request> {
+    let mut methods = vec![0; conn.read_u8().await? as usize];
+    conn.read_exact(&mut methods).await?;
+    let method = match config.credential {
+        Some(_) => AUTH_PASSWORD,
+        None => AUTH_NONE,
+    };
+    if !methods.contains(&method) {
+        conn.write_all(&[5, AUTH_NO_ACCEPTABLE]).await?;
+        anyhow::bail!("no acceptable socks auth method");
+    }
+    conn.write_all(&[5, method]).await?;
+
+    if let Some(credential) = config.credential.as_ref() {
+        let version = conn.read_u8().await?;
+        let mut username = vec![0; conn.read_u8().await? as usize];
+        conn.read_exact(&mut username).await?;
+        let mut password = Zeroizing::new(vec![0; conn.read_u8().await? as usize]);
+        conn.read_exact(&mut password).await?;
+        let ok = version == 1 && credential.matches(&username, &password);
+        conn.write_all(&[1, if ok { 0 } else { 1 }]).await?;
+        if !ok {
+            anyhow::bail!(
+                "socks auth failed for user {}",
+                String::from_utf8_lossy(&username)
+            );
+        }
+    }
+
+    let mut head = [0; 4];
+    conn.read_exact(&mut head).await?;
+    if head[0] != 5 {
+        anyhow::bail!("unsupported socks version {}", head[0]);
+    }
+    if head[1] != CMD_CONNECT {
+        socks5_reply(conn, REPLY_COMMAND_NOT_SUPPORTED, unspecified()).await?;
+        anyhow::bail!("unsupported socks command {}", head[1]);
+    }
+    let target = match head[3] {
+        1 => {
+            let mut ip = [0; 4];
+            conn.read_exact(&mut ip).await?;
+            let port = conn.read_u16().await?;
+            TargetAddr::Ip(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+        }
+        3 => {
+            let mut domain = vec![0; conn.read_u8().await? as usize];
+            conn.read_exact(&mut domain).await?;
+            let port = conn.read_u16().await?;
+            TargetAddr::Domain(Cow::Owned(String::from_utf8(domain)?), port)
+        }
+        4 => {
+            let mut ip = [0; 16];
+            conn.read_exact(&mut ip).await?;
+            let port = conn.read_u16().await?;
+            TargetAddr::Ip(SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+        }
+        atyp => {
+            socks5_reply(conn, REPLY_ADDRESS_NOT_SUPPORTED, unspecified()).await?;
+            anyhow::bail!("unsupported socks address type {}", atyp);
+        }
+    };
+    Ok(Request {
+        version: Version::Socks5,
+        target,
+    })
+}
+
+async fn accept_socks4(conn: &mut TcpStream) -> anyhow::Result<Request> {
+    let command = conn.read_u8().await?;
+    let port = conn.read_u16().await?;
+    let mut ip = [0; 4];
+    conn.read_exact(&mut ip).await?;
+    // user id, not used
+    read_cstr(conn).await?;
+    // socks4a: 0.0.0.x with x != 0 means a domain follows
+    let target = if ip[..3] == [0, 0, 0] && ip[3] != 0 {
+        let domain = String::from_utf8(read_cstr(conn).await?)?;
+        TargetAddr::Domain(Cow::Owned(domain), port)
+    } else {
+        TargetAddr::Ip(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+    };
+    if command != CMD_CONNECT {
+        conn.write_all(&[0, SOCKS4_REJECTED, 0, 0, 0, 0, 0, 0])
+            .await?;
+        anyhow::bail!("unsupported socks command {}", command);
+    }
+    Ok(Request {
+        version: Version::Socks4,
+        target,
+    })
+}
+
+/// Read a null terminated string of at most 255 bytes.
+async fn read_cstr(conn: &mut TcpStream) -> anyhow::Result<Vec<u8>> {
+    let mut s = Vec::new();
+    loop {
+        match conn.read_u8().await? {
+            0 => return Ok(s),
+            _ if s.len() >= 255 => anyhow::bail!("socks4 field too long"),
+            b => s.push(b),
+        }
+    }
+}
+
+async fn socks5_reply(conn: &mut TcpStream, code: u8, bound: SocketAddr) -> std::io::Result<()> {
+    let mut reply = vec![5, code, 0];
+    match bound {
+        SocketAddr::V4(addr) => {
+            reply.push(1);
+            reply.extend_from_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            reply.push(4);
+            reply.extend_from_slice(&addr.ip().octets());
+        }
+    }
+    reply.extend_from_slice(&bound.port().to_be_bytes());
+    conn.write_all(&reply).await
+}
+
+fn unspecified() -> SocketAddr {
+    SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
+}
+
+fn reply_code(e: &anyhow::Error) -> u8 {
+    if e.is::<Rejected>() {
+        return REPLY_NOT_ALLOWED;
+    }
+    match RetryOn::classify(e) {
+        Some(RetryOn::Refused) => REPLY_CONNECTION_REFUSED,
+        Some(RetryOn::Unreachable) => REPLY_HOST_UNREACHABLE,
+        Some(RetryOn::Timeout) => REPLY_TTL_EXPIRED,
+        _ => REPLY_GENERAL_FAILURE,
+    }
+}
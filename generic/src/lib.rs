@@ -0,0 +1,61 @@
+//! Forward incoming TCP connections to a target, directly or through a socks5 proxy.
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! let forwarder = socks5_forwarder::Forwarder::builder()
+//!     .listen("127.0.0.1:8000")
+//!     .target("1.1.1.1:443")
+//!     .build()?;
+//! forwarder.run().await
+//! # }
+//! ```
+
+mod admin;
+mod bench;
+mod capture;
+mod check;
+mod connect;
+mod dns;
+#[cfg(feature = "ebpf")]
+mod ebpf;
+mod forwarder;
+mod happy_eyeballs;
+mod inbound;
+mod mux;
+mod net;
+mod port_range;
+mod proxy;
+mod registry;
+mod relay;
+mod retry;
+mod rules;
+mod shadowsocks;
+mod socket;
+#[cfg(target_os = "linux")]
+mod splice;
+mod tap;
+mod tunnel;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+mod utils;
+
+pub use bench::{BenchOptions, BenchReport};
+pub use capture::CaptureConfig;
+pub use check::{CheckOptions, CheckReport, CheckStep};
+pub use connect::ConnectOptions;
+pub use dns::{DnsConfig, ResolveMode};
+pub use forwarder::{Forwarder, ForwarderBuilder, ForwarderConfig, ListenerInfo};
+pub use inbound::{HttpServer, HttpServerConfig, MuxServer, SocksServer, SocksServerConfig};
+pub use mux::MuxConfig;
+pub use net::Cidr;
+pub use proxy::{Credential, Fallback, ProxyConfig, ProxyProtocol, Secret};
+pub use registry::{ConnectionInfo, Stats};
+pub use relay::{BoxRelay, DirectRelay, PortMap, ProxiedRelay, Relay, RelayMode};
+pub use retry::{RetryOn, RetryPolicy};
+pub use rules::{Action, Matcher, Rule, RuleSet};
+pub use shadowsocks::Cipher;
+pub use socket::{BindOptions, Keepalive, ListenOptions, SocketOptions};
+pub use tap::{TapConfig, TapDirection, TapSink};
+pub use tunnel::{Agent, AgentConfig, TunnelServerConfig};
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub use uring::UringForwarder;
@@ -0,0 +1,717 @@
+//! Stream multiplexing between forwarder instances.
+//!
+//! Many streams share one long-lived TCP connection, a session. Each frame starts
+//! with a 9 byte header: kind, stream id (u32 BE) and length (u32 BE). The client
+//! opens a stream by sending OPEN with the target address as payload and writes data
+//! right away, without waiting for the server to connect the target.
+//!
+//! Every stream has a receive window, the sender never has more unacknowledged data
+//! in flight than that, and the receiver grants more with WINDOW frames as the data
+//! is consumed. So one slow stream never blocks the others on the same session.
+//!
+//! Before any frame the client proves the shared secret with the challenge of the
+//! reverse tunnel, see [`crate::tunnel`].
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use serde::Serialize;
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter, ReadBuf,
+};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tokio_socks::TargetAddr;
+
+use crate::connect::Dialer;
+use crate::proxy::Secret;
+use crate::tunnel::{self, HANDSHAKE_TIMEOUT, KIND_MUX};
+
+const KIND_DATA: u8 = 0;
+/// Length is the number of bytes granted to the sender, no payload.
+const KIND_WINDOW: u8 = 1;
+/// Payload is the target address, like `example.com:443`.
+const KIND_OPEN: u8 = 2;
+/// The sender will send no more data on the stream.
+const KIND_FIN: u8 = 3;
+/// The stream is aborted in both directions.
+const KIND_RESET: u8 = 4;
+
+const HEADER_LEN: usize = 9;
+const MAX_FRAME: usize = 16 * 1024;
+const MAX_TARGET_LEN: usize = 512;
+/// Receive window of each stream, large enough to fill long fat links.
+const WINDOW: usize = 1024 * 1024;
+
+const DEFAULT_CONNECTIONS: usize = 4;
+
+/// Multiplex outbound streams to a peer forwarder running as a mux server.
+#[derive(Debug, Clone, Serialize)]
+pub struct MuxConfig {
+    /// Address of the peer, like 10.0.0.1:7001.
+    pub peer: String,
+    /// Sessions kept to the peer, streams are spread over them.
+    pub connections: usize,
+    /// Shared with the mux server, never sent over the wire.
+    #[serde(skip)]
+    pub secret: Secret,
+}
+
+impl MuxConfig {
+    pub fn new(peer: impl Into<String>, secret: Secret) -> Self {
+        Self {
+            peer: peer.into(),
+            connections: DEFAULT_CONNECTIONS,
+            secret,
+        }
+    }
+}
+
+/// Client side of the session handshake.
+pub(crate) async fn authenticate(conn: &mut TcpStream, secret: &Secret) -> anyhow::Result<()> {
+    tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        tunnel::authenticate(conn, secret, KIND_MUX, 0),
+    )
+    .await
+    .map_err(|_| anyhow::anyhow!("mux handshake timed out"))?
+}
+
+/// Server side of the session handshake.
+pub(crate) async fn verify(conn: &mut TcpStream, secret: &Secret) -> anyhow::Result<()> {
+    let (kind, _) = tokio::time::timeout(HANDSHAKE_TIMEOUT, tunnel::verify(conn, secret))
+        .await
+        .map_err(|_| anyhow::anyhow!("mux handshake timed out"))??;
+    if kind != KIND_MUX {
+        anyhow::bail!("not a mux session");
+    }
+    Ok(())
+}
+
+struct Frame {
+    kind: u8,
+    id: u32,
+    len: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn new(kind: u8, id: u32, payload: Vec<u8>) -> Self {
+        Self {
+            kind,
+            id,
+            len: payload.len() as u32,
+            payload,
+        }
+    }
+}
+
+#[derive(Default)]
+struct StreamInner {
+    received: VecDeque<Vec<u8>>,
+    /// Read offset into the front chunk.
+    offset: usize,
+    buffered: usize,
+    /// Bytes consumed but not yet granted back to the peer.
+    consumed: usize,
+    eof: bool,
+    reset: bool,
+    fin_sent: bool,
+    /// Bytes the peer still accepts.
+    credit: usize,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl StreamInner {
+    fn reset(&mut self) {
+        self.reset = true;
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+type StreamState = Arc<Mutex<StreamInner>>;
+
+struct Shared {
+    tx: mpsc::UnboundedSender<Frame>,
+    /// `None` once the session is closed.
+    streams: Mutex<Option<HashMap<u32, StreamState>>>,
+    peer: SocketAddr,
+    local: SocketAddr,
+    /// Notified when the last stream is gone or the session is closed.
+    idle: Notify,
+}
+
+impl Shared {
+    fn send(&self, frame: Frame) -> bool {
+        self.tx.send(frame).is_ok()
+    }
+
+    fn stream(&self, id: u32) -> Option<StreamState> {
+        self.streams.lock().unwrap().as_ref()?.get(&id).cloned()
+    }
+
+    fn insert(&self, id: u32) -> Option<StreamState> {
+        let state = Arc::new(Mutex::new(StreamInner {
+            credit: WINDOW,
+            ..Default::default()
+        }));
+        self.streams
+            .lock()
+            .unwrap()
+            .as_mut()?
+            .insert(id, state.clone());
+        Some(state)
+    }
+
+    /// Reset all streams, no more can be opened.
+    fn close(&self) {
+        if let Some(streams) = self.streams.lock().unwrap().take() {
+            for state in streams.values() {
+                state.lock().unwrap().reset();
+            }
+        }
+        self.idle.notify_waiters();
+    }
+
+    fn remove(&self, id: u32) {
+        let mut streams = self.streams.lock().unwrap();
+        if let Some(streams) = streams.as_mut() {
+            streams.remove(&id);
+            if streams.is_empty() {
+                self.idle.notify_waiters();
+            }
+        }
+    }
+
+    /// Wait until no stream is left or the session is closed.
+    async fn wait_idle(&self) {
+        loop {
+            // registered before the check so a notification in between is not lost
+            let notified = self.idle.notified();
+            match self.streams.lock().unwrap().as_ref() {
+                Some(streams) if !streams.is_empty() => {}
+                _ => return,
+            }
+            notified.await;
+        }
+    }
+}
+
+/// One multiplexed connection, closed with all its streams when dropped.
+pub(crate) struct Session {
+    shared: Arc<Shared>,
+    next_id: AtomicU32,
+    /// No more streams are opened, the open ones go on.
+    draining: AtomicBool,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Session {
+    /// Open streams on the connection to a mux server.
+    pub(crate) fn client(conn: TcpStream) -> anyhow::Result<Self> {
+        let (session, _) = Self::start(conn, false)?;
+        Ok(session)
+    }
+
+    /// Serve a connection from a mux client which passed [`verify`], opened streams
+    /// and their targets are received from the returned channel until the session is
+    /// closed.
+    pub(crate) fn server(
+        conn: TcpStream,
+    ) -> anyhow::Result<(Self, mpsc::UnboundedReceiver<(MuxStream, String)>)> {
+        Self::start(conn, true)
+    }
+
+    fn start(
+        conn: TcpStream,
+        server: bool,
+    ) -> anyhow::Result<(Self, mpsc::UnboundedReceiver<(MuxStream, String)>)> {
+        let peer = conn.peer_addr()?;
+        let local = conn.local_addr()?;
+        let (r, w) = conn.into_split();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (accept_tx, accept_rx) = mpsc::unbounded_channel();
+        let shared = Arc::new(Shared {
+            tx,
+            streams: Mutex::new(Some(HashMap::new())),
+            peer,
+            local,
+            idle: Notify::new(),
+        });
+        let reader = {
+            let shared = shared.clone();
+            let accept_tx = if server { Some(accept_tx) } else { None };
+            tokio::spawn(async move {
+                match read_loop(r, &shared, accept_tx).await {
+                    Ok(()) => tracing::debug!("Mux session with {} closed", shared.peer),
+                    Err(e) => tracing::warn!("Mux session with {} failed: {}", shared.peer, e),
+                }
+                shared.close();
+            })
+        };
+        let writer = {
+            let shared = shared.clone();
+            tokio::spawn(async move {
+                if let Err(e) = write_loop(w, rx).await {
+                    tracing::warn!("Mux session with {} failed: {}", shared.peer, e);
+                }
+                shared.close();
+            })
+        };
+        Ok((
+            Self {
+                shared,
+                next_id: AtomicU32::new(1),
+                draining: AtomicBool::new(false),
+                tasks: vec![reader, writer],
+            },
+            accept_rx,
+        ))
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.shared.streams.lock().unwrap().is_none()
+    }
+
+    pub(crate) fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Open a stream to the target, the server connects it while data is already sent.
+    ///
+    /// Stream ids are never reused, the session drains once they run out.
+    pub(crate) fn open(&self, target: &str) -> anyhow::Result<MuxStream> {
+        if self.is_draining() {
+            anyhow::bail!("mux session is draining");
+        }
+        let id = match self
+            .next_id
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |id| id.checked_add(2))
+        {
+            Ok(id) => id,
+            Err(_) => {
+                self.draining.store(true, Ordering::Relaxed);
+                anyhow::bail!("mux stream ids are exhausted, session draining");
+            }
+        };
+        let state = self
+            .shared
+            .insert(id)
+            .ok_or_else(|| anyhow::anyhow!("mux session is closed"))?;
+        let stream = MuxStream {
+            id,
+            state,
+            shared: self.shared.clone(),
+        };
+        if !self
+            .shared
+            .send(Frame::new(KIND_OPEN, id, target.as_bytes().to_vec()))
+        {
+            anyhow::bail!("mux session is closed");
+        }
+        Ok(stream)
+    }
+
+    /// Close the session in the background once its streams are finished.
+    fn retire(self) {
+        tokio::spawn(async move {
+            self.shared.wait_idle().await;
+            tracing::info!("Mux session to {} drained", self.shared.peer);
+        });
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        for task in self.tasks.iter() {
+            task.abort();
+        }
+        self.shared.close();
+    }
+}
+
+async fn read_loop(
+    r: OwnedReadHalf,
+    shared: &Arc<Shared>,
+    accept_tx: Option<mpsc::UnboundedSender<(MuxStream, String)>>,
+) -> anyhow::Result<()> {
+    let mut r = BufReader::new(r);
+    let mut header = [0; HEADER_LEN];
+    loop {
+        match r.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        let kind = header[0];
+        let id = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+        let len = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as usize;
+        match kind {
+            KIND_DATA => {
+                if len > MAX_FRAME {
+                    anyhow::bail!("frame of {} bytes is too large", len);
+                }
+                let mut data = vec![0; len];
+                r.read_exact(&mut data).await?;
+                // the stream may be dropped here already, it was reset then
+                if let Some(state) = shared.stream(id) {
+                    let mut state = state.lock().unwrap();
+                    if state.buffered + len > WINDOW {
+                        anyhow::bail!("stream {} exceeds its window", id);
+                    }
+                    state.buffered += len;
+                    state.received.push_back(data);
+                    if let Some(waker) = state.read_waker.take() {
+                        waker.wake();
+                    }
+                }
+            }
+            KIND_WINDOW => {
+                if let Some(state) = shared.stream(id) {
+                    let mut state = state.lock().unwrap();
+                    state.credit += len;
+                    if let Some(waker) = state.write_waker.take() {
+                        waker.wake();
+                    }
+                }
+            }
+            KIND_OPEN => {
+                let accept_tx = accept_tx
+                    .as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("streams are opened by clients only"))?;
+                if len > MAX_TARGET_LEN {
+                    anyhow::bail!("target of {} bytes is too long", len);
+                }
+                let mut target = vec![0; len];
+                r.read_exact(&mut target).await?;
+                let target = String::from_utf8(target)?;
+                if shared.stream(id).is_some() {
+                    anyhow::bail!("stream {} is already open", id);
+                }
+                if let Some(state) = shared.insert(id) {
+                    let stream = MuxStream {
+                        id,
+                        state,
+                        shared: shared.clone(),
+                    };
+                    let _ = accept_tx.send((stream, target));
+                }
+            }
+            KIND_FIN => {
+                if let Some(state) = shared.stream(id) {
+                    let mut state = state.lock().unwrap();
+                    state.eof = true;
+                    if let Some(waker) = state.read_waker.take() {
+                        waker.wake();
+                    }
+                }
+            }
+            KIND_RESET => {
+                if let Some(state) = shared.stream(id) {
+                    state.lock().unwrap().reset();
+                }
+            }
+            _ => anyhow::bail!("unknown frame kind {}", kind),
+        }
+    }
+}
+
+async fn write_loop(
+    w: OwnedWriteHalf,
+    mut rx: mpsc::UnboundedReceiver<Frame>,
+) -> std::io::Result<()> {
+    let mut w = BufWriter::new(w);
+    while let Some(mut frame) = rx.recv().await {
+        loop {
+            let mut header = [0; HEADER_LEN];
+            header[0] = frame.kind;
+            header[1..5].copy_from_slice(&frame.id.to_be_bytes());
+            header[5..].copy_from_slice(&frame.len.to_be_bytes());
+            w.write_all(&header).await?;
+            w.write_all(&frame.payload).await?;
+            // batch queued frames into as few writes as possible
+            match rx.try_recv() {
+                Ok(next) => frame = next,
+                Err(_) => break,
+            }
+        }
+        w.flush().await?;
+    }
+    w.shutdown().await
+}
+
+/// Stream multiplexed over a session.
+pub(crate) struct MuxStream {
+    id: u32,
+    state: StreamState,
+    shared: Arc<Shared>,
+}
+
+impl MuxStream {
+    /// Address of the peer the session is connected to.
+    pub(crate) fn peer_addr(&self) -> SocketAddr {
+        self.shared.peer
+    }
+
+    pub(crate) fn local_addr(&self) -> SocketAddr {
+        self.shared.local
+    }
+}
+
+fn reset_error() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::ConnectionReset, "mux stream is reset")
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let mut state = self.state.lock().unwrap();
+        if state.buffered == 0 {
+            if state.reset {
+                return Poll::Ready(Err(reset_error()));
+            }
+            if state.eof {
+                return Poll::Ready(Ok(()));
+            }
+            state.read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let state = &mut *state;
+        while buf.remaining() > 0 {
+            let chunk = match state.received.front() {
+                Some(chunk) => chunk,
+                None => break,
+            };
+            let n = buf.remaining().min(chunk.len() - state.offset);
+            buf.put_slice(&chunk[state.offset..state.offset + n]);
+            state.offset += n;
+            state.buffered -= n;
+            state.consumed += n;
+            if state.offset == chunk.len() {
+                state.received.pop_front();
+                state.offset = 0;
+            }
+        }
+        // grant the window back in batches
+        if state.consumed >= WINDOW / 2 && !state.reset {
+            let granted = std::mem::take(&mut state.consumed);
+            let mut frame = Frame::new(KIND_WINDOW, self.id, Vec::new());
+            frame.len = granted as u32;
+            self.shared.send(frame);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.reset {
+            return Poll::Ready(Err(reset_error()));
+        }
+        if state.fin_sent {
+            return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+        }
+        if state.credit == 0 {
+            state.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(state.credit).min(MAX_FRAME);
+        state.credit -= n;
+        if !self
+            .shared
+            .send(Frame::new(KIND_DATA, self.id, buf[..n].to_vec()))
+        {
+            return Poll::Ready(Err(reset_error()));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.fin_sent && !state.reset {
+            state.fin_sent = true;
+            self.shared.send(Frame::new(KIND_FIN, self.id, Vec::new()));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        let state = self.state.lock().unwrap();
+        let finished = state.reset || (state.fin_sent && state.eof);
+        if !finished {
+            self.shared
+                .send(Frame::new(KIND_RESET, self.id, Vec::new()));
+        }
+        drop(state);
+        self.shared.remove(self.id);
+    }
+}
+
+/// Sessions to a mux server, dialed on demand and redialed once closed or draining.
+pub(crate) struct MuxPool {
+    config: MuxConfig,
+    dialer: Arc<Dialer>,
+    sessions: Vec<tokio::sync::Mutex<Option<Session>>>,
+    next: AtomicUsize,
+}
+
+impl MuxPool {
+    pub(crate) fn new(config: MuxConfig, dialer: Arc<Dialer>) -> anyhow::Result<Self> {
+        if config.connections == 0 {
+            anyhow::bail!("mux connections must not be zero");
+        }
+        tunnel::check_secret(&config.secret)?;
+        let sessions = (0..config.connections)
+            .map(|_| tokio::sync::Mutex::new(None))
+            .collect();
+        Ok(Self {
+            config,
+            dialer,
+            sessions,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    pub(crate) fn peer(&self) -> &str {
+        &self.config.peer
+    }
+
+    /// Open a stream to the target on the next session, sessions take turns.
+    pub(crate) async fn open(&self, target: &TargetAddr<'_>) -> anyhow::Result<MuxStream> {
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut session = self.sessions[next % self.sessions.len()].lock().await;
+        let usable = match session.as_ref() {
+            Some(session) => !session.is_closed() && !session.is_draining(),
+            None => false,
+        };
+        if !usable {
+            self.replace(&mut session).await?;
+        }
+        let target = target.to_string();
+        match session.as_ref().unwrap().open(&target) {
+            // the stream ids ran out, go on with a new session
+            Err(_) if session.as_ref().unwrap().is_draining() => {
+                self.replace(&mut session).await?;
+                session.as_ref().unwrap().open(&target)
+            }
+            result => result,
+        }
+    }
+
+    /// Dial a new session into the slot, the old one is closed once it is drained.
+    async fn replace(&self, slot: &mut Option<Session>) -> anyhow::Result<()> {
+        let session = self.dial().await?;
+        if let Some(old) = slot.replace(session) {
+            old.retire();
+        }
+        Ok(())
+    }
+
+    async fn dial(&self) -> anyhow::Result<Session> {
+        let mut conn = self.dialer.connect(self.config.peer.as_str()).await?;
+        authenticate(&mut conn, &self.config.secret).await?;
+        tracing::info!("Mux session to {} opened", self.config.peer);
+        Session::client(conn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::connect::ConnectOptions;
+
+    #[tokio::test]
+    async fn stream_ids_exhausted() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let conn = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut peer, _) = listener.accept().await.unwrap();
+        let session = Session::client(conn).unwrap();
+
+        session.next_id.store(u32::MAX - 2, Ordering::Relaxed);
+        let mut stream = session.open("example.com:80").unwrap();
+        assert_eq!(stream.id, u32::MAX - 2);
+        // the next id would wrap around to ids already used
+        assert!(session.open("example.com:80").is_err());
+        assert!(session.is_draining());
+        assert!(!session.is_closed());
+
+        // the open stream goes on
+        stream.write_all(b"hello").await.unwrap();
+        let mut frames = vec![0; HEADER_LEN * 2 + "example.com:80".len() + 5];
+        peer.read_exact(&mut frames).await.unwrap();
+        assert!(frames.ends_with(b"hello"));
+    }
+
+    #[tokio::test]
+    async fn pool_replaces_draining_session() {
+        let secret = Secret::from("secret".to_string());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (accepted_tx, mut accepted) = mpsc::unbounded_channel();
+        let server_secret = secret.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                verify(&mut conn, &server_secret).await.unwrap();
+                let _ = accepted_tx.send(Session::server(conn).unwrap());
+            }
+        });
+        let mut config = MuxConfig::new(addr.to_string(), secret);
+        config.connections = 1;
+        let dialer = Arc::new(Dialer::new(ConnectOptions::default()).unwrap());
+        let pool = MuxPool::new(config, dialer).unwrap();
+        let target = TargetAddr::Domain("example.com".into(), 80);
+
+        let mut first = pool.open(&target).await.unwrap();
+        let (_session, mut streams) = accepted.recv().await.unwrap();
+        let (mut served, _) = streams.recv().await.unwrap();
+        if let Some(session) = pool.sessions[0].lock().await.as_ref() {
+            session.next_id.store(u32::MAX, Ordering::Relaxed);
+        }
+
+        // a new session is dialed for the next stream
+        let _second = pool.open(&target).await.unwrap();
+        let _replacement = accepted.recv().await.unwrap();
+        // while the stream on the draining one still relays
+        first.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        served.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+}
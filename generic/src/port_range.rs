@@ -0,0 +1,112 @@
+//! Port range syntax of listen and target addresses, like `0.0.0.0:10000-10100`.
+use std::ops::RangeInclusive;
+
+use crate::relay::PortMap;
+
+/// Host with an optional port range, a single port is a range of one.
+struct HostPorts<'a> {
+    host: &'a str,
+    ports: Option<RangeInclusive<u16>>,
+}
+
+impl<'a> HostPorts<'a> {
+    fn parse(s: &'a str) -> anyhow::Result<Self> {
+        // the port follows the last colon, unless the colon is inside a bracketed IPv6 address
+        let (host, ports) = match s.rfind(':') {
+            Some(idx) if !s[idx..].contains(']') => (&s[..idx], Some(parse_ports(&s[idx + 1..])?)),
+            _ => (s, None),
+        };
+        if host.is_empty() {
+            anyhow::bail!("host is required in {}", s);
+        }
+        Ok(Self { host, ports })
+    }
+
+    fn with_port(&self, port: u16) -> String {
+        format!("{}:{}", self.host, port)
+    }
+}
+
+fn parse_ports(s: &str) -> anyhow::Result<RangeInclusive<u16>> {
+    let parse = |p: &str| {
+        p.parse::<u16>()
+            .map_err(|_| anyhow::anyhow!("invalid port {}", p))
+    };
+    match s.split_once('-') {
+        Some((start, end)) => {
+            let (start, end) = (parse(start)?, parse(end)?);
+            if start > end {
+                anyhow::bail!("invalid port range {}", s);
+            }
+            Ok(start..=end)
+        }
+        None => {
+            let port = parse(s)?;
+            Ok(port..=port)
+        }
+    }
+}
+
+fn display(ports: &RangeInclusive<u16>) -> String {
+    format!("{}-{}", ports.start(), ports.end())
+}
+
+/// Expand listen addresses to one address per port.
+pub(crate) fn expand(specs: &[String]) -> anyhow::Result<Vec<String>> {
+    let mut addrs = Vec::with_capacity(specs.len());
+    for spec in specs {
+        let spec = HostPorts::parse(spec)?;
+        let ports = spec
+            .ports
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("port is required in listen address {}", spec.host))?;
+        addrs.extend(ports.map(|port| spec.with_port(port)));
+    }
+    Ok(addrs)
+}
+
+/// Split the target into the address of the first port and the mapping from listen ports.
+///
+/// A target with a port range, or without port for the same ports, needs all listen
+/// addresses to share one port range of the same length.
+pub(crate) fn map_target(
+    listen: &[String],
+    target: &str,
+) -> anyhow::Result<(String, Option<PortMap>)> {
+    let target = HostPorts::parse(target)?;
+    if let Some(ports) = target.ports.as_ref() {
+        if ports.start() == ports.end() {
+            return Ok((target.with_port(*ports.start()), None));
+        }
+    }
+
+    let mut listen_ports = None;
+    for spec in listen {
+        let ports = HostPorts::parse(spec)?.ports;
+        match (listen_ports.as_ref(), ports) {
+            (_, None) => anyhow::bail!("port is required in listen address {}", spec),
+            (None, Some(ports)) => listen_ports = Some(ports),
+            (Some(expected), Some(ports)) if *expected == ports => (),
+            (Some(_), Some(_)) => {
+                anyhow::bail!("listen addresses must share one port range to map target ports")
+            }
+        }
+    }
+    let listen_ports =
+        listen_ports.ok_or_else(|| anyhow::anyhow!("at least one listen address is required"))?;
+    let target_ports = target.ports.clone().unwrap_or_else(|| listen_ports.clone());
+    if target_ports.end() - target_ports.start() != listen_ports.end() - listen_ports.start() {
+        anyhow::bail!(
+            "target port range {} does not match listen port range {}",
+            display(&target_ports),
+            display(&listen_ports)
+        );
+    }
+
+    let map = PortMap {
+        listen_start: *listen_ports.start(),
+        listen_end: *listen_ports.end(),
+        target_start: *target_ports.start(),
+    };
+    Ok((target.with_port(map.target_start), Some(map)))
+}
@@ -0,0 +1,352 @@
+//! Registry of live connections, for inspection and control at runtime.
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use futures::future::AbortHandle;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::capture::{Capture, Flow, Side};
+use crate::tap::{Mirror, Mirrors, Tap};
+
+tokio::task_local! {
+    static CURRENT: Arc<Connection>;
+}
+
+/// Bytes relayed in each direction so far.
+#[derive(Default)]
+pub(crate) struct Counters {
+    /// Client to target.
+    pub(crate) sent: AtomicU64,
+    /// Target to client.
+    pub(crate) received: AtomicU64,
+}
+
+/// Totals since the forwarder started.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Stats {
+    /// Connections made directly after the proxy failed.
+    pub fallback_to_direct: u64,
+    /// Connections made through the proxy after connecting directly failed.
+    pub fallback_to_proxy: u64,
+}
+
+#[derive(Default)]
+struct Totals {
+    fallback_to_direct: AtomicU64,
+    fallback_to_proxy: AtomicU64,
+}
+
+/// Snapshot of a live connection.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    /// Listen address the connection arrived on.
+    pub listen: String,
+    pub client: SocketAddr,
+    /// Known once the relay picked it.
+    pub target: Option<String>,
+    /// Upstream proxy address, if relayed through one.
+    pub proxy: Option<String>,
+    /// Whether the route is the fallback of the preferred one.
+    pub fallback: bool,
+    /// Client to target bytes, not counted in eBPF mode.
+    pub bytes_sent: u64,
+    /// Target to client bytes, not counted in eBPF mode.
+    pub bytes_received: u64,
+    /// Whether the connection is mirrored to the tap.
+    pub tapped: bool,
+    /// Whether the connection is recorded to the pcap capture.
+    pub captured: bool,
+    /// Unix timestamp in seconds.
+    pub started_at: u64,
+    pub age_secs: f64,
+}
+
+pub(crate) struct Connection {
+    id: u64,
+    listen: String,
+    client: SocketAddr,
+    started: Instant,
+    started_at: SystemTime,
+    route: Mutex<(Option<String>, Option<String>)>,
+    fallback: AtomicBool,
+    counters: Arc<Counters>,
+    totals: Arc<Totals>,
+    tap: Option<Arc<Tap>>,
+    capture: Option<Arc<Capture>>,
+    abort: AbortHandle,
+}
+
+impl Connection {
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn info(&self) -> ConnectionInfo {
+        let (target, proxy) = self.route.lock().unwrap().clone();
+        ConnectionInfo {
+            id: self.id,
+            listen: self.listen.clone(),
+            client: self.client,
+            target,
+            proxy,
+            fallback: self.fallback.load(Ordering::Relaxed),
+            bytes_sent: self.counters.sent.load(Ordering::Relaxed),
+            bytes_received: self.counters.received.load(Ordering::Relaxed),
+            tapped: self.tap.is_some(),
+            captured: self.capture.is_some(),
+            started_at: self
+                .started_at
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            age_secs: self.started.elapsed().as_secs_f64(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Registry {
+    next_id: AtomicU64,
+    connections: Mutex<HashMap<u64, Arc<Connection>>>,
+    totals: Arc<Totals>,
+}
+
+impl Registry {
+    /// Register a connection, it is removed when the returned guard drops.
+    pub(crate) fn insert(
+        self: &Arc<Self>,
+        listen: &str,
+        client: SocketAddr,
+        tap: Option<Arc<Tap>>,
+        capture: Option<Arc<Capture>>,
+        abort: AbortHandle,
+    ) -> Registered {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let connection = Arc::new(Connection {
+            id,
+            listen: listen.to_string(),
+            client,
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+            route: Mutex::new((None, None)),
+            fallback: AtomicBool::new(false),
+            counters: Arc::new(Counters::default()),
+            totals: self.totals.clone(),
+            tap,
+            capture,
+            abort,
+        });
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(id, connection.clone());
+        Registered {
+            registry: self.clone(),
+            connection,
+        }
+    }
+
+    pub(crate) fn stats(&self) -> Stats {
+        Stats {
+            fallback_to_direct: self.totals.fallback_to_direct.load(Ordering::Relaxed),
+            fallback_to_proxy: self.totals.fallback_to_proxy.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Live connections ordered by id.
+    pub(crate) fn list(&self) -> Vec<ConnectionInfo> {
+        let mut list: Vec<_> = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .map(|c| c.info())
+            .collect();
+        list.sort_by_key(|c| c.id);
+        list
+    }
+
+    /// Abort the relay of the connection, dropping both sockets.
+    pub(crate) fn close(&self, id: u64) -> bool {
+        match self.connections.lock().unwrap().get(&id) {
+            Some(connection) => {
+                connection.abort.abort();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Keeps a connection registered while alive.
+pub(crate) struct Registered {
+    registry: Arc<Registry>,
+    connection: Arc<Connection>,
+}
+
+impl Registered {
+    pub(crate) fn connection(&self) -> Arc<Connection> {
+        self.connection.clone()
+    }
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        self.registry
+            .connections
+            .lock()
+            .unwrap()
+            .remove(&self.connection.id);
+    }
+}
+
+/// Run the relay with `connection` as the current connection.
+pub(crate) async fn scope<F: Future>(connection: Arc<Connection>, f: F) -> F::Output {
+    CURRENT.scope(connection, f).await
+}
+
+/// Record where the current connection is relayed to, on its registry entry and span.
+pub(crate) fn record_route(target: &dyn Display, proxy: Option<&str>) {
+    let span = tracing::Span::current();
+    span.record("target", tracing::field::display(target));
+    if let Some(proxy) = proxy {
+        span.record("proxy", proxy);
+    }
+    let _ = CURRENT.try_with(|c| {
+        *c.route.lock().unwrap() = (Some(target.to_string()), proxy.map(str::to_string));
+    });
+}
+
+/// Record that the current connection falls back to another route, then record
+/// the route as [`record_route`] does.
+pub(crate) fn record_fallback(target: &dyn Display, proxy: Option<&str>) {
+    record_route(target, proxy);
+    let span = tracing::Span::current();
+    span.record("fallback", true);
+    if proxy.is_none() {
+        span.record("proxy", "direct");
+    }
+    let _ = CURRENT.try_with(|c| {
+        c.fallback.store(true, Ordering::Relaxed);
+        let total = match proxy {
+            Some(_) => &c.totals.fallback_to_proxy,
+            None => &c.totals.fallback_to_direct,
+        };
+        total.fetch_add(1, Ordering::Relaxed);
+    });
+}
+
+/// Byte counters of the current connection, detached ones outside a registered relay.
+pub(crate) fn counters() -> Arc<Counters> {
+    CURRENT.try_with(|c| c.counters.clone()).unwrap_or_default()
+}
+
+/// Whether the current connection is tapped or captured, its bytes must pass
+/// through userspace then.
+pub(crate) fn observed() -> bool {
+    CURRENT
+        .try_with(|c| c.tap.is_some() || c.capture.is_some())
+        .unwrap_or(false)
+}
+
+/// Start mirroring the current connection if it is tapped.
+pub(crate) fn open_tap() -> Option<Mirrors> {
+    CURRENT
+        .try_with(|c| c.tap.as_ref().map(|tap| tap.open(c.id)))
+        .ok()
+        .flatten()
+}
+
+/// Start capturing the current connection, relayed to `server`, if it is captured.
+pub(crate) fn open_capture(server: SocketAddr) -> Option<Arc<Flow>> {
+    CURRENT
+        .try_with(|c| {
+            c.capture
+                .as_ref()
+                .map(|capture| Arc::new(capture.open(c.client, server)))
+        })
+        .ok()
+        .flatten()
+}
+
+/// Stream observing the bytes read from it: counted, and mirrored or captured if asked.
+pub(crate) struct Observed<'a, S> {
+    inner: &'a mut S,
+    counter: &'a AtomicU64,
+    mirror: Option<Mirror>,
+    capture: Option<(Arc<Flow>, Side)>,
+}
+
+impl<'a, S> Observed<'a, S> {
+    pub(crate) fn new(inner: &'a mut S, counter: &'a AtomicU64) -> Self {
+        Self {
+            inner,
+            counter,
+            mirror: None,
+            capture: None,
+        }
+    }
+
+    pub(crate) fn mirror(mut self, mirror: Option<Mirror>) -> Self {
+        self.mirror = mirror;
+        self
+    }
+
+    /// Capture what is read as sent by `side` of the flow.
+    pub(crate) fn capture(mut self, flow: Option<Arc<Flow>>, side: Side) -> Self {
+        self.capture = flow.map(|flow| (flow, side));
+        self
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Observed<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut *self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            let data = &buf.filled()[before..];
+            self.counter.fetch_add(data.len() as u64, Ordering::Relaxed);
+            if let Some(mirror) = self.mirror.as_mut() {
+                mirror.send(data);
+            }
+            if let Some((flow, side)) = self.capture.as_ref() {
+                if !data.is_empty() {
+                    flow.data(*side, data);
+                } else if buf.remaining() > 0 {
+                    flow.fin(*side);
+                }
+            }
+        }
+        res
+    }
+}
+impl<S: AsyncWrite + Unpin> AsyncWrite for Observed<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut *self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
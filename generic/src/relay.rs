@@ -0,0 +1,557 @@
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::{future::BoxFuture, Future};
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_socks::{IntoTargetAddr, TargetAddr};
+
+use crate::capture::Side;
+use crate::connect::{ConnectOptions, Dialer};
+use crate::mux::{MuxPool, MuxStream};
+use crate::proxy::{Fallback, ProxyConfig};
+use crate::registry::{self, Observed};
+use crate::shadowsocks::ShadowsocksStream;
+
+/// Relay takes over an accepted inbound connection and forwards it somewhere.
+///
+/// Implement it to plug a custom relay into [`Forwarder`](crate::Forwarder).
+pub trait Relay {
+    type Fut: Future<Output = anyhow::Result<()>>;
+
+    fn relay(&self, conn: TcpStream) -> Self::Fut;
+
+    /// Called once before listening, return an error to refuse to start.
+    fn init_check(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+impl<R> Relay for Box<R>
+where
+    R: Relay + ?Sized,
+{
+    type Fut = R::Fut;
+
+    fn relay(&self, conn: TcpStream) -> Self::Fut {
+        (**self).relay(conn)
+    }
+
+    fn init_check(&self) -> anyhow::Result<()> {
+        (**self).init_check()
+    }
+}
+
+/// Type-erased relay, which is what [`ForwarderBuilder::build`](crate::ForwarderBuilder::build) produces.
+pub type BoxRelay = Box<dyn Relay<Fut = BoxFuture<'static, anyhow::Result<()>>> + Send + Sync>;
+
+/// How data is moved between inbound and outbound once both are connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayMode {
+    /// Use eBPF if available, userspace otherwise; splice is only used when asked for.
+    Auto,
+    /// Redirect with eBPF sockmap, fail to start if it is unavailable.
+    Ebpf,
+    /// Move data through a pipe with splice(2), linux only.
+    Splice,
+    /// Copy data in userspace.
+    Userspace,
+}
+
+impl FromStr for RelayMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(RelayMode::Auto),
+            "ebpf" => Ok(RelayMode::Ebpf),
+            "splice" => Ok(RelayMode::Splice),
+            "userspace" => Ok(RelayMode::Userspace),
+            _ => Err(anyhow::anyhow!("unknown relay mode {}", s)),
+        }
+    }
+}
+
+impl Display for RelayMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RelayMode::Auto => f.write_str("auto"),
+            RelayMode::Ebpf => f.write_str("ebpf"),
+            RelayMode::Splice => f.write_str("splice"),
+            RelayMode::Userspace => f.write_str("userspace"),
+        }
+    }
+}
+
+/// Resolved relay mode, holding the loaded eBPF maps if any.
+#[derive(Clone)]
+pub(crate) enum Transfer {
+    Copy,
+    #[cfg(target_os = "linux")]
+    Splice,
+    #[cfg(feature = "ebpf")]
+    Ebpf(crate::ebpf::SharedMaps),
+}
+
+impl Transfer {
+    pub(crate) fn new(mode: RelayMode) -> anyhow::Result<Self> {
+        let transfer = match mode {
+            RelayMode::Userspace => Transfer::Copy,
+            RelayMode::Ebpf => Self::ebpf()?,
+            RelayMode::Splice => Self::splice()?,
+            RelayMode::Auto => match Self::ebpf() {
+                Ok(transfer) => transfer,
+                Err(e) => {
+                    if cfg!(feature = "ebpf") {
+                        tracing::warn!("eBPF unavailable, fallback to userspace relay: {}", e);
+                    }
+                    Transfer::Copy
+                }
+            },
+        };
+        tracing::info!("Relay mode: {}", transfer.mode());
+        Ok(transfer)
+    }
+
+    #[cfg(feature = "ebpf")]
+    fn ebpf() -> anyhow::Result<Self> {
+        Ok(Transfer::Ebpf(crate::ebpf::load()?))
+    }
+
+    #[cfg(not(feature = "ebpf"))]
+    fn ebpf() -> anyhow::Result<Self> {
+        anyhow::bail!("eBPF support is not compiled in")
+    }
+
+    #[cfg(target_os = "linux")]
+    fn splice() -> anyhow::Result<Self> {
+        crate::splice::check()?;
+        Ok(Transfer::Splice)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn splice() -> anyhow::Result<Self> {
+        anyhow::bail!("splice is only supported on linux")
+    }
+
+    pub(crate) fn mode(&self) -> RelayMode {
+        match self {
+            Transfer::Copy => RelayMode::Userspace,
+            #[cfg(target_os = "linux")]
+            Transfer::Splice => RelayMode::Splice,
+            #[cfg(feature = "ebpf")]
+            Transfer::Ebpf(_) => RelayMode::Ebpf,
+        }
+    }
+
+    /// Streams which are not plain TCP are always relayed in userspace.
+    pub(crate) async fn relay(
+        self,
+        mut inbound: TcpStream,
+        outbound: impl Into<Outbound>,
+    ) -> anyhow::Result<()> {
+        let mut outbound = match outbound.into() {
+            Outbound::Tcp(outbound) => outbound,
+            mut outbound => {
+                tracing::info!("Start relay");
+                let server = outbound.peer_addr();
+                copy(&mut inbound, &mut outbound, server).await?;
+                tracing::info!("Relay finished");
+                return Ok(());
+            }
+        };
+        let transfer = if registry::observed() {
+            Transfer::Copy
+        } else {
+            self
+        };
+        match transfer {
+            Transfer::Copy => {
+                tracing::info!("Start relay");
+                let server = outbound.peer_addr().ok();
+                copy(&mut inbound, &mut outbound, server).await?;
+                tracing::info!("Relay finished");
+                Ok(())
+            }
+            #[cfg(target_os = "linux")]
+            Transfer::Splice => crate::splice::relay(inbound, outbound).await,
+            #[cfg(feature = "ebpf")]
+            Transfer::Ebpf(bpf) => crate::ebpf::relay(bpf, inbound, outbound).await,
+        }
+    }
+}
+
+/// Copy both directions in userspace, counting, tapping and capturing bytes of the
+/// current connection. `server` is the address the outbound stream is connected to.
+pub(crate) async fn copy<S>(
+    inbound: &mut TcpStream,
+    outbound: &mut S,
+    server: Option<SocketAddr>,
+) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let counters = registry::counters();
+    let (sent, received) = match registry::open_tap() {
+        Some(mirrors) => {
+            tracing::info!("Mirror to tap");
+            (mirrors.sent, mirrors.received)
+        }
+        None => (None, None),
+    };
+    let flow = server.and_then(registry::open_capture);
+    let mut inbound = Observed::new(inbound, &counters.sent)
+        .mirror(sent)
+        .capture(flow.clone(), Side::Client);
+    let mut outbound = Observed::new(outbound, &counters.received)
+        .mirror(received)
+        .capture(flow, Side::Server);
+    tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await?;
+    Ok(())
+}
+
+/// Outbound connection, a plain TCP one or a stream carried over another protocol.
+pub(crate) enum Outbound {
+    Tcp(TcpStream),
+    Mux(MuxStream),
+    Shadowsocks(Box<ShadowsocksStream>),
+}
+
+impl Outbound {
+    pub(crate) fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        match self {
+            Outbound::Tcp(conn) => conn.local_addr(),
+            Outbound::Mux(stream) => Ok(stream.local_addr()),
+            Outbound::Shadowsocks(stream) => stream.local_addr(),
+        }
+    }
+
+    /// Address of the connection carrying the stream.
+    pub(crate) fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Outbound::Tcp(conn) => conn.peer_addr().ok(),
+            Outbound::Mux(stream) => Some(stream.peer_addr()),
+            Outbound::Shadowsocks(stream) => stream.peer_addr().ok(),
+        }
+    }
+}
+
+impl From<TcpStream> for Outbound {
+    fn from(conn: TcpStream) -> Self {
+        Outbound::Tcp(conn)
+    }
+}
+
+impl From<MuxStream> for Outbound {
+    fn from(stream: MuxStream) -> Self {
+        Outbound::Mux(stream)
+    }
+}
+
+impl From<ShadowsocksStream> for Outbound {
+    fn from(stream: ShadowsocksStream) -> Self {
+        Outbound::Shadowsocks(Box::new(stream))
+    }
+}
+
+impl AsyncRead for Outbound {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Outbound::Tcp(conn) => Pin::new(conn).poll_read(cx, buf),
+            Outbound::Mux(stream) => Pin::new(stream).poll_read(cx, buf),
+            Outbound::Shadowsocks(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Outbound {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Outbound::Tcp(conn) => Pin::new(conn).poll_write(cx, buf),
+            Outbound::Mux(stream) => Pin::new(stream).poll_write(cx, buf),
+            Outbound::Shadowsocks(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Outbound::Tcp(conn) => Pin::new(conn).poll_flush(cx),
+            Outbound::Mux(stream) => Pin::new(stream).poll_flush(cx),
+            Outbound::Shadowsocks(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Outbound::Tcp(conn) => Pin::new(conn).poll_shutdown(cx),
+            Outbound::Mux(stream) => Pin::new(stream).poll_shutdown(cx),
+            Outbound::Shadowsocks(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Maps the local port a connection arrived on to the target port, for port ranges.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortMap {
+    pub listen_start: u16,
+    /// Inclusive.
+    pub listen_end: u16,
+    pub target_start: u16,
+}
+
+impl PortMap {
+    fn target_port(&self, local_port: u16) -> Option<u16> {
+        if local_port < self.listen_start || local_port > self.listen_end {
+            return None;
+        }
+        self.target_start
+            .checked_add(local_port - self.listen_start)
+    }
+}
+
+/// Target of the connection, with the port mapped from the local port if asked.
+fn target_for<T: IntoTargetAddr<'static>>(
+    target: T,
+    port_map: Option<PortMap>,
+    inbound: &TcpStream,
+) -> anyhow::Result<TargetAddr<'static>> {
+    let mut target = target.into_target_addr()?;
+    if let Some(map) = port_map {
+        let local_port = inbound.local_addr()?.port();
+        let port = map
+            .target_port(local_port)
+            .ok_or_else(|| anyhow::anyhow!("no target port for local port {}", local_port))?;
+        match &mut target {
+            TargetAddr::Ip(addr) => addr.set_port(port),
+            TargetAddr::Domain(_, p) => *p = port,
+        }
+    }
+    Ok(target)
+}
+
+/// Connect the target through the proxy, falling back to a direct connection or the
+/// other way round as its fallback policy says. The route taken is recorded.
+pub(crate) async fn connect_proxied<'a>(
+    dialer: &'a Dialer,
+    proxy: &'a ProxyConfig,
+    target: TargetAddr<'static>,
+) -> anyhow::Result<Outbound> {
+    match proxy.fallback {
+        Fallback::Off => {
+            registry::record_route(&target, Some(&proxy.address));
+            tracing::info!("Connect target {} via proxy {}", target, proxy.address);
+            dialer.connect_proxy(proxy, target).await
+        }
+        Fallback::Direct => {
+            registry::record_route(&target, Some(&proxy.address));
+            tracing::info!("Connect target {} via proxy {}", target, proxy.address);
+            match dialer.connect_proxy(proxy, target.clone()).await {
+                Ok(outbound) => Ok(outbound),
+                Err(e) => {
+                    tracing::warn!(
+                        "Proxy {} failed: {:#}, connect target directly",
+                        proxy.address,
+                        e
+                    );
+                    registry::record_fallback(&target, None);
+                    Ok(dialer.connect_from(target, &proxy.bind).await?.into())
+                }
+            }
+        }
+        Fallback::Proxy => {
+            registry::record_route(&target, None);
+            tracing::info!("Connect target {}", target);
+            match dialer.connect_from(target.clone(), &proxy.bind).await {
+                Ok(outbound) => Ok(outbound.into()),
+                Err(e) => {
+                    tracing::warn!(
+                        "Connect target failed: {:#}, fall back to proxy {}",
+                        e,
+                        proxy.address
+                    );
+                    registry::record_fallback(&target, Some(&proxy.address));
+                    dialer.connect_proxy(proxy, target).await
+                }
+            }
+        }
+    }
+}
+
+/// Connect to the target directly.
+pub struct DirectRelay<T> {
+    target_addr: T,
+    port_map: Option<PortMap>,
+    dialer: Arc<Dialer>,
+    transfer: Transfer,
+}
+
+/// Connect to the target through the upstream proxy.
+pub struct ProxiedRelay<T> {
+    target_addr: T,
+    port_map: Option<PortMap>,
+    proxy_config: Arc<ProxyConfig>,
+    dialer: Arc<Dialer>,
+    transfer: Transfer,
+}
+
+impl<T> DirectRelay<T>
+where
+    T: IntoTargetAddr<'static> + Clone + Send + 'static,
+{
+    pub fn new(target_addr: T, options: ConnectOptions, mode: RelayMode) -> anyhow::Result<Self> {
+        Ok(Self {
+            target_addr,
+            port_map: None,
+            dialer: Arc::new(Dialer::new(options)?),
+            transfer: Transfer::new(mode)?,
+        })
+    }
+
+    /// Connect the target port matching the port each connection arrived on.
+    pub fn port_map(mut self, port_map: Option<PortMap>) -> Self {
+        self.port_map = port_map;
+        self
+    }
+
+    /// The mode actually in use after fallback.
+    pub fn mode(&self) -> RelayMode {
+        self.transfer.mode()
+    }
+}
+
+impl<T> Relay for DirectRelay<T>
+where
+    T: IntoTargetAddr<'static> + Clone + Send + Sync + Display + 'static,
+{
+    type Fut = BoxFuture<'static, anyhow::Result<()>>;
+
+    fn relay(&self, inbound: TcpStream) -> Self::Fut {
+        let target = self.target_addr.clone();
+        let port_map = self.port_map;
+        let dialer = self.dialer.clone();
+        let transfer = self.transfer.clone();
+
+        Box::pin(async move {
+            let target = target_for(target, port_map, &inbound)?;
+            registry::record_route(&target, None);
+            tracing::info!("Connect target {}", target);
+            let outbound = dialer.connect(target).await?;
+
+            transfer.relay(inbound, outbound).await
+        })
+    }
+}
+
+impl<T> ProxiedRelay<T>
+where
+    T: IntoTargetAddr<'static> + Clone + Send + 'static,
+{
+    pub fn new(
+        target_addr: T,
+        proxy_config: ProxyConfig,
+        options: ConnectOptions,
+        mode: RelayMode,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            target_addr,
+            port_map: None,
+            proxy_config: Arc::new(proxy_config),
+            dialer: Arc::new(Dialer::new(options)?),
+            transfer: Transfer::new(mode)?,
+        })
+    }
+
+    /// Connect the target port matching the port each connection arrived on.
+    pub fn port_map(mut self, port_map: Option<PortMap>) -> Self {
+        self.port_map = port_map;
+        self
+    }
+
+    /// The mode actually in use after fallback.
+    pub fn mode(&self) -> RelayMode {
+        self.transfer.mode()
+    }
+}
+
+impl<T> Relay for ProxiedRelay<T>
+where
+    T: IntoTargetAddr<'static> + Clone + Send + Sync + Display + 'static,
+{
+    type Fut = BoxFuture<'static, anyhow::Result<()>>;
+
+    fn relay(&self, inbound: TcpStream) -> Self::Fut {
+        let target = self.target_addr.clone();
+        let port_map = self.port_map;
+        let proxy = self.proxy_config.clone();
+        let dialer = self.dialer.clone();
+        let transfer = self.transfer.clone();
+
+        Box::pin(async move {
+            let target = target_for(target, port_map, &inbound)?;
+            let outbound = connect_proxied(&dialer, &proxy, target).await?;
+
+            transfer.relay(inbound, outbound).await
+        })
+    }
+}
+
+/// Open a stream to the target over a mux session to a peer forwarder.
+pub(crate) struct MuxRelay<T> {
+    target_addr: T,
+    port_map: Option<PortMap>,
+    pool: Arc<MuxPool>,
+    transfer: Transfer,
+}
+
+impl<T> MuxRelay<T> {
+    pub(crate) fn new(target_addr: T, pool: Arc<MuxPool>, mode: RelayMode) -> anyhow::Result<Self> {
+        Ok(Self {
+            target_addr,
+            port_map: None,
+            pool,
+            transfer: Transfer::new(mode)?,
+        })
+    }
+
+    pub(crate) fn port_map(mut self, port_map: Option<PortMap>) -> Self {
+        self.port_map = port_map;
+        self
+    }
+}
+
+impl<T> Relay for MuxRelay<T>
+where
+    T: IntoTargetAddr<'static> + Clone + Send + Sync + Display + 'static,
+{
+    type Fut = BoxFuture<'static, anyhow::Result<()>>;
+
+    fn relay(&self, inbound: TcpStream) -> Self::Fut {
+        let target = self.target_addr.clone();
+        let port_map = self.port_map;
+        let pool = self.pool.clone();
+        let transfer = self.transfer.clone();
+
+        Box::pin(async move {
+            let target = target_for(target, port_map, &inbound)?;
+            registry::record_route(&target, Some(pool.peer()));
+            tracing::info!("Open stream to {} via mux peer {}", target, pool.peer());
+            let outbound = pool.open(&target).await?;
+
+            transfer.relay(inbound, outbound).await
+        })
+    }
+}
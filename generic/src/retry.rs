@@ -0,0 +1,154 @@
+use std::fmt::Display;
+use std::io;
+use std::str::FromStr;
+use std::time::Duration;
+
+use futures::Future;
+use serde::Serialize;
+
+/// Class of connect errors which may be retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RetryOn {
+    /// TCP connection refused, or the proxy replied connection refused.
+    Refused,
+    /// Connection reset or closed during the handshake, like a restarting proxy.
+    Reset,
+    /// Timed out, or the proxy replied TTL expired.
+    Timeout,
+    /// Network or host unreachable, locally or replied by the proxy.
+    Unreachable,
+    /// Socks5 general server failure reply.
+    GeneralFailure,
+}
+
+impl FromStr for RetryOn {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "refused" => Ok(RetryOn::Refused),
+            "reset" => Ok(RetryOn::Reset),
+            "timeout" => Ok(RetryOn::Timeout),
+            "unreachable" => Ok(RetryOn::Unreachable),
+            "general-failure" => Ok(RetryOn::GeneralFailure),
+            _ => Err(anyhow::anyhow!("unknown retryable error {}", s)),
+        }
+    }
+}
+
+impl Display for RetryOn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RetryOn::Refused => f.write_str("refused"),
+            RetryOn::Reset => f.write_str("reset"),
+            RetryOn::Timeout => f.write_str("timeout"),
+            RetryOn::Unreachable => f.write_str("unreachable"),
+            RetryOn::GeneralFailure => f.write_str("general-failure"),
+        }
+    }
+}
+
+impl RetryOn {
+    pub(crate) fn classify(e: &anyhow::Error) -> Option<Self> {
+        for cause in e.chain() {
+            if let Some(e) = cause.downcast_ref::<tokio_socks::Error>() {
+                use tokio_socks::Error::*;
+                return match e {
+                    Io(e) => Self::classify_io(e),
+                    ConnectionRefused => Some(RetryOn::Refused),
+                    TtlExpired => Some(RetryOn::Timeout),
+                    NetworkUnreachable | HostUnreachable => Some(RetryOn::Unreachable),
+                    GeneralSocksServerFailure => Some(RetryOn::GeneralFailure),
+                    _ => None,
+                };
+            }
+            if let Some(e) = cause.downcast_ref::<io::Error>() {
+                return Self::classify_io(e);
+            }
+        }
+        None
+    }
+
+    fn classify_io(e: &io::Error) -> Option<Self> {
+        match e.kind() {
+            io::ErrorKind::ConnectionRefused => return Some(RetryOn::Refused),
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::UnexpectedEof => return Some(RetryOn::Reset),
+            io::ErrorKind::TimedOut => return Some(RetryOn::Timeout),
+            _ => (),
+        }
+        match e.raw_os_error() {
+            Some(libc::ENETUNREACH) | Some(libc::EHOSTUNREACH) => Some(RetryOn::Unreachable),
+            _ => None,
+        }
+    }
+}
+
+/// How connecting to the proxy or target is retried while the inbound connection waits.
+#[derive(Debug, Clone, Serialize)]
+pub struct RetryPolicy {
+    /// Total attempts including the first one, 1 disables retry.
+    pub attempts: u32,
+    /// Backoff before the first retry, doubled for each following one.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Random fraction in 0.0..=1.0 taken off each backoff.
+    pub jitter: f64,
+    pub retry_on: Vec<RetryOn>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 1,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            jitter: 0.2,
+            retry_on: vec![RetryOn::Refused, RetryOn::Reset, RetryOn::GeneralFailure],
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .checked_mul(1 << (retry - 1).min(16))
+            .map_or(self.max_backoff, |b| b.min(self.max_backoff));
+        let jitter = self.jitter.clamp(0.0, 1.0) * rand::random::<f64>();
+        backoff.mul_f64(1.0 - jitter)
+    }
+
+    fn should_retry(&self, e: &anyhow::Error) -> bool {
+        matches!(RetryOn::classify(e), Some(class) if self.retry_on.contains(&class))
+    }
+
+    /// Run `f` until it succeeds, fails with an error not retryable, or attempts are used up.
+    pub(crate) async fn run<F, Fut, T>(&self, mut f: F) -> anyhow::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Ok(v) => return Ok(v),
+                Err(e) if attempt < self.attempts && self.should_retry(&e) => {
+                    let backoff = self.backoff(attempt);
+                    tracing::warn!(
+                        "Connect failed(attempt {}/{}): {}, retry in {:?}",
+                        attempt,
+                        self.attempts,
+                        e,
+                        backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
@@ -0,0 +1,393 @@
+//! Ordered routing rules picking, per destination, a direct connection, one of the
+//! named upstream proxies or a rejection.
+//!
+//! Rules files have one rule per line, `#` starts a comment:
+//!
+//! ```text
+//! proxy a socks5://10.0.0.1:1080
+//! proxy b ss://chacha20-ietf-poly1305:pass@10.0.0.2:8388 fallback=direct
+//! proxy c socks5://10.1.0.1:1080 interface=wg0
+//! domain-suffix example.com a
+//! domain-suffix example.net a resolve=local
+//! domain-suffix example.org a fallback=direct
+//! domain-keyword tracker reject
+//! cidr 10.0.0.0/8 direct
+//! cidr 172.16.0.0/12 direct bind=172.16.0.2 fwmark=100
+//! cidr-file cn.txt direct
+//! port 6881-6889 reject
+//! client 192.168.1.0/24 b
+//! final a
+//! ```
+//!
+//! The first matching rule wins, `final` is the action when none matches, `direct`
+//! by default. CIDR rules resolve domain destinations, files list one network per
+//! line and are relative to the rules file. Options after a proxy, on its declaration
+//! or on a rule, override the defaults for it. `bind=`, `interface=` and `fwmark=`
+//! set the source binding and apply to direct rules too.
+use std::borrow::Cow;
+use std::fmt::{self, Display};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+use tokio_socks::TargetAddr;
+
+use crate::dns::ResolveMode;
+use crate::net::Cidr;
+use crate::proxy::{Fallback, ProxyConfig};
+use crate::socket::BindOptions;
+
+/// Condition on the destination or the client of a connection.
+#[derive(Debug, Clone)]
+pub enum Matcher {
+    /// The domain or one of its subdomains.
+    DomainSuffix(String),
+    DomainKeyword(String),
+    /// Destination address, domains are resolved to match.
+    Cidr(Vec<Cidr>),
+    /// Destination port range, inclusive.
+    Port(u16, u16),
+    Client(Vec<Cidr>),
+}
+
+/// Where a matching connection goes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Direct,
+    /// Through the proxy of this name.
+    Proxy(String),
+    Reject,
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Direct => f.write_str("direct"),
+            Action::Proxy(name) => write!(f, "proxy {}", name),
+            Action::Reject => f.write_str("reject"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub matcher: Matcher,
+    pub action: Action,
+    /// Where the target is resolved when the action is a proxy, as the proxy says
+    /// when `None`.
+    pub resolve: Option<ResolveMode>,
+    /// Fallback when the action is a proxy, the one of the proxy when `None`.
+    pub fallback: Option<Fallback>,
+    /// Source binding of direct and proxied connections, the options not set are
+    /// taken from the proxy, then from the outbound options.
+    pub bind: BindOptions,
+}
+
+impl Rule {
+    pub fn new(matcher: Matcher, action: Action) -> Self {
+        Self {
+            matcher,
+            action,
+            resolve: None,
+            fallback: None,
+            bind: BindOptions::default(),
+        }
+    }
+
+    pub fn with_resolve(mut self, resolve: ResolveMode) -> Self {
+        self.resolve = Some(resolve);
+        self
+    }
+
+    pub fn with_fallback(mut self, fallback: Fallback) -> Self {
+        self.fallback = Some(fallback);
+        self
+    }
+
+    pub fn with_bind(mut self, bind: BindOptions) -> Self {
+        self.bind = bind;
+        self
+    }
+
+    fn overrides(&self) -> bool {
+        self.resolve.is_some() || self.fallback.is_some() || !self.bind.is_empty()
+    }
+}
+
+/// Ordered rules and the named proxies they refer to.
+#[derive(Debug, Clone)]
+pub struct RuleSet {
+    proxies: Vec<(String, ProxyConfig)>,
+    rules: Vec<Rule>,
+    default: Action,
+}
+
+/// Route picked for a connection.
+pub(crate) enum Route<'a> {
+    /// Source binding options of the rule, the outbound ones stand in for the others.
+    Direct(BindOptions),
+    /// Owned when the rule overrides settings of the proxy.
+    Proxy(Cow<'a, ProxyConfig>),
+    Reject,
+}
+
+/// Error of connections rejected by a rule.
+#[derive(Debug)]
+pub(crate) struct Rejected;
+
+impl Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("rejected by rule")
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+impl Default for RuleSet {
+    fn default() -> Self {
+        Self {
+            proxies: Vec::new(),
+            rules: Vec::new(),
+            default: Action::Direct,
+        }
+    }
+}
+
+impl RuleSet {
+    /// Declare a proxy rules can refer to by name.
+    pub fn proxy(mut self, name: impl Into<String>, proxy: ProxyConfig) -> Self {
+        self.proxies.push((name.into(), proxy));
+        self
+    }
+
+    /// Append a rule, evaluated after the ones added before.
+    pub fn rule(self, matcher: Matcher, action: Action) -> Self {
+        self.add_rule(Rule::new(matcher, action))
+    }
+
+    /// Append a rule with its options, evaluated after the ones added before.
+    pub fn add_rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Action when no rule matches, `direct` by default.
+    pub fn default_action(mut self, action: Action) -> Self {
+        self.default = action;
+        self
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Load a rules file.
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("read rules {} failed: {}", path.display(), e))?;
+        Self::parse(&content, path.parent().unwrap_or_else(|| Path::new(".")))
+    }
+
+    /// Parse rules, CIDR files are relative to `dir`. Errors tell the line but never
+    /// its content, which may carry a proxy password.
+    pub fn parse(content: &str, dir: &Path) -> anyhow::Result<Self> {
+        let mut set = Self::default();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            set = set
+                .parse_line(line, dir)
+                .map_err(|e| anyhow::anyhow!("rules line {}: {}", i + 1, e))?;
+        }
+        set.check()?;
+        Ok(set)
+    }
+
+    fn parse_line(self, line: &str, dir: &Path) -> anyhow::Result<Self> {
+        let words: Vec<_> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["proxy", name, url, options @ ..] => {
+                let mut proxy: ProxyConfig = url.parse()?;
+                for option in options {
+                    match option.split_once('=') {
+                        Some(("fallback", fallback)) => {
+                            proxy = proxy.with_fallback(fallback.parse()?)
+                        }
+                        Some(("resolve", resolve)) => proxy = proxy.with_resolve(resolve.parse()?),
+                        Some((key, value)) if proxy.bind.parse_option(key, value)? => {}
+                        _ => anyhow::bail!("unknown proxy option {}", option),
+                    }
+                }
+                Ok(self.proxy(*name, proxy))
+            }
+            ["final", action] => Ok(self.default_action(parse_action(action))),
+            [kind, value, action, options @ ..] => {
+                let matcher = match *kind {
+                    "domain-suffix" => {
+                        Matcher::DomainSuffix(value.trim_start_matches('.').to_lowercase())
+                    }
+                    "domain-keyword" => Matcher::DomainKeyword(value.to_lowercase()),
+                    "cidr" => Matcher::Cidr(vec![value.parse()?]),
+                    "cidr-file" => Matcher::Cidr(read_cidr_file(&dir.join(value))?),
+                    "port" => {
+                        let (lo, hi) = value.split_once('-').unwrap_or((value, value));
+                        let invalid = || anyhow::anyhow!("invalid port {}", value);
+                        let lo = lo.parse().map_err(|_| invalid())?;
+                        let hi = hi.parse().map_err(|_| invalid())?;
+                        if lo > hi {
+                            return Err(invalid());
+                        }
+                        Matcher::Port(lo, hi)
+                    }
+                    "client" => Matcher::Client(vec![value.parse()?]),
+                    _ => anyhow::bail!("unknown rule {}", kind),
+                };
+                let mut rule = Rule::new(matcher, parse_action(action));
+                for option in options {
+                    match option.split_once('=') {
+                        Some(("resolve", resolve)) => rule = rule.with_resolve(resolve.parse()?),
+                        Some(("fallback", fallback)) => {
+                            rule = rule.with_fallback(fallback.parse()?)
+                        }
+                        Some((key, value)) if rule.bind.parse_option(key, value)? => {}
+                        _ => anyhow::bail!("unknown rule option {}", option),
+                    }
+                }
+                Ok(self.add_rule(rule))
+            }
+            _ => anyhow::bail!("expected `<rule> <value> <action>`"),
+        }
+    }
+
+    /// Make sure every action refers to a declared proxy, only proxy actions have
+    /// proxy options and rejections have none.
+    pub(crate) fn check(&self) -> anyhow::Result<()> {
+        for rule in self.rules.iter() {
+            let proxied = matches!(rule.action, Action::Proxy(_));
+            if (rule.resolve.is_some() || rule.fallback.is_some()) && !proxied {
+                anyhow::bail!("resolve and fallback of a rule only apply to proxy actions");
+            }
+            if rule.action == Action::Reject && rule.overrides() {
+                anyhow::bail!("options of a rule do not apply to reject actions");
+            }
+            rule.bind.check()?;
+        }
+        for (_, proxy) in self.proxies.iter() {
+            proxy.bind.check()?;
+        }
+        let actions = self.rules.iter().map(|r| &r.action);
+        for action in actions.chain(std::iter::once(&self.default)) {
+            if let Action::Proxy(name) = action {
+                if self.find_proxy(name).is_none() {
+                    anyhow::bail!("proxy {} is not declared", name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn find_proxy(&self, name: &str) -> Option<&ProxyConfig> {
+        self.proxies.iter().find(|(n, _)| n == name).map(|(_, p)| p)
+    }
+
+    /// Evaluate the rules in order, `resolve` is called at most once when a CIDR
+    /// rule meets a domain destination.
+    pub(crate) async fn route<F, Fut>(
+        &self,
+        target: &TargetAddr<'_>,
+        client: SocketAddr,
+        resolve: F,
+    ) -> (Option<usize>, Route<'_>)
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = anyhow::Result<Vec<SocketAddr>>>,
+    {
+        let (domain, port) = match target {
+            TargetAddr::Ip(addr) => (None, addr.port()),
+            TargetAddr::Domain(domain, port) => (Some(domain.to_lowercase()), *port),
+        };
+        let mut resolve = Some(resolve);
+        let mut ips: Vec<IpAddr> = match target {
+            TargetAddr::Ip(addr) => vec![addr.ip()],
+            TargetAddr::Domain(..) => Vec::new(),
+        };
+
+        for (i, rule) in self.rules.iter().enumerate() {
+            let matched = match &rule.matcher {
+                Matcher::DomainSuffix(suffix) => match domain.as_deref() {
+                    Some(domain) => {
+                        domain == suffix
+                            || (domain.ends_with(suffix.as_str())
+                                && domain[..domain.len() - suffix.len()].ends_with('.'))
+                    }
+                    None => false,
+                },
+                Matcher::DomainKeyword(keyword) => match domain.as_deref() {
+                    Some(domain) => domain.contains(keyword.as_str()),
+                    None => false,
+                },
+                Matcher::Cidr(networks) => {
+                    if let Some(resolve) = resolve.take() {
+                        if domain.is_some() {
+                            match resolve().await {
+                                Ok(addrs) => ips = addrs.iter().map(SocketAddr::ip).collect(),
+                                Err(e) => tracing::warn!("Resolve for rules failed: {:#}", e),
+                            }
+                        }
+                    }
+                    networks
+                        .iter()
+                        .any(|net| ips.iter().any(|ip| net.contains(*ip)))
+                }
+                Matcher::Port(lo, hi) => *lo <= port && port <= *hi,
+                Matcher::Client(networks) => networks.iter().any(|net| net.contains(client.ip())),
+            };
+            if matched {
+                let route = match self.resolve_action(&rule.action) {
+                    Route::Direct(_) => Route::Direct(rule.bind.clone()),
+                    Route::Proxy(proxy) if rule.overrides() => {
+                        let mut proxy = proxy.into_owned();
+                        proxy.resolve = rule.resolve.or(proxy.resolve);
+                        proxy.fallback = rule.fallback.unwrap_or(proxy.fallback);
+                        proxy.bind = rule.bind.or(&proxy.bind);
+                        Route::Proxy(Cow::Owned(proxy))
+                    }
+                    route => route,
+                };
+                return (Some(i), route);
+            }
+        }
+        (None, self.resolve_action(&self.default))
+    }
+
+    fn resolve_action(&self, action: &Action) -> Route<'_> {
+        match action {
+            Action::Direct => Route::Direct(BindOptions::default()),
+            Action::Reject => Route::Reject,
+            Action::Proxy(name) => Route::Proxy(Cow::Borrowed(
+                self.find_proxy(name).expect("proxies are checked on load"),
+            )),
+        }
+    }
+}
+
+fn parse_action(action: &str) -> Action {
+    match action {
+        "direct" => Action::Direct,
+        "reject" => Action::Reject,
+        name => Action::Proxy(name.to_string()),
+    }
+}
+
+fn read_cidr_file(path: &Path) -> anyhow::Result<Vec<Cidr>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("read {} failed: {}", path.display(), e))?;
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(str::parse)
+        .collect()
+}
@@ -0,0 +1,414 @@
+//! Shadowsocks AEAD client (SIP004), the outbound side only.
+//!
+//! Each direction starts with a random salt, from which a subkey is derived with
+//! HKDF-SHA1. Data follows in chunks of an encrypted 2 byte length and the encrypted
+//! payload, each sealed with its own tag and an incrementing little-endian nonce. The
+//! first chunk the client sends carries the target address in socks5 format.
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+
+use aes_gcm::aead::{AeadInPlace, NewAead};
+use aes_gcm::{Aes128Gcm, Aes256Gcm};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use hkdf::Hkdf;
+use md5::{Digest, Md5};
+use serde::Serialize;
+use sha1::Sha1;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_socks::TargetAddr;
+use zeroize::Zeroizing;
+
+use crate::proxy::Secret;
+
+const TAG_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const MAX_PAYLOAD: usize = 0x3fff;
+const READ_BUF: usize = 16 * 1024;
+
+/// AEAD cipher of a Shadowsocks server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Cipher {
+    #[serde(rename = "aes-128-gcm")]
+    Aes128Gcm,
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    #[serde(rename = "chacha20-ietf-poly1305")]
+    Chacha20IetfPoly1305,
+}
+
+impl Cipher {
+    fn key_len(self) -> usize {
+        match self {
+            Cipher::Aes128Gcm => 16,
+            Cipher::Aes256Gcm | Cipher::Chacha20IetfPoly1305 => 32,
+        }
+    }
+}
+
+impl FromStr for Cipher {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aes-128-gcm" => Ok(Cipher::Aes128Gcm),
+            "aes-256-gcm" => Ok(Cipher::Aes256Gcm),
+            "chacha20-ietf-poly1305" => Ok(Cipher::Chacha20IetfPoly1305),
+            _ => Err(anyhow::anyhow!("unsupported shadowsocks cipher {}", s)),
+        }
+    }
+}
+
+impl Display for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Cipher::Aes128Gcm => f.write_str("aes-128-gcm"),
+            Cipher::Aes256Gcm => f.write_str("aes-256-gcm"),
+            Cipher::Chacha20IetfPoly1305 => f.write_str("chacha20-ietf-poly1305"),
+        }
+    }
+}
+
+/// Master key from the password, like OpenSSL's EVP_BytesToKey with MD5.
+fn master_key(password: &Secret, len: usize) -> Zeroizing<Vec<u8>> {
+    let mut key = Zeroizing::new(Vec::with_capacity(len + 16));
+    let mut prev: Vec<u8> = Vec::new();
+    while key.len() < len {
+        let mut md5 = Md5::new();
+        md5.update(&prev);
+        md5.update(password.expose().as_bytes());
+        prev = md5.finalize().to_vec();
+        key.extend_from_slice(&prev);
+    }
+    key.truncate(len);
+    key
+}
+
+/// Sealing or opening side of one direction.
+struct Session {
+    aead: Aead,
+    nonce: [u8; NONCE_LEN],
+}
+
+enum Aead {
+    Aes128Gcm(Box<Aes128Gcm>),
+    Aes256Gcm(Box<Aes256Gcm>),
+    Chacha20(Box<ChaCha20Poly1305>),
+}
+
+impl Session {
+    fn new(cipher: Cipher, key: &[u8], salt: &[u8]) -> Self {
+        let mut subkey = Zeroizing::new(vec![0; cipher.key_len()]);
+        Hkdf::<Sha1>::new(Some(salt), key)
+            .expand(b"ss-subkey", &mut subkey)
+            .expect("subkey length is valid");
+        let aead = match cipher {
+            Cipher::Aes128Gcm => Aead::Aes128Gcm(Box::new(
+                Aes128Gcm::new_from_slice(&subkey).expect("key length matches"),
+            )),
+            Cipher::Aes256Gcm => Aead::Aes256Gcm(Box::new(
+                Aes256Gcm::new_from_slice(&subkey).expect("key length matches"),
+            )),
+            Cipher::Chacha20IetfPoly1305 => Aead::Chacha20(Box::new(
+                ChaCha20Poly1305::new_from_slice(&subkey).expect("key length matches"),
+            )),
+        };
+        Self {
+            aead,
+            nonce: [0; NONCE_LEN],
+        }
+    }
+
+    fn next_nonce(&mut self) -> Nonce {
+        let nonce = *Nonce::from_slice(&self.nonce);
+        for byte in self.nonce.iter_mut() {
+            *byte = byte.wrapping_add(1);
+            if *byte != 0 {
+                break;
+            }
+        }
+        nonce
+    }
+
+    /// Append the sealed `data` and its tag to `out`.
+    fn seal(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        let nonce = self.next_nonce();
+        let mut sealed = data.to_vec();
+        let result = match &self.aead {
+            Aead::Aes128Gcm(aead) => aead.encrypt_in_place(&nonce, b"", &mut sealed),
+            Aead::Aes256Gcm(aead) => aead.encrypt_in_place(&nonce, b"", &mut sealed),
+            Aead::Chacha20(aead) => aead.encrypt_in_place(&nonce, b"", &mut sealed),
+        };
+        result.expect("buffer grows for the tag");
+        out.extend_from_slice(&sealed);
+    }
+
+    /// Open sealed data with its tag, returns the plaintext.
+    fn open(&mut self, sealed: &[u8]) -> std::io::Result<Vec<u8>> {
+        let nonce = self.next_nonce();
+        let mut data = sealed.to_vec();
+        let result = match &self.aead {
+            Aead::Aes128Gcm(aead) => aead.decrypt_in_place(&nonce, b"", &mut data),
+            Aead::Aes256Gcm(aead) => aead.decrypt_in_place(&nonce, b"", &mut data),
+            Aead::Chacha20(aead) => aead.decrypt_in_place(&nonce, b"", &mut data),
+        };
+        result.map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "shadowsocks decryption failed, check the password and cipher",
+            )
+        })?;
+        Ok(data)
+    }
+}
+
+/// Target address in socks5 format: type, address and port.
+fn encode_target(target: &TargetAddr<'_>) -> anyhow::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    match target {
+        TargetAddr::Ip(SocketAddr::V4(addr)) => {
+            out.push(1);
+            out.extend_from_slice(&addr.ip().octets());
+        }
+        TargetAddr::Ip(SocketAddr::V6(addr)) => {
+            out.push(4);
+            out.extend_from_slice(&addr.ip().octets());
+        }
+        TargetAddr::Domain(domain, _) => {
+            if domain.len() > 255 {
+                anyhow::bail!("domain {} is too long", domain);
+            }
+            out.push(3);
+            out.push(domain.len() as u8);
+            out.extend_from_slice(domain.as_bytes());
+        }
+    }
+    let port = match target {
+        TargetAddr::Ip(addr) => addr.port(),
+        TargetAddr::Domain(_, port) => *port,
+    };
+    out.extend_from_slice(&port.to_be_bytes());
+    Ok(out)
+}
+
+enum ReadState {
+    Salt,
+    Length,
+    Payload(usize),
+}
+
+/// Connection to a Shadowsocks server, carrying the stream to one target.
+pub(crate) struct ShadowsocksStream {
+    inner: TcpStream,
+    cipher: Cipher,
+    key: Zeroizing<Vec<u8>>,
+    encrypt: Session,
+    /// Sealed bytes not yet written to `inner`.
+    pending: Vec<u8>,
+    pending_pos: usize,
+    decrypt: Option<Session>,
+    state: ReadState,
+    received: Vec<u8>,
+    received_pos: usize,
+    plain: Vec<u8>,
+    plain_pos: usize,
+}
+
+impl ShadowsocksStream {
+    /// Start the stream to the target over a connection to the server, no reply is
+    /// waited for.
+    pub(crate) async fn connect(
+        inner: TcpStream,
+        cipher: Cipher,
+        password: &Secret,
+        target: &TargetAddr<'_>,
+    ) -> anyhow::Result<Self> {
+        let key = master_key(password, cipher.key_len());
+        let salt: Vec<u8> = (0..cipher.key_len()).map(|_| rand::random()).collect();
+        let mut stream = Self {
+            inner,
+            cipher,
+            encrypt: Session::new(cipher, &key, &salt),
+            key,
+            pending: salt,
+            pending_pos: 0,
+            decrypt: None,
+            state: ReadState::Salt,
+            received: Vec::new(),
+            received_pos: 0,
+            plain: Vec::new(),
+            plain_pos: 0,
+        };
+        stream.seal_chunk(&encode_target(target)?);
+        // sent right away, the target may speak first
+        stream.flush().await?;
+        Ok(stream)
+    }
+
+    pub(crate) fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub(crate) fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn seal_chunk(&mut self, data: &[u8]) {
+        debug_assert!(data.len() <= MAX_PAYLOAD);
+        let len = (data.len() as u16).to_be_bytes();
+        self.encrypt.seal(&len, &mut self.pending);
+        self.encrypt.seal(data, &mut self.pending);
+    }
+
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while self.pending_pos < self.pending.len() {
+            let n =
+                match Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.pending_pos..]) {
+                    Poll::Ready(Ok(0)) => {
+                        return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()))
+                    }
+                    Poll::Ready(Ok(n)) => n,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                };
+            self.pending_pos += n;
+        }
+        self.pending.clear();
+        self.pending_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Decrypt what is complete in the received bytes, returns false if more are needed.
+    fn decode(&mut self) -> std::io::Result<bool> {
+        let available = &self.received[self.received_pos..];
+        match self.state {
+            ReadState::Salt => {
+                let len = self.cipher.key_len();
+                if available.len() < len {
+                    return Ok(false);
+                }
+                self.decrypt = Some(Session::new(self.cipher, &self.key, &available[..len]));
+                self.received_pos += len;
+                self.state = ReadState::Length;
+            }
+            ReadState::Length => {
+                if available.len() < 2 + TAG_LEN {
+                    return Ok(false);
+                }
+                let decrypt = self.decrypt.as_mut().expect("salt is read");
+                let len = decrypt.open(&available[..2 + TAG_LEN])?;
+                let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+                if len > MAX_PAYLOAD {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("shadowsocks chunk of {} bytes is too large", len),
+                    ));
+                }
+                self.received_pos += 2 + TAG_LEN;
+                self.state = ReadState::Payload(len);
+            }
+            ReadState::Payload(len) => {
+                if available.len() < len + TAG_LEN {
+                    return Ok(false);
+                }
+                let decrypt = self.decrypt.as_mut().expect("salt is read");
+                self.plain = decrypt.open(&available[..len + TAG_LEN])?;
+                self.plain_pos = 0;
+                self.received_pos += len + TAG_LEN;
+                self.state = ReadState::Length;
+            }
+        }
+        if self.received_pos == self.received.len() {
+            self.received.clear();
+            self.received_pos = 0;
+        }
+        Ok(true)
+    }
+}
+
+impl AsyncRead for ShadowsocksStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.plain_pos < this.plain.len() {
+                let n = buf.remaining().min(this.plain.len() - this.plain_pos);
+                buf.put_slice(&this.plain[this.plain_pos..this.plain_pos + n]);
+                this.plain_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.decode()? {
+                continue;
+            }
+            if this.received_pos > 0 {
+                this.received.drain(..this.received_pos);
+                this.received_pos = 0;
+            }
+            let mut chunk = [0; READ_BUF];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+            if chunk_buf.filled().is_empty() {
+                // EOF is only clean between chunks
+                let clean = this.received.is_empty() && matches!(this.state, ReadState::Length);
+                return if clean {
+                    Poll::Ready(Ok(()))
+                } else {
+                    Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()))
+                };
+            }
+            this.received.extend_from_slice(chunk_buf.filled());
+        }
+    }
+}
+
+impl AsyncWrite for ShadowsocksStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if let Poll::Ready(Err(e)) = this.poll_write_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        // keep at most one sealed chunk in flight
+        if !this.pending.is_empty() {
+            return Poll::Pending;
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = buf.len().min(MAX_PAYLOAD);
+        this.seal_chunk(&buf[..n]);
+        if let Poll::Ready(Err(e)) = this.poll_write_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_write_pending(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_write_pending(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+#[cfg(unix)]
+use crate::utils::with_socket2;
+
+pub(crate) const DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(15);
+const DEFAULT_BACKLOG: u32 = 1024;
+
+/// TCP keepalive settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Keepalive {
+    /// Idle time before the first probe.
+    pub time: Duration,
+    /// Time between probes.
+    pub interval: Option<Duration>,
+    /// Probes to send before dropping the connection.
+    pub retries: Option<u32>,
+}
+
+impl Keepalive {
+    /// Probe after `duration` idle and then every `duration`.
+    pub fn new(duration: Duration) -> Self {
+        Self {
+            time: duration,
+            interval: Some(duration),
+            retries: None,
+        }
+    }
+}
+
+/// Options applied to accepted or outbound TCP sockets.
+///
+/// Options marked linux only make startup fail on other platforms.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SocketOptions {
+    pub keepalive: Option<Keepalive>,
+    /// `TCP_NODELAY`, system default when `None`.
+    pub nodelay: Option<bool>,
+    /// `TCP_USER_TIMEOUT`, linux only.
+    pub user_timeout: Option<Duration>,
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+    /// `TCP_CONGESTION` algorithm like `bbr`, linux only.
+    pub congestion: Option<String>,
+    /// `TCP_FASTOPEN_CONNECT`, ignored for accepted sockets, linux only.
+    pub fastopen: bool,
+}
+
+impl SocketOptions {
+    pub(crate) fn with_default_keepalive() -> Self {
+        Self {
+            keepalive: Some(Keepalive::new(DEFAULT_KEEPALIVE_TIMEOUT)),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn check(&self) -> anyhow::Result<()> {
+        if cfg!(not(any(target_os = "linux", target_os = "android")))
+            && (self.user_timeout.is_some() || self.congestion.is_some() || self.fastopen)
+        {
+            anyhow::bail!(
+                "tcp user timeout, congestion control and fastopen are only supported on linux"
+            );
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn apply<S: std::os::unix::io::AsRawFd>(&self, sock: &S) -> io::Result<()> {
+        with_socket2(sock, |socket| {
+            if let Some(keepalive) = self.keepalive {
+                #[allow(unused_mut)]
+                let mut params = socket2::TcpKeepalive::new().with_time(keepalive.time);
+                #[cfg(any(
+                    target_os = "freebsd",
+                    target_os = "fuchsia",
+                    target_os = "linux",
+                    target_os = "netbsd",
+                    target_vendor = "apple",
+                ))]
+                {
+                    if let Some(interval) = keepalive.interval {
+                        params = params.with_interval(interval);
+                    }
+                    if let Some(retries) = keepalive.retries {
+                        params = params.with_retries(retries);
+                    }
+                }
+                socket.set_tcp_keepalive(&params)?;
+            }
+            if let Some(nodelay) = self.nodelay {
+                socket.set_nodelay(nodelay)?;
+            }
+            if let Some(size) = self.send_buffer_size {
+                socket.set_send_buffer_size(size)?;
+            }
+            if let Some(size) = self.recv_buffer_size {
+                socket.set_recv_buffer_size(size)?;
+            }
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            {
+                if self.user_timeout.is_some() {
+                    socket.set_tcp_user_timeout(self.user_timeout)?;
+                }
+                if let Some(congestion) = self.congestion.as_ref() {
+                    linux::set_congestion(socket, congestion)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn apply<S>(&self, _sock: &S) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Options of listening sockets.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ListenOptions {
+    /// Applied to accepted sockets, buffer sizes are set on the listener too so they count from SYN.
+    pub socket: SocketOptions,
+    /// `SO_REUSEPORT`, so several processes can share the port.
+    pub reuse_port: bool,
+    pub backlog: u32,
+    /// `TCP_FASTOPEN` queue length, linux only.
+    pub fastopen_queue: Option<u32>,
+}
+
+impl Default for ListenOptions {
+    fn default() -> Self {
+        Self {
+            socket: SocketOptions::with_default_keepalive(),
+            reuse_port: false,
+            backlog: DEFAULT_BACKLOG,
+            fastopen_queue: None,
+        }
+    }
+}
+
+impl ListenOptions {
+    pub(crate) fn check(&self) -> anyhow::Result<()> {
+        if cfg!(not(any(target_os = "linux", target_os = "android")))
+            && self.fastopen_queue.is_some()
+        {
+            anyhow::bail!("tcp fastopen is only supported on linux");
+        }
+        self.socket.check()
+    }
+
+    /// Bind the first address `addr` resolves to.
+    pub(crate) async fn bind(&self, addr: &str) -> anyhow::Result<TcpListener> {
+        let mut last_err = None;
+        for addr in tokio::net::lookup_host(addr).await? {
+            match self.bind_addr(addr) {
+                Ok(listener) => return Ok(listener),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err
+            .map(Into::into)
+            .unwrap_or_else(|| anyhow::anyhow!("no address to listen for {}", addr)))
+    }
+
+    fn bind_addr(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        #[cfg(unix)]
+        {
+            socket.set_reuseaddr(true)?;
+            with_socket2(&socket, |socket| {
+                #[cfg(not(any(target_os = "solaris", target_os = "illumos")))]
+                if self.reuse_port {
+                    socket.set_reuse_port(true)?;
+                }
+                if let Some(size) = self.socket.send_buffer_size {
+                    socket.set_send_buffer_size(size)?;
+                }
+                if let Some(size) = self.socket.recv_buffer_size {
+                    socket.set_recv_buffer_size(size)?;
+                }
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                if let Some(queue) = self.fastopen_queue {
+                    linux::setsockopt_int(socket, libc::TCP_FASTOPEN, queue as libc::c_int)?;
+                }
+                Ok(())
+            })?;
+        }
+        socket.bind(addr)?;
+        socket.listen(self.backlog)
+    }
+}
+
+/// Source binding of outbound sockets, for multi-homed hosts and policy routing.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BindOptions {
+    /// Source IP, only targets of the same family are dialed when set.
+    pub address: Option<IpAddr>,
+    /// Interface to bind with `SO_BINDTODEVICE`, linux only.
+    pub interface: Option<String>,
+    /// `SO_MARK` for policy routing, linux only.
+    pub mark: Option<u32>,
+}
+
+impl BindOptions {
+    pub(crate) fn check(&self) -> anyhow::Result<()> {
+        if cfg!(not(any(target_os = "linux", target_os = "android")))
+            && (self.interface.is_some() || self.mark.is_some())
+        {
+            anyhow::bail!("binding interface or fwmark is only supported on linux");
+        }
+        Ok(())
+    }
+
+    /// The options set here, the ones of `base` for the others.
+    pub(crate) fn or(&self, base: &BindOptions) -> BindOptions {
+        BindOptions {
+            address: self.address.or(base.address),
+            interface: self.interface.clone().or_else(|| base.interface.clone()),
+            mark: self.mark.or(base.mark),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        *self == BindOptions::default()
+    }
+
+    /// Set the option of a `bind=`, `interface=` or `fwmark=` pair of a rules file,
+    /// returns false for other keys.
+    pub(crate) fn parse_option(&mut self, key: &str, value: &str) -> anyhow::Result<bool> {
+        match key {
+            "bind" => self.address = Some(value.parse()?),
+            "interface" => self.interface = Some(value.to_string()),
+            "fwmark" => self.mark = Some(value.parse()?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    pub(crate) fn accepts(&self, addr: &SocketAddr) -> bool {
+        match self.address {
+            Some(ip) => ip.is_ipv4() == addr.is_ipv4(),
+            None => true,
+        }
+    }
+}
+
+/// Connect to addr from the configured source with the socket options set before SYN.
+pub(crate) async fn connect(
+    addr: SocketAddr,
+    bind: &BindOptions,
+    options: &SocketOptions,
+) -> io::Result<TcpStream> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    options.apply(&socket)?;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    with_socket2(&socket, |socket| {
+        if let Some(interface) = bind.interface.as_ref() {
+            socket.bind_device(Some(interface.as_bytes()))?;
+        }
+        if let Some(mark) = bind.mark {
+            socket.set_mark(mark)?;
+        }
+        if options.fastopen {
+            linux::setsockopt_int(socket, libc::TCP_FASTOPEN_CONNECT, 1)?;
+        }
+        Ok(())
+    })?;
+    if let Some(ip) = bind.address {
+        socket.bind(SocketAddr::new(ip, 0))?;
+    }
+    socket.connect(addr).await
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod linux {
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    pub(super) fn setsockopt_int(
+        socket: &socket2::Socket,
+        name: libc::c_int,
+        value: libc::c_int,
+    ) -> io::Result<()> {
+        setsockopt(
+            socket,
+            name,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>(),
+        )
+    }
+
+    pub(super) fn set_congestion(socket: &socket2::Socket, algorithm: &str) -> io::Result<()> {
+        setsockopt(
+            socket,
+            libc::TCP_CONGESTION,
+            algorithm.as_ptr() as *const libc::c_void,
+            algorithm.len(),
+        )
+    }
+
+    fn setsockopt(
+        socket: &socket2::Socket,
+        name: libc::c_int,
+        value: *const libc::c_void,
+        len: usize,
+    ) -> io::Result<()> {
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                name,
+                value,
+                len as libc::socklen_t,
+            )
+        };
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+//! Zero-copy relay, data is moved between the sockets through a pipe with splice(2).
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+
+use crate::registry;
+use crate::utils::with_socket2;
+
+/// Default pipe capacity, so one splice never blocks on a drained pipe.
+const PIPE_SIZE: usize = 64 * 1024;
+
+struct Pipe {
+    read: RawFd,
+    write: RawFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            read: fds[0],
+            write: fds[1],
+        })
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+/// Check splice is usable, called once at startup.
+pub(crate) fn check() -> io::Result<()> {
+    Pipe::new().map(|_| ())
+}
+
+/// Relay until both directions finish, falls back to copy when pipes can not be created.
+pub(crate) async fn relay(mut inbound: TcpStream, mut outbound: TcpStream) -> anyhow::Result<()> {
+    let (inbound_pipe, outbound_pipe) = match (Pipe::new(), Pipe::new()) {
+        (Ok(i), Ok(o)) => (i, o),
+        (Err(e), _) | (_, Err(e)) => {
+            tracing::warn!("Create pipe failed, fallback to copy: {}", e);
+            tracing::info!("Start relay");
+            let server = outbound.peer_addr().ok();
+            crate::relay::copy(&mut inbound, &mut outbound, server).await?;
+            tracing::info!("Relay finished");
+            return Ok(());
+        }
+    };
+
+    tracing::info!("Start splice relay");
+    let counters = registry::counters();
+    let client_to_server = async {
+        let n = splice_one_way(&inbound, &outbound, &inbound_pipe, &counters.sent).await?;
+        tracing::info!("Relay inbound -> outbound finished");
+        Ok::<_, io::Error>(n)
+    };
+    let server_to_client = async {
+        let n = splice_one_way(&outbound, &inbound, &outbound_pipe, &counters.received).await?;
+        tracing::info!("Relay outbound -> inbound finished");
+        Ok::<_, io::Error>(n)
+    };
+    tokio::try_join!(client_to_server, server_to_client)?;
+
+    tracing::info!("Relay finished");
+    Ok(())
+}
+
+/// Move data from `src` to `dst` until EOF, then shutdown write of `dst`.
+async fn splice_one_way(
+    src: &TcpStream,
+    dst: &TcpStream,
+    pipe: &Pipe,
+    counter: &AtomicU64,
+) -> io::Result<u64> {
+    let mut total = 0;
+    loop {
+        // socket -> pipe, the pipe is always drained here so only the socket can block
+        src.readable().await?;
+        let n = match src.try_io(Interest::READABLE, || {
+            splice(src.as_raw_fd(), pipe.write, PIPE_SIZE)
+        }) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        };
+
+        // pipe -> socket
+        let mut left = n;
+        while left > 0 {
+            dst.writable().await?;
+            match dst.try_io(Interest::WRITABLE, || {
+                splice(pipe.read, dst.as_raw_fd(), left)
+            }) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(m) => left -= m,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        total += n as u64;
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    let _ = with_socket2(dst, |socket| socket.shutdown(std::net::Shutdown::Write));
+    Ok(total)
+}
+
+fn splice(fd_in: RawFd, fd_out: RawFd, len: usize) -> io::Result<usize> {
+    let ret = unsafe {
+        libc::splice(
+            fd_in,
+            std::ptr::null_mut(),
+            fd_out,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
@@ -0,0 +1,232 @@
+//! Mirror the byte streams of selected connections to a tap, for debugging.
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::Instrument;
+
+const DEFAULT_BUFFER: usize = 1024 * 1024;
+
+/// Where mirrored streams are written, each tapped direction of a connection gets its own.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TapSink {
+    /// A TCP connection to the address per stream, carrying the raw bytes.
+    Tcp(String),
+    /// A file per stream in the directory, named
+    /// `<unix time>-<connection id>-<direction>.bin`.
+    Dir(PathBuf),
+}
+
+impl FromStr for TapSink {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("tcp", addr)) => Ok(TapSink::Tcp(addr.to_string())),
+            Some(("dir", path)) => Ok(TapSink::Dir(PathBuf::from(path))),
+            _ => Err(anyhow::anyhow!(
+                "unknown tap sink {}, expect tcp:<addr> or dir:<path>",
+                s
+            )),
+        }
+    }
+}
+
+/// Which direction of a connection is mirrored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TapDirection {
+    ClientToTarget,
+    TargetToClient,
+    Both,
+}
+
+impl FromStr for TapDirection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client-to-target" => Ok(TapDirection::ClientToTarget),
+            "target-to-client" => Ok(TapDirection::TargetToClient),
+            "both" => Ok(TapDirection::Both),
+            _ => Err(anyhow::anyhow!("unknown tap direction {}", s)),
+        }
+    }
+}
+
+impl Display for TapDirection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TapDirection::ClientToTarget => f.write_str("client-to-target"),
+            TapDirection::TargetToClient => f.write_str("target-to-client"),
+            TapDirection::Both => f.write_str("both"),
+        }
+    }
+}
+
+/// Traffic mirroring settings.
+///
+/// Tapped connections are relayed in userspace whatever the relay mode, since
+/// splice and eBPF never see the bytes.
+#[derive(Debug, Clone, Serialize)]
+pub struct TapConfig {
+    pub sink: TapSink,
+    pub direction: TapDirection,
+    /// Only connections from these client IPs are tapped, all when empty.
+    pub clients: Vec<IpAddr>,
+    /// Bytes queued per stream, more are dropped so a slow sink never slows the relay.
+    pub buffer: usize,
+}
+
+impl TapConfig {
+    /// Mirror both directions of all connections to the sink.
+    pub fn new(sink: TapSink) -> Self {
+        Self {
+            sink,
+            direction: TapDirection::Both,
+            clients: Vec::new(),
+            buffer: DEFAULT_BUFFER,
+        }
+    }
+}
+
+pub(crate) struct Tap {
+    config: TapConfig,
+}
+
+impl Tap {
+    pub(crate) fn new(config: TapConfig) -> anyhow::Result<Self> {
+        if config.buffer == 0 {
+            anyhow::bail!("tap buffer must not be zero");
+        }
+        if let TapSink::Dir(dir) = &config.sink {
+            std::fs::create_dir_all(dir)?;
+        }
+        Ok(Self { config })
+    }
+
+    pub(crate) fn selects(&self, client: IpAddr) -> bool {
+        self.config.clients.is_empty() || self.config.clients.contains(&client)
+    }
+
+    /// Start the mirrors of a connection, must be called within a tokio runtime.
+    pub(crate) fn open(&self, id: u64) -> Mirrors {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let mirror = |direction: TapDirection| {
+            let name = format!("{}-{}-{}", started, id, direction);
+            Mirror::start(self.config.sink.clone(), name, self.config.buffer)
+        };
+        let direction = self.config.direction;
+        Mirrors {
+            sent: match direction {
+                TapDirection::TargetToClient => None,
+                _ => Some(mirror(TapDirection::ClientToTarget)),
+            },
+            received: match direction {
+                TapDirection::ClientToTarget => None,
+                _ => Some(mirror(TapDirection::TargetToClient)),
+            },
+        }
+    }
+}
+
+/// Mirrors of the directions a connection is tapped in.
+pub(crate) struct Mirrors {
+    pub(crate) sent: Option<Mirror>,
+    pub(crate) received: Option<Mirror>,
+}
+
+/// Queue of one mirrored stream, drained by a writer task.
+pub(crate) struct Mirror {
+    name: String,
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    queued: Arc<AtomicUsize>,
+    limit: usize,
+    dropped: u64,
+}
+
+impl Mirror {
+    fn start(sink: TapSink, name: String, limit: usize) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let queued = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(
+            write_loop(sink, name.clone(), rx, queued.clone()).instrument(tracing::Span::current()),
+        );
+        Self {
+            name,
+            tx,
+            queued,
+            limit,
+            dropped: 0,
+        }
+    }
+
+    /// Queue a copy of the data, or drop it if the queue is full or the sink failed.
+    pub(crate) fn send(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        if self.queued.load(Ordering::Relaxed) + data.len() > self.limit {
+            self.dropped += data.len() as u64;
+            return;
+        }
+        self.queued.fetch_add(data.len(), Ordering::Relaxed);
+        if self.tx.send(data.to_vec()).is_err() {
+            self.dropped += data.len() as u64;
+        }
+    }
+}
+
+impl Drop for Mirror {
+    fn drop(&mut self) {
+        if self.dropped > 0 {
+            tracing::warn!("Tap {} dropped {} bytes", self.name, self.dropped);
+        }
+    }
+}
+
+async fn write_loop(
+    sink: TapSink,
+    name: String,
+    mut rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    queued: Arc<AtomicUsize>,
+) {
+    let mut writer: Box<dyn AsyncWrite + Unpin + Send> = match &sink {
+        TapSink::Tcp(addr) => match tokio::net::TcpStream::connect(addr.as_str()).await {
+            Ok(conn) => Box::new(conn),
+            Err(e) => {
+                tracing::warn!("Connect tap {} at {} failed: {}", name, addr, e);
+                return;
+            }
+        },
+        TapSink::Dir(dir) => {
+            let path = dir.join(format!("{}.bin", name));
+            match tokio::fs::File::create(&path).await {
+                Ok(file) => Box::new(file),
+                Err(e) => {
+                    tracing::warn!("Create tap file {} failed: {}", path.display(), e);
+                    return;
+                }
+            }
+        }
+    };
+    while let Some(chunk) = rx.recv().await {
+        if let Err(e) = writer.write_all(&chunk).await {
+            tracing::warn!("Write tap {} failed: {}", name, e);
+            return;
+        }
+        queued.fetch_sub(chunk.len(), Ordering::Relaxed);
+    }
+    let _ = writer.shutdown().await;
+}
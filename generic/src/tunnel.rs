@@ -0,0 +1,477 @@
+//! Reverse tunnel for targets behind NAT.
+//!
+//! An agent next to the target dials the public tunnel server and keeps a control
+//! connection. For each accepted client the server asks the agent over it to open a
+//! stream, the agent dials back a data connection carrying the stream id, which the
+//! server pairs with the waiting client, and then connects the target.
+//!
+//! Every connection to the tunnel server starts with a mutual challenge: the server
+//! sends a random nonce, the agent answers with version, kind, stream id, a nonce of
+//! its own and an HMAC-SHA256 of them keyed by the shared secret. The server replies
+//! one status byte and, when accepted, an HMAC over both nonces, so the agent never
+//! acts for a server which does not know the secret. Mux sessions start with the
+//! same challenge.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot, watch};
+use tracing::Instrument;
+
+use crate::connect::{ConnectOptions, Dialer};
+use crate::proxy::Secret;
+use crate::registry;
+use crate::relay::{Relay, RelayMode, Transfer};
+
+const VERSION: u8 = 1;
+const KIND_CONTROL: u8 = 1;
+const KIND_DATA: u8 = 2;
+/// A mux session, see [`crate::mux`].
+pub(crate) const KIND_MUX: u8 = 3;
+const STATUS_OK: u8 = 0;
+const STATUS_DENIED: u8 = 1;
+
+const MSG_PING: u8 = 0;
+const MSG_OPEN: u8 = 1;
+
+const NONCE_LEN: usize = 32;
+const MAC_LEN: usize = 32;
+const HEADER_LEN: usize = 10;
+/// Keeps the proof of the server apart from the answer of the agent.
+const SERVER_PROOF: &[u8] = b"server";
+/// The server pings agents this often, either side gives up after three silent periods.
+const HEARTBEAT: Duration = Duration::from_secs(15);
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a client waits for the agent to open its stream.
+const OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+const CONTROL_QUEUE: usize = 1024;
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Public side of the reverse tunnel.
+#[derive(Debug, Clone)]
+pub struct TunnelServerConfig {
+    /// Address agents connect to, separate from the client listen addresses.
+    pub listen: String,
+    /// Shared with the agents, never sent over the wire.
+    pub secret: Secret,
+}
+
+/// Agent side of the reverse tunnel.
+#[derive(Debug, Clone)]
+pub struct AgentConfig {
+    /// Tunnel address of the public forwarder.
+    pub server: String,
+    /// Target reachable from the agent, like 192.168.1.10:22.
+    pub target: String,
+    pub secret: Secret,
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The server rejected the secret, retrying will not help.
+#[derive(Debug)]
+struct Denied;
+
+impl std::fmt::Display for Denied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("server denied the secret, check it")
+    }
+}
+
+impl std::error::Error for Denied {}
+
+pub(crate) fn check_secret(secret: &Secret) -> anyhow::Result<()> {
+    if secret.expose().is_empty() {
+        anyhow::bail!("secret must not be empty");
+    }
+    Ok(())
+}
+
+fn mac(secret: &Secret, nonce: &[u8], parts: &[&[u8]]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.expose().as_bytes())
+        .expect("hmac accepts keys of any size");
+    mac.update(nonce);
+    for part in parts {
+        mac.update(part);
+    }
+    mac
+}
+
+/// Agent side of the challenge, fails unless the server proves the secret too.
+pub(crate) async fn authenticate(
+    conn: &mut TcpStream,
+    secret: &Secret,
+    kind: u8,
+    id: u64,
+) -> anyhow::Result<()> {
+    let mut nonce = [0; NONCE_LEN];
+    conn.read_exact(&mut nonce).await?;
+    let own: [u8; NONCE_LEN] = rand::random();
+    let mut message = vec![VERSION, kind];
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&own);
+    let tag = mac(secret, &nonce, &[&message]).finalize().into_bytes();
+    message.extend_from_slice(&tag);
+    conn.write_all(&message).await?;
+    if conn.read_u8().await? != STATUS_OK {
+        return Err(Denied.into());
+    }
+    let mut proof = [0; MAC_LEN];
+    conn.read_exact(&mut proof).await?;
+    let header = &message[..HEADER_LEN];
+    mac(secret, &own, &[SERVER_PROOF, &nonce, header])
+        .verify_slice(&proof)
+        .map_err(|_| anyhow::anyhow!("tunnel server does not know the secret"))
+}
+
+/// Server side of the challenge, returns the kind and stream id.
+pub(crate) async fn verify(conn: &mut TcpStream, secret: &Secret) -> anyhow::Result<(u8, u64)> {
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    conn.write_all(&nonce).await?;
+    let mut message = [0; HEADER_LEN + NONCE_LEN + MAC_LEN];
+    conn.read_exact(&mut message).await?;
+    let (signed, tag) = message.split_at(HEADER_LEN + NONCE_LEN);
+    let (header, agent_nonce) = signed.split_at(HEADER_LEN);
+    if header[0] != VERSION || mac(secret, &nonce, &[signed]).verify_slice(tag).is_err() {
+        let _ = conn.write_u8(STATUS_DENIED).await;
+        anyhow::bail!("authentication failed");
+    }
+    let proof = mac(secret, agent_nonce, &[SERVER_PROOF, &nonce, header])
+        .finalize()
+        .into_bytes();
+    let mut reply = vec![STATUS_OK];
+    reply.extend_from_slice(&proof);
+    conn.write_all(&reply).await?;
+    let mut id = [0; 8];
+    id.copy_from_slice(&header[2..HEADER_LEN]);
+    Ok((header[1], u64::from_be_bytes(id)))
+}
+
+struct AgentHandle {
+    id: u64,
+    addr: String,
+    open_tx: mpsc::Sender<u64>,
+}
+
+/// Connected agents and clients waiting for their stream.
+pub(crate) struct Hub {
+    config: TunnelServerConfig,
+    next_id: AtomicU64,
+    next_agent: AtomicUsize,
+    agents: Mutex<Vec<AgentHandle>>,
+    pending: Mutex<HashMap<u64, oneshot::Sender<TcpStream>>>,
+}
+
+impl Hub {
+    pub(crate) fn new(config: TunnelServerConfig) -> anyhow::Result<Self> {
+        check_secret(&config.secret)?;
+        Ok(Self {
+            config,
+            next_id: AtomicU64::new(0),
+            next_agent: AtomicUsize::new(0),
+            agents: Mutex::new(Vec::new()),
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub(crate) fn listen_addr(&self) -> &str {
+        &self.config.listen
+    }
+
+    fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Ask an agent to open the stream, agents take turns.
+    fn request(&self, id: u64) -> anyhow::Result<String> {
+        let agents = self.agents.lock().unwrap();
+        if agents.is_empty() {
+            anyhow::bail!("no agent connected");
+        }
+        let start = self.next_agent.fetch_add(1, Ordering::Relaxed);
+        for i in 0..agents.len() {
+            let agent = &agents[(start + i) % agents.len()];
+            if agent.open_tx.try_send(id).is_ok() {
+                return Ok(agent.addr.clone());
+            }
+        }
+        anyhow::bail!("all agents are busy")
+    }
+
+    /// Accept agent connections until shutdown.
+    pub(crate) async fn serve(
+        self: Arc<Self>,
+        listener: TcpListener,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> anyhow::Result<()> {
+        loop {
+            if *shutdown.borrow() {
+                return Ok(());
+            }
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((conn, peer)) => {
+                        let hub = self.clone();
+                        let span = tracing::info_span!("agent", addr = %peer);
+                        tokio::spawn(
+                            async move {
+                                if let Err(e) = hub.handle(conn, peer.to_string()).await {
+                                    tracing::warn!("Agent connection failed: {}", e);
+                                }
+                            }
+                            .instrument(span),
+                        );
+                    }
+                    Err(e) => tracing::error!("Tunnel accept error: {}", e),
+                },
+                _ = shutdown.changed() => {}
+            }
+        }
+    }
+
+    async fn handle(self: Arc<Self>, mut conn: TcpStream, addr: String) -> anyhow::Result<()> {
+        let (kind, id) =
+            tokio::time::timeout(HANDSHAKE_TIMEOUT, verify(&mut conn, &self.config.secret))
+                .await
+                .map_err(|_| anyhow::anyhow!("handshake timed out"))??;
+        match kind {
+            KIND_CONTROL => self.control(conn, addr).await,
+            KIND_DATA => {
+                match self.pending.lock().unwrap().remove(&id) {
+                    Some(waiting) => {
+                        let _ = waiting.send(conn);
+                    }
+                    None => tracing::warn!("Stream {} is not waited for", id),
+                }
+                Ok(())
+            }
+            _ => anyhow::bail!("unknown connection kind {}", kind),
+        }
+    }
+
+    async fn control(&self, mut conn: TcpStream, addr: String) -> anyhow::Result<()> {
+        let agent_id = self.next_id();
+        let (open_tx, mut open_rx) = mpsc::channel(CONTROL_QUEUE);
+        self.agents.lock().unwrap().push(AgentHandle {
+            id: agent_id,
+            addr,
+            open_tx,
+        });
+        tracing::info!("Agent connected");
+
+        let result = async {
+            let mut heartbeat = tokio::time::interval(HEARTBEAT);
+            let mut last_seen = tokio::time::Instant::now();
+            loop {
+                tokio::select! {
+                    Some(id) = open_rx.recv() => {
+                        let mut message = [MSG_OPEN, 0, 0, 0, 0, 0, 0, 0, 0];
+                        message[1..].copy_from_slice(&u64::to_be_bytes(id));
+                        conn.write_all(&message).await?;
+                    }
+                    _ = heartbeat.tick() => {
+                        if last_seen.elapsed() > HEARTBEAT * 3 {
+                            anyhow::bail!("agent stopped answering pings");
+                        }
+                        conn.write_u8(MSG_PING).await?;
+                    }
+                    // only pongs are sent by agents
+                    pong = conn.read_u8() => {
+                        pong?;
+                        last_seen = tokio::time::Instant::now();
+                    }
+                }
+            }
+        }
+        .await;
+
+        self.agents.lock().unwrap().retain(|a| a.id != agent_id);
+        tracing::info!("Agent disconnected");
+        result
+    }
+}
+
+/// Relay handing accepted clients to agents, built by
+/// [`ForwarderBuilder::tunnel_server`](crate::ForwarderBuilder::tunnel_server).
+pub(crate) struct TunnelRelay {
+    hub: Arc<Hub>,
+    transfer: Transfer,
+}
+
+impl TunnelRelay {
+    pub(crate) fn new(hub: Arc<Hub>, mode: RelayMode) -> anyhow::Result<Self> {
+        Ok(Self {
+            hub,
+            transfer: Transfer::new(mode)?,
+        })
+    }
+}
+
+/// Forgets the waiting client if it is dropped before the agent answers.
+struct Waiting<'a> {
+    hub: &'a Hub,
+    id: u64,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.hub.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Relay for TunnelRelay {
+    type Fut = BoxFuture<'static, anyhow::Result<()>>;
+
+    fn relay(&self, inbound: TcpStream) -> Self::Fut {
+        let hub = self.hub.clone();
+        let transfer = self.transfer.clone();
+
+        Box::pin(async move {
+            let id = hub.next_id();
+            let (tx, rx) = oneshot::channel();
+            hub.pending.lock().unwrap().insert(id, tx);
+            let _waiting = Waiting { hub: &hub, id };
+
+            let agent = hub.request(id)?;
+            registry::record_route(&format_args!("agent {} stream {}", agent, id), None);
+            tracing::info!("Open stream {} via agent {}", id, agent);
+            let outbound = tokio::time::timeout(OPEN_TIMEOUT, rx)
+                .await
+                .map_err(|_| anyhow::anyhow!("agent did not open stream {} in time", id))?
+                .map_err(|_| anyhow::anyhow!("stream {} is dropped", id))?;
+
+            transfer.relay(inbound, outbound).await
+        })
+    }
+}
+
+/// Agent next to a target behind NAT, serving the streams the tunnel server asks for.
+///
+/// Cloning is cheap and all clones control the same agent.
+#[derive(Clone)]
+pub struct Agent {
+    inner: Arc<AgentInner>,
+}
+
+struct AgentInner {
+    config: AgentConfig,
+    dialer: Dialer,
+    transfer: Transfer,
+    shutdown_tx: watch::Sender<bool>,
+    shutdown_rx: watch::Receiver<bool>,
+}
+
+impl Agent {
+    /// Must be called within a tokio runtime, which the resolver is created on.
+    pub fn new(
+        config: AgentConfig,
+        options: ConnectOptions,
+        mode: RelayMode,
+    ) -> anyhow::Result<Self> {
+        check_secret(&config.secret)?;
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        Ok(Self {
+            inner: Arc::new(AgentInner {
+                config,
+                dialer: Dialer::new(options)?,
+                transfer: Transfer::new(mode)?,
+                shutdown_tx,
+                shutdown_rx,
+            }),
+        })
+    }
+
+    /// Keep the control connection up until [`shutdown`](Self::shutdown), reconnecting
+    /// with backoff. Returns an error if the server denies the secret.
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let mut shutdown = self.inner.shutdown_rx.clone();
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            if *shutdown.borrow() {
+                return Ok(());
+            }
+            let mut connected = false;
+            tokio::select! {
+                result = self.control(&mut connected) => match result {
+                    Ok(()) => tracing::info!("Control connection closed"),
+                    Err(e) if e.is::<Denied>() => return Err(e),
+                    Err(e) => tracing::warn!("Control connection failed: {}", e),
+                },
+                _ = shutdown.changed() => continue,
+            }
+            if connected {
+                backoff = Duration::from_secs(1);
+            }
+            tracing::info!("Reconnect to tunnel server in {:?}", backoff);
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = shutdown.changed() => {}
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Stop serving, `run` returns after that. Open streams are not interrupted.
+    pub fn shutdown(&self) {
+        let _ = self.inner.shutdown_tx.send(true);
+    }
+
+    async fn control(&self, connected: &mut bool) -> anyhow::Result<()> {
+        let config = &self.inner.config;
+        let mut conn = self.inner.dialer.connect(config.server.as_str()).await?;
+        tokio::time::timeout(
+            HANDSHAKE_TIMEOUT,
+            authenticate(&mut conn, &config.secret, KIND_CONTROL, 0),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("handshake timed out"))??;
+        *connected = true;
+        tracing::info!("Connected to tunnel server {}", config.server);
+
+        loop {
+            let message = tokio::time::timeout(HEARTBEAT * 3, conn.read_u8())
+                .await
+                .map_err(|_| anyhow::anyhow!("tunnel server stopped pinging"))?;
+            match message {
+                Ok(MSG_PING) => conn.write_u8(MSG_PING).await?,
+                Ok(MSG_OPEN) => {
+                    let id = conn.read_u64().await?;
+                    let agent = self.clone();
+                    tokio::spawn(
+                        async move {
+                            if let Err(e) = agent.open(id).await {
+                                tracing::error!("Stream failed: {}", e);
+                            }
+                        }
+                        .instrument(tracing::info_span!("stream", id)),
+                    );
+                }
+                Ok(message) => anyhow::bail!("unknown control message {}", message),
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Dial back before the target, so a failed target closes the data connection
+    /// and the waiting client with it.
+    async fn open(&self, id: u64) -> anyhow::Result<()> {
+        let config = &self.inner.config;
+        let mut data = self.inner.dialer.connect(config.server.as_str()).await?;
+        tokio::time::timeout(
+            HANDSHAKE_TIMEOUT,
+            authenticate(&mut data, &config.secret, KIND_DATA, id),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("handshake timed out"))??;
+        tracing::info!("Connect target {}", config.target);
+        let target = self.inner.dialer.connect(config.target.as_str()).await?;
+        self.inner.transfer.clone().relay(data, target).await
+    }
+}
@@ -0,0 +1,262 @@
+//! io_uring backend, a thread-per-core runtime where each thread owns a ring and a listener.
+use std::io;
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::watch;
+use tokio_uring::net::{TcpListener, TcpStream};
+use tracing::Instrument;
+
+use crate::connect::{ConnectOptions, Dialer};
+use crate::proxy::ProxyConfig;
+use crate::relay::Outbound;
+use crate::socket::ListenOptions;
+
+/// Buffer size of each relay direction.
+const BUF_SIZE: usize = 64 * 1024;
+
+/// Forwarder running on io_uring, built by
+/// [`ForwarderBuilder::build_uring`](crate::ForwarderBuilder::build_uring).
+///
+/// Every thread binds the listen addresses with `SO_REUSEPORT` so the kernel spreads
+/// connections among them, accepts and relays with io_uring and never hands a
+/// connection to another thread. Connecting the proxy or target still goes through
+/// the tokio reactor of the thread.
+///
+/// Cloning is cheap and all clones control the same forwarder.
+#[derive(Clone)]
+pub struct UringForwarder {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    listen_addrs: Vec<String>,
+    listen_options: ListenOptions,
+    target_addr: String,
+    proxy: Option<ProxyConfig>,
+    options: ConnectOptions,
+    /// Connection ids are unique among all threads.
+    next_id: AtomicU64,
+    shutdown_tx: watch::Sender<bool>,
+    shutdown_rx: watch::Receiver<bool>,
+}
+
+impl UringForwarder {
+    pub(crate) fn new(
+        listen_addrs: Vec<String>,
+        listen_options: ListenOptions,
+        target_addr: String,
+        proxy: Option<ProxyConfig>,
+        options: ConnectOptions,
+    ) -> anyhow::Result<Self> {
+        listen_options.check()?;
+        // tokio-uring binds the listeners itself, with SO_REUSEPORT and the default backlog
+        if listen_options.fastopen_queue.is_some() {
+            anyhow::bail!("tcp fastopen on listeners is not supported by the io_uring backend");
+        }
+        let defaults = ListenOptions::default();
+        if listen_options.backlog != defaults.backlog {
+            anyhow::bail!(
+                "listen backlog other than {} is not supported by the io_uring backend",
+                defaults.backlog
+            );
+        }
+        if listen_options.socket.send_buffer_size.is_some()
+            || listen_options.socket.recv_buffer_size.is_some()
+        {
+            anyhow::bail!("socket buffer sizes are not supported by the io_uring backend");
+        }
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        Ok(Self {
+            inner: Arc::new(Inner {
+                listen_addrs,
+                listen_options,
+                target_addr,
+                proxy,
+                options,
+                next_id: AtomicU64::new(0),
+                shutdown_tx,
+                shutdown_rx,
+            }),
+        })
+    }
+
+    /// Serve on `threads` threads, one per cpu when 0, blocking until all of them stop.
+    ///
+    /// Must not be called within a tokio runtime.
+    pub fn run(&self, threads: usize) -> anyhow::Result<()> {
+        let threads = match threads {
+            0 => cpus(),
+            n => n,
+        };
+        let mut addrs = Vec::with_capacity(self.inner.listen_addrs.len());
+        for addr in self.inner.listen_addrs.iter() {
+            let resolved = addr
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("no address to listen for {}", addr))?;
+            tracing::info!("Listening at {} with {} io_uring threads", addr, threads);
+            addrs.push(resolved);
+        }
+
+        let handles = (0..threads)
+            .map(|id| {
+                let this = self.clone();
+                let addrs = addrs.clone();
+                std::thread::Builder::new()
+                    .name(format!("uring-{}", id))
+                    .spawn(move || {
+                        let res = this.serve_thread(addrs);
+                        if res.is_err() {
+                            // stop the other threads too
+                            this.shutdown();
+                        }
+                        res
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut result = Ok(());
+        for handle in handles {
+            let res = handle
+                .join()
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("io_uring thread panicked")));
+            if res.is_err() {
+                result = res;
+            }
+        }
+        result
+    }
+
+    /// Stop accepting new connections, `run` returns after that.
+    pub fn shutdown(&self) {
+        let _ = self.inner.shutdown_tx.send(true);
+    }
+
+    fn serve_thread(&self, addrs: Vec<SocketAddr>) -> anyhow::Result<()> {
+        let runtime = tokio_uring::Runtime::new(&tokio_uring::builder())
+            .map_err(|e| anyhow::anyhow!("create io_uring runtime failed: {}", e))?;
+        runtime.block_on(self.serve(addrs))
+    }
+
+    async fn serve(&self, addrs: Vec<SocketAddr>) -> anyhow::Result<()> {
+        let dialer = Rc::new(Dialer::new(self.inner.options.clone())?);
+        let mut listeners = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            // SO_REUSEPORT is always set so every thread can bind the same address
+            listeners.push(TcpListener::bind(addr)?);
+        }
+        futures::future::try_join_all(
+            listeners
+                .into_iter()
+                .map(|l| self.accept_loop(l, dialer.clone())),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn accept_loop(&self, listener: TcpListener, dialer: Rc<Dialer>) -> anyhow::Result<()> {
+        let mut shutdown = self.inner.shutdown_rx.clone();
+        loop {
+            if *shutdown.borrow() {
+                tracing::info!("Listener closed");
+                return Ok(());
+            }
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((conn, peer)) => {
+                        let span = tracing::info_span!(
+                            "conn",
+                            id = self.inner.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+                            client = %peer,
+                            target = %self.inner.target_addr,
+                            proxy = self.inner.proxy.as_ref().map(|p| p.address.as_str()),
+                        );
+                        let _enter = span.enter();
+                        tracing::info!("Accept new incoming connection");
+                        if let Err(e) = self.inner.listen_options.socket.apply(&conn) {
+                            tracing::error!("Set socket options failed: {}", e);
+                            continue;
+                        }
+                        let this = self.clone();
+                        let dialer = dialer.clone();
+                        tokio_uring::spawn(
+                            async move {
+                                if let Err(e) = this.relay(conn, &dialer).await {
+                                    tracing::error!("Relay failed: {}", e);
+                                }
+                            }
+                            .instrument(span.clone()),
+                        );
+                    }
+                    Err(e) => {
+                        tracing::error!("Accept error: {}", e);
+                    }
+                },
+                _ = shutdown.changed() => {}
+            }
+        }
+    }
+
+    async fn relay(&self, inbound: TcpStream, dialer: &Dialer) -> anyhow::Result<()> {
+        let target = self.inner.target_addr.as_str();
+        let outbound = match self.inner.proxy.as_ref() {
+            Some(proxy) => {
+                tracing::info!("Connect target {} via proxy {}", target, proxy.address);
+                match dialer.connect_proxy(proxy, target).await? {
+                    Outbound::Tcp(outbound) => outbound,
+                    _ => anyhow::bail!("only socks5 proxies are supported by the io_uring backend"),
+                }
+            }
+            None => {
+                tracing::info!("Connect target {}", target);
+                dialer.connect(target).await?
+            }
+        };
+        let outbound = TcpStream::from_std(outbound.into_std()?);
+
+        tracing::info!("Start io_uring relay");
+        let client_to_server = async {
+            let n = copy_one_way(&inbound, &outbound).await?;
+            tracing::info!("Relay inbound -> outbound finished");
+            Ok::<_, io::Error>(n)
+        };
+        let server_to_client = async {
+            let n = copy_one_way(&outbound, &inbound).await?;
+            tracing::info!("Relay outbound -> inbound finished");
+            Ok::<_, io::Error>(n)
+        };
+        futures::try_join!(client_to_server, server_to_client)?;
+
+        tracing::info!("Relay finished");
+        Ok(())
+    }
+}
+
+/// Copy from `src` to `dst` until EOF, then shutdown write of `dst`.
+async fn copy_one_way(src: &TcpStream, dst: &TcpStream) -> io::Result<u64> {
+    let mut buf = Vec::with_capacity(BUF_SIZE);
+    let mut total = 0;
+    loop {
+        let (res, read) = src.read(buf).await;
+        let n = res?;
+        if n == 0 {
+            break;
+        }
+        let (res, written) = dst.write_all(read).await;
+        res?;
+        buf = written;
+        buf.clear();
+        total += n as u64;
+    }
+    let _ = dst.shutdown(Shutdown::Write);
+    Ok(total)
+}
+
+fn cpus() -> usize {
+    match unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) } {
+        n if n > 0 => n as usize,
+        _ => 1,
+    }
+}
@@ -0,0 +1,13 @@
+/// Borrow the fd as a `socket2::Socket` to reach options tokio does not expose.
+#[cfg(unix)]
+pub(crate) fn with_socket2<S, F, T>(sock: &S, f: F) -> std::io::Result<T>
+where
+    S: std::os::unix::io::AsRawFd,
+    F: FnOnce(&socket2::Socket) -> std::io::Result<T>,
+{
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    let socket = unsafe { socket2::Socket::from_raw_fd(sock.as_raw_fd()) };
+    let res = f(&socket);
+    let _ = socket.into_raw_fd();
+    res
+}
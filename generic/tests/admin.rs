@@ -0,0 +1,140 @@
+mod common;
+
+use std::time::Duration;
+
+use serde_json::Value;
+use socks5_forwarder::{BoxRelay, Forwarder, ProxyConfig, Secret};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Serve the admin api on a free port, returning its address.
+fn admin(forwarder: &Forwarder<BoxRelay>) -> String {
+    let addr = common::free_addr().to_string();
+    let forwarder = forwarder.clone();
+    let serving = addr.clone();
+    tokio::spawn(async move { forwarder.serve_admin(&serving).await });
+    addr
+}
+
+async fn request(admin: &str, method: &str, path: &str) -> (u16, Value) {
+    let mut conn = common::connect(admin.parse().unwrap()).await;
+    let request = format!("{} {} HTTP/1.1\r\nHost: admin\r\n\r\n", method, path);
+    conn.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    conn.read_to_string(&mut response).await.unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = response.split("\r\n\r\n").nth(1).unwrap();
+    (status, serde_json::from_str(body).unwrap())
+}
+
+async fn echo(conn: &mut TcpStream, data: &[u8]) {
+    conn.write_all(data).await.unwrap();
+    let mut received = vec![0; data.len()];
+    conn.read_exact(&mut received).await.unwrap();
+    assert_eq!(received, data);
+}
+
+#[tokio::test]
+async fn list_and_close_connections() {
+    let target = common::echo_server().await;
+    let (listen, forwarder) = common::start(Forwarder::builder().target(target.to_string()));
+    let admin = admin(&forwarder);
+
+    let mut conn = common::connect(listen).await;
+    echo(&mut conn, &common::payload(1000)).await;
+
+    let (status, list) = request(&admin, "GET", "/connections").await;
+    assert_eq!(status, 200);
+    let connections = list.as_array().unwrap();
+    assert_eq!(connections.len(), 1);
+    let connection = &connections[0];
+    assert_eq!(connection["listen"], listen.to_string());
+    assert_eq!(connection["target"], target.to_string());
+    assert_eq!(connection["proxy"], Value::Null);
+    assert_eq!(connection["bytes_sent"], 1000);
+    assert_eq!(connection["bytes_received"], 1000);
+
+    let id = connection["id"].as_u64().unwrap();
+    let (status, _) = request(&admin, "DELETE", &format!("/connections/{}", id)).await;
+    assert_eq!(status, 200);
+    let mut buf = [0; 1];
+    let closed = tokio::time::timeout(Duration::from_secs(5), conn.read(&mut buf))
+        .await
+        .expect("connection is not closed");
+    assert!(matches!(closed, Ok(0) | Err(_)));
+
+    // removed once the relay task ends
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let (_, list) = request(&admin, "GET", "/connections").await;
+    assert!(list.as_array().unwrap().is_empty());
+    let (status, _) = request(&admin, "DELETE", &format!("/connections/{}", id)).await;
+    assert_eq!(status, 404);
+}
+
+#[tokio::test]
+async fn pause_and_resume_listener() {
+    let target = common::echo_server().await;
+    let (listen, forwarder) = common::start(Forwarder::builder().target(target.to_string()));
+    let admin = admin(&forwarder);
+    common::roundtrip(listen, b"ping").await.unwrap();
+
+    let path = format!("/listeners/{}/pause", listen);
+    let (status, listeners) = request(&admin, "POST", &path).await;
+    assert_eq!(status, 200);
+    assert_eq!(listeners[0]["paused"], true);
+
+    // the client waits in the backlog until resumed
+    let mut conn = common::connect(listen).await;
+    conn.write_all(b"ping").await.unwrap();
+    let mut buf = [0; 4];
+    let waiting = tokio::time::timeout(Duration::from_millis(200), conn.read_exact(&mut buf)).await;
+    assert!(waiting.is_err());
+
+    let path = format!("/listeners/{}/resume", listen);
+    let (status, listeners) = request(&admin, "POST", &path).await;
+    assert_eq!(status, 200);
+    assert_eq!(listeners[0]["paused"], false);
+    conn.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+
+    let (status, _) = request(&admin, "POST", "/listeners/127.0.0.1:1/pause").await;
+    assert_eq!(status, 404);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn config_over_unix_socket() {
+    let proxy = ProxyConfig::new("127.0.0.1:1080")
+        .with_credential("alice", Secret::from("secret".to_string()));
+    let (_, forwarder) = common::start(Forwarder::builder().target("example.com:443").proxy(proxy));
+    let path = std::env::temp_dir().join(format!("forwarder-admin-{}.sock", std::process::id()));
+    let addr = format!("unix:{}", path.display());
+    let serving = forwarder.clone();
+    tokio::spawn(async move { serving.serve_admin(&addr).await });
+
+    let mut conn = None;
+    for _ in 0..50 {
+        if let Ok(c) = tokio::net::UnixStream::connect(&path).await {
+            conn = Some(c);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let mut conn = conn.expect("admin api is not listening");
+    conn.write_all(b"GET /config HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    conn.read_to_string(&mut response).await.unwrap();
+    let _ = std::fs::remove_file(&path);
+
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(!response.contains("secret"), "{}", response);
+    let body = response.split("\r\n\r\n").nth(1).unwrap();
+    let config: Value = serde_json::from_str(body).unwrap();
+    assert_eq!(config["inbound"], "forward");
+    assert_eq!(config["target"], "example.com:443");
+    assert_eq!(config["proxy"], "127.0.0.1:1080");
+    assert_eq!(config["proxy_auth"], true);
+    assert_eq!(config["mode"], "auto");
+}
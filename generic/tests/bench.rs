@@ -0,0 +1,49 @@
+mod common;
+
+use common::{MockConfig, MockSocks5};
+use socks5_forwarder::{BenchOptions, Forwarder, ProxyConfig, RelayMode, SocksServerConfig};
+
+fn options() -> BenchOptions {
+    BenchOptions {
+        connections: 3,
+        bytes: 1024 * 1024,
+        setups: 20,
+        requests: 10,
+    }
+}
+
+#[test]
+fn bench_modes() {
+    for mode in [RelayMode::Userspace, RelayMode::Splice].iter() {
+        let report = Forwarder::builder().mode(*mode).bench(&options()).unwrap();
+        assert_eq!(report.name, mode.to_string());
+        assert_eq!(report.bytes, 3 * 1024 * 1024);
+        assert_eq!(report.setups, 20);
+        assert_eq!(report.latencies.len(), 30);
+        assert!(report.latency(50.0) <= report.latency(99.0));
+        assert!(report.upload_rate() > 0.0 && report.download_rate() > 0.0);
+        assert!(report.to_string().starts_with(&mode.to_string()));
+    }
+}
+
+#[test]
+fn bench_through_proxy() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let proxy = runtime.block_on(MockSocks5::start(MockConfig::default()));
+    let report = Forwarder::builder()
+        .mode(RelayMode::Userspace)
+        .proxy(ProxyConfig::new(proxy.addr.to_string()))
+        .bench(&options())
+        .unwrap();
+    assert_eq!(report.latencies.len(), 30);
+    // one connection to wait for the listener and one for each connection of each phase
+    assert_eq!(proxy.requests().len(), 1 + 3 * 3 + 20);
+}
+
+#[test]
+fn bench_requires_forwarding() {
+    let result = Forwarder::builder()
+        .socks_server(SocksServerConfig::default())
+        .bench(&options());
+    assert!(result.is_err());
+}
@@ -0,0 +1,101 @@
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use socks5_forwarder::{BindOptions, CheckOptions, CheckReport, Forwarder};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+
+async fn check_bind(bind: BindOptions) -> CheckReport {
+    let target = common::echo_server().await;
+    Forwarder::builder()
+        .listen(common::free_addr().to_string())
+        .target(target.to_string())
+        .bind(bind)
+        .check(CheckOptions::default())
+        .await
+}
+
+#[tokio::test]
+async fn bind_address() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let source = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .target(target.local_addr().unwrap().to_string())
+            .bind(BindOptions {
+                address: Some(source),
+                ..Default::default()
+            }),
+    );
+
+    let mut conn = common::connect(listen).await;
+    conn.write_all(b"hello").await.unwrap();
+    let (_, peer) = target.accept().await.unwrap();
+    assert_eq!(peer.ip(), source);
+}
+
+#[tokio::test]
+async fn bind_unknown_interface() {
+    let report = check_bind(BindOptions {
+        interface: Some("nosuchif0".to_string()),
+        ..Default::default()
+    })
+    .await;
+    assert!(!report.passed());
+    let last = report.steps.last().unwrap();
+    assert!(last.name.starts_with("connect target"), "{}", report);
+}
+
+/// Drop `CAP_NET_ADMIN` from the effective set of the calling thread only, and
+/// `CAP_NET_RAW` which newer kernels accept for `SO_MARK` too.
+fn drop_net_admin() {
+    const CAP_NET_ADMIN: u32 = 12;
+    const CAP_NET_RAW: u32 = 13;
+    const VERSION_3: u32 = 0x2008_0522;
+
+    #[repr(C)]
+    struct Header {
+        version: u32,
+        pid: libc::c_int,
+    }
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct Data {
+        effective: u32,
+        permitted: u32,
+        inheritable: u32,
+    }
+
+    let mut header = Header {
+        version: VERSION_3,
+        pid: 0,
+    };
+    let mut data = [Data::default(); 2];
+    unsafe {
+        assert_eq!(
+            libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()),
+            0
+        );
+        data[0].effective &= !(1 << CAP_NET_ADMIN | 1 << CAP_NET_RAW);
+        assert_eq!(libc::syscall(libc::SYS_capset, &header, data.as_ptr()), 0);
+    }
+}
+
+#[tokio::test]
+async fn fwmark_without_net_admin() {
+    // the test runtime is single threaded, so the dial runs on this thread
+    drop_net_admin();
+    let report = check_bind(BindOptions {
+        mark: Some(1),
+        ..Default::default()
+    })
+    .await;
+    assert!(!report.passed());
+    let last = report.steps.last().unwrap();
+    assert!(last.name.starts_with("connect target"), "{}", report);
+    let error = last.result.as_ref().unwrap_err();
+    assert!(error.contains("not permitted"), "{}", error);
+}
@@ -0,0 +1,127 @@
+mod common;
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use socks5_forwarder::{CaptureConfig, Forwarder};
+
+const SYN: u8 = 0x02;
+const FIN: u8 = 0x01;
+
+/// TCP segment read back from a capture.
+struct Segment {
+    src_port: u16,
+    flags: u8,
+    payload: Vec<u8>,
+}
+
+/// Parse a pcap file of raw IPv4 packets.
+fn read_pcap(path: &Path) -> Vec<Segment> {
+    let data = std::fs::read(path).unwrap();
+    assert_eq!(&data[..4], &0xa1b2c3d4u32.to_le_bytes());
+    assert_eq!(&data[20..24], &101u32.to_le_bytes());
+    let mut segments = Vec::new();
+    let mut rest = &data[24..];
+    while !rest.is_empty() {
+        let len = u32::from_le_bytes([rest[8], rest[9], rest[10], rest[11]]) as usize;
+        let packet = &rest[16..16 + len];
+        assert_eq!(packet[0], 0x45);
+        let tcp = &packet[20..];
+        let header_len = (tcp[12] >> 4) as usize * 4;
+        segments.push(Segment {
+            src_port: u16::from_be_bytes([tcp[0], tcp[1]]),
+            flags: tcp[13],
+            payload: tcp[header_len..].to_vec(),
+        });
+        rest = &rest[16 + len..];
+    }
+    segments
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("forwarder-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Wait for the writer thread to flush the expected number of FIN segments.
+async fn wait_fins(path: &Path, fins: usize) -> Vec<Segment> {
+    for _ in 0..100 {
+        let segments = read_pcap(path);
+        if segments.iter().filter(|s| s.flags & FIN != 0).count() >= fins {
+            return segments;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("capture is not flushed");
+}
+
+#[tokio::test]
+async fn capture_streams() {
+    let target = common::count_server().await;
+    let dir = temp_dir("capture");
+    let path = dir.join("relay.pcap");
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .target(target.to_string())
+            .capture(CaptureConfig::new(&path)),
+    );
+
+    let data = common::payload(100 * 1024);
+    let reply = common::roundtrip(listen, &data).await.unwrap();
+    let segments = wait_fins(&path, 2).await;
+
+    assert_eq!(segments[0].flags, SYN);
+    let client_port = segments[0].src_port;
+    let stream = |from_client: bool| {
+        segments
+            .iter()
+            .filter(|s| (s.src_port == client_port) == from_client)
+            .flat_map(|s| s.payload.clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(stream(true), data);
+    assert_eq!(stream(false), reply);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn filter_and_rotate() {
+    let target = common::echo_server().await;
+    let dir = temp_dir("capture-rotate");
+
+    let path = dir.join("filtered.pcap");
+    let mut capture = CaptureConfig::new(&path);
+    capture.clients = vec!["10.0.0.0/8".parse().unwrap()];
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .target(target.to_string())
+            .capture(capture),
+    );
+    common::roundtrip(listen, b"not captured").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(read_pcap(&path).is_empty());
+
+    let path = dir.join("rotated.pcap");
+    let mut capture = CaptureConfig::new(&path);
+    capture.clients = vec!["127.0.0.0/8".parse().unwrap()];
+    capture.max_file_size = Some(16 * 1024);
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .target(target.to_string())
+            .capture(capture),
+    );
+    let data = common::payload(64 * 1024);
+    for chunk in data.chunks(4096) {
+        assert_eq!(common::roundtrip(listen, chunk).await.unwrap(), chunk);
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let rotated: Vec<_> = (1..4)
+        .map(|i| dir.join(format!("rotated.{}.pcap", i)))
+        .collect();
+    for file in std::iter::once(&path).chain(rotated.iter()) {
+        assert!(std::fs::metadata(file).unwrap().len() <= 16 * 1024);
+        read_pcap(file);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
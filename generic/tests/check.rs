@@ -0,0 +1,128 @@
+mod common;
+
+use common::{MockConfig, MockSocks5};
+use socks5_forwarder::{
+    Action, CheckOptions, Forwarder, Matcher, ProxyConfig, RuleSet, Secret, SocksServerConfig,
+};
+
+fn probe(expect: &[u8]) -> CheckOptions {
+    CheckOptions {
+        probe: Some(b"ping".to_vec()),
+        expect: Some(expect.to_vec()),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn check_through_proxy() {
+    let target = common::echo_server().await;
+    let proxy = MockSocks5::start(MockConfig {
+        credential: Some(("user".to_string(), "pass".to_string())),
+        ..Default::default()
+    })
+    .await;
+    let builder = |password: &str| {
+        Forwarder::builder()
+            .listen(common::free_addr().to_string())
+            .target(target.to_string())
+            .proxy(
+                ProxyConfig::new(proxy.addr.to_string())
+                    .with_credential("user", Secret::from(password.to_string())),
+            )
+    };
+
+    let report = builder("pass").check(probe(b"ping")).await;
+    assert!(report.passed(), "{}", report);
+    let names: Vec<_> = report.steps.iter().map(|s| s.name.as_str()).collect();
+    assert!(names.iter().any(|n| n.starts_with("socks5 handshake")));
+    assert_eq!(report.steps.last().unwrap().name, "probe 4 bytes");
+
+    // a wrong password fails the handshake, before any probe
+    let report = builder("wrong").check(probe(b"ping")).await;
+    assert!(!report.passed());
+    let last = report.steps.last().unwrap();
+    assert!(last.name.starts_with("socks5 handshake"), "{}", report);
+    assert!(last.result.is_err());
+
+    // the reply must contain what is expected
+    let report = builder("pass").check(probe(b"pong")).await;
+    assert!(!report.passed());
+}
+
+#[tokio::test]
+async fn live_check() {
+    let target = common::echo_server().await;
+    let builder = || Forwarder::builder().target(target.to_string());
+    let (listen, _) = common::start(builder());
+    // wait until listening
+    drop(common::connect(listen).await);
+
+    let live = CheckOptions {
+        live: true,
+        ..Default::default()
+    };
+    let report = builder()
+        .listen(listen.to_string())
+        .check(live.clone())
+        .await;
+    assert!(report.passed(), "{}", report);
+    let report = builder()
+        .listen(common::free_addr().to_string())
+        .check(live)
+        .await;
+    assert!(!report.passed());
+}
+
+#[tokio::test]
+async fn check_rules() {
+    let target = common::echo_server().await;
+    let rules = RuleSet::default()
+        .rule(Matcher::Port(target.port(), target.port()), Action::Direct)
+        .default_action(Action::Reject);
+    let builder = Forwarder::builder()
+        .listen(common::free_addr().to_string())
+        .socks_server(SocksServerConfig::default())
+        .rules(rules);
+
+    let report = builder
+        .check(CheckOptions {
+            target: Some(target.to_string()),
+            ..Default::default()
+        })
+        .await;
+    assert!(report.passed(), "{}", report);
+    let report = builder
+        .check(CheckOptions {
+            target: Some("127.0.0.1:1".to_string()),
+            ..Default::default()
+        })
+        .await;
+    assert!(!report.passed());
+    assert!(report.to_string().contains("rejected"));
+}
+
+#[tokio::test]
+async fn check_undeclared_proxy() {
+    let target = common::echo_server().await;
+    let rules = RuleSet::default().rule(
+        Matcher::Port(target.port(), target.port()),
+        Action::Proxy("nosuch".to_string()),
+    );
+    let report = Forwarder::builder()
+        .listen(common::free_addr().to_string())
+        .socks_server(SocksServerConfig::default())
+        .rules(rules)
+        .check(CheckOptions {
+            target: Some(target.to_string()),
+            ..Default::default()
+        })
+        .await;
+    assert!(!report.passed());
+    let last = report.steps.last().unwrap();
+    assert_eq!(last.name, "rules");
+    assert!(
+        last.result.as_ref().unwrap_err().contains("not declared"),
+        "{}",
+        report
+    );
+}
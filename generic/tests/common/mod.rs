@@ -0,0 +1,459 @@
+//! Harness shared by the integration tests: targets, mock socks5 and shadowsocks
+//! servers and helpers to start a forwarder and push data through it.
+#![allow(dead_code)]
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use hkdf::Hkdf;
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use socks5_forwarder::{BoxRelay, Forwarder, ForwarderBuilder};
+
+/// Echo target, closes its write side after the client does.
+pub async fn echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut conn, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut r, mut w) = conn.split();
+                let _ = tokio::io::copy(&mut r, &mut w).await;
+                let _ = w.shutdown().await;
+            });
+        }
+    });
+    addr
+}
+
+/// Target which answers only after EOF, with the number of bytes it received.
+pub async fn count_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut conn, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let n = tokio::io::copy(&mut conn, &mut tokio::io::sink()).await?;
+                conn.write_all(n.to_string().as_bytes()).await?;
+                conn.shutdown().await
+            });
+        }
+    });
+    addr
+}
+
+/// Behaviour of [`MockSocks5`].
+#[derive(Clone, Default)]
+pub struct MockConfig {
+    /// Require username/password auth when set.
+    pub credential: Option<(String, String)>,
+    /// Reply code to CONNECT requests, 0 connects the target.
+    pub reply: u8,
+    /// Fail this many CONNECT requests with `reply` before connecting the target.
+    pub fail_first: Option<usize>,
+    /// Connect this address instead of the requested target.
+    pub connect_to: Option<SocketAddr>,
+}
+
+/// Nameserver answering A queries with `addrs` in order, and other queries with no
+/// records.
+pub async fn dns_server(addrs: Vec<Ipv4Addr>) -> SocketAddr {
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0; 512];
+        while let Ok((n, peer)) = socket.recv_from(&mut buf).await {
+            if let Some(reply) = dns_reply(&buf[..n], &addrs) {
+                let _ = socket.send_to(&reply, peer).await;
+            }
+        }
+    });
+    addr
+}
+
+fn dns_reply(query: &[u8], addrs: &[Ipv4Addr]) -> Option<Vec<u8>> {
+    // the question follows the 12 byte header: labels, then type and class
+    let mut end = 12;
+    while *query.get(end)? != 0 {
+        end += 1 + query[end] as usize;
+    }
+    let question = query.get(12..end + 5)?;
+    let qtype = u16::from_be_bytes([query[end + 1], query[end + 2]]);
+    let answers = if qtype == 1 { addrs } else { &[] };
+
+    let mut reply = query[..2].to_vec();
+    reply.extend_from_slice(&[0x81, 0x80, 0, 1]);
+    reply.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+    reply.extend_from_slice(&[0, 0, 0, 0]);
+    reply.extend_from_slice(question);
+    for ip in answers {
+        // name pointer to the question, type A, class IN, ttl 60
+        reply.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        reply.extend_from_slice(&ip.octets());
+    }
+    Some(reply)
+}
+
+/// In-process socks5 server supporting CONNECT with no auth or username/password.
+#[derive(Clone)]
+pub struct MockSocks5 {
+    pub addr: SocketAddr,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockSocks5 {
+    pub async fn start(config: MockConfig) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = listener.accept().await {
+                let config = config.clone();
+                let recorded = recorded.clone();
+                tokio::spawn(async move {
+                    let _ = serve_socks5(conn, config, recorded).await;
+                });
+            }
+        });
+        Self { addr, requests }
+    }
+
+    /// Targets of the CONNECT requests received so far, like `localhost:80` or `127.0.0.1:80`.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+async fn serve_socks5(
+    mut conn: TcpStream,
+    config: MockConfig,
+    requests: Arc<Mutex<Vec<String>>>,
+) -> io::Result<()> {
+    let mut head = [0; 2];
+    conn.read_exact(&mut head).await?;
+    let mut methods = vec![0; head[1] as usize];
+    conn.read_exact(&mut methods).await?;
+
+    match config.credential.as_ref() {
+        None => conn.write_all(&[5, 0]).await?,
+        Some((username, password)) => {
+            if !methods.contains(&2) {
+                return conn.write_all(&[5, 0xff]).await;
+            }
+            conn.write_all(&[5, 2]).await?;
+            let mut ver_len = [0; 2];
+            conn.read_exact(&mut ver_len).await?;
+            let mut user = vec![0; ver_len[1] as usize];
+            conn.read_exact(&mut user).await?;
+            let mut pass = vec![0; conn.read_u8().await? as usize];
+            conn.read_exact(&mut pass).await?;
+            if user != username.as_bytes() || pass != password.as_bytes() {
+                return conn.write_all(&[1, 1]).await;
+            }
+            conn.write_all(&[1, 0]).await?;
+        }
+    }
+
+    let mut request = [0; 4];
+    conn.read_exact(&mut request).await?;
+    let host = match request[3] {
+        1 => {
+            let mut ip = [0; 4];
+            conn.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        3 => {
+            let mut domain = vec![0; conn.read_u8().await? as usize];
+            conn.read_exact(&mut domain).await?;
+            String::from_utf8_lossy(&domain).into_owned()
+        }
+        4 => {
+            let mut ip = [0; 16];
+            conn.read_exact(&mut ip).await?;
+            format!("[{}]", std::net::Ipv6Addr::from(ip))
+        }
+        _ => return Err(io::ErrorKind::InvalidData.into()),
+    };
+    let target = format!("{}:{}", host, conn.read_u16().await?);
+    let seen = {
+        let mut requests = requests.lock().unwrap();
+        requests.push(target.clone());
+        requests.len()
+    };
+
+    let fail = match config.fail_first {
+        Some(n) => seen <= n,
+        None => config.reply != 0,
+    };
+    if fail {
+        return conn
+            .write_all(&[5, config.reply, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await;
+    }
+    let connected = match config.connect_to {
+        Some(addr) => TcpStream::connect(addr).await,
+        None => TcpStream::connect(target.as_str()).await,
+    };
+    let mut outbound = match connected {
+        Ok(outbound) => outbound,
+        Err(_) => return conn.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).await,
+    };
+    conn.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).await?;
+    tokio::io::copy_bidirectional(&mut conn, &mut outbound).await?;
+    Ok(())
+}
+
+/// In-process shadowsocks AEAD server for `aes-256-gcm` and `chacha20-ietf-poly1305`,
+/// drops connections it can not decrypt.
+#[derive(Clone)]
+pub struct MockShadowsocks {
+    pub addr: SocketAddr,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockShadowsocks {
+    pub async fn start(cipher: &'static str, password: &str) -> Self {
+        Self::with_chunk(cipher, password, 0x3fff).await
+    }
+
+    /// Seal replies in chunks of up to `chunk` bytes, more than the protocol allows
+    /// to test the client.
+    pub async fn with_chunk(cipher: &'static str, password: &str, chunk: usize) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // EVP_BytesToKey with MD5
+        let mut key = Vec::new();
+        let mut last = Vec::new();
+        while key.len() < 32 {
+            let mut md5 = Md5::new();
+            md5.update(&last);
+            md5.update(password.as_bytes());
+            last = md5.finalize().to_vec();
+            key.extend_from_slice(&last);
+        }
+        key.truncate(32);
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = listener.accept().await {
+                let key = key.clone();
+                let recorded = recorded.clone();
+                tokio::spawn(async move {
+                    let _ = serve_shadowsocks(conn, cipher, key, chunk, recorded).await;
+                });
+            }
+        });
+        Self { addr, requests }
+    }
+
+    /// Targets requested so far, like `localhost:80` or `127.0.0.1:80`.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+enum MockAead {
+    Aes(Box<Aes256Gcm>),
+    Chacha(ChaCha20Poly1305),
+}
+
+/// One direction of a shadowsocks connection.
+struct MockCipher {
+    aead: MockAead,
+    nonce: u128,
+}
+
+impl MockCipher {
+    fn new(cipher: &str, key: &[u8], salt: &[u8]) -> Self {
+        let mut subkey = [0; 32];
+        Hkdf::<Sha1>::new(Some(salt), key)
+            .expand(b"ss-subkey", &mut subkey)
+            .unwrap();
+        let aead = match cipher {
+            "aes-256-gcm" => MockAead::Aes(Box::new(Aes256Gcm::new_from_slice(&subkey).unwrap())),
+            "chacha20-ietf-poly1305" => {
+                MockAead::Chacha(ChaCha20Poly1305::new_from_slice(&subkey).unwrap())
+            }
+            _ => panic!("unsupported cipher {}", cipher),
+        };
+        Self { aead, nonce: 0 }
+    }
+
+    fn apply(&mut self, encrypt: bool, data: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = self.nonce.to_le_bytes();
+        self.nonce += 1;
+        let nonce = Nonce::from_slice(&nonce[..12]);
+        let result = match (&self.aead, encrypt) {
+            (MockAead::Aes(aead), true) => aead.encrypt(nonce, data),
+            (MockAead::Aes(aead), false) => aead.decrypt(nonce, data),
+            (MockAead::Chacha(aead), true) => aead.encrypt(nonce, data),
+            (MockAead::Chacha(aead), false) => aead.decrypt(nonce, data),
+        };
+        result.map_err(|_| io::ErrorKind::InvalidData.into())
+    }
+
+    fn seal_chunk(&mut self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let mut chunk = self.apply(true, &(payload.len() as u16).to_be_bytes())?;
+        chunk.extend(self.apply(true, payload)?);
+        Ok(chunk)
+    }
+
+    /// Read and open a chunk, `None` on EOF between chunks.
+    async fn open_chunk<R: AsyncRead + Unpin>(&mut self, r: &mut R) -> io::Result<Option<Vec<u8>>> {
+        let mut len = [0; 2 + 16];
+        match r.read_exact(&mut len).await {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let len = self.apply(false, &len)?;
+        let mut payload = vec![0; u16::from_be_bytes([len[0], len[1]]) as usize + 16];
+        r.read_exact(&mut payload).await?;
+        self.apply(false, &payload).map(Some)
+    }
+}
+
+/// Parse a socks5 address, returns it with the length consumed.
+fn parse_address(buf: &[u8]) -> Option<(String, usize)> {
+    let (host, len) = match *buf.first()? {
+        1 if buf.len() >= 7 => (Ipv4Addr::new(buf[1], buf[2], buf[3], buf[4]).to_string(), 5),
+        3 if buf.len() >= 2 && buf.len() >= 4 + buf[1] as usize => {
+            let end = 2 + buf[1] as usize;
+            (String::from_utf8_lossy(&buf[2..end]).into_owned(), end)
+        }
+        4 if buf.len() >= 19 => {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&buf[1..17]);
+            (format!("[{}]", std::net::Ipv6Addr::from(ip)), 17)
+        }
+        _ => return None,
+    };
+    let port = u16::from_be_bytes([buf[len], buf[len + 1]]);
+    Some((format!("{}:{}", host, port), len + 2))
+}
+
+async fn serve_shadowsocks(
+    conn: TcpStream,
+    cipher: &'static str,
+    key: Vec<u8>,
+    chunk: usize,
+    requests: Arc<Mutex<Vec<String>>>,
+) -> io::Result<()> {
+    let (mut r, mut w) = conn.into_split();
+    let mut salt = vec![0; key.len()];
+    r.read_exact(&mut salt).await?;
+    let mut opener = MockCipher::new(cipher, &key, &salt);
+
+    // the target address, maybe followed by early data
+    let mut plain = Vec::new();
+    let (target, consumed) = loop {
+        if let Some(parsed) = parse_address(&plain) {
+            break parsed;
+        }
+        match opener.open_chunk(&mut r).await? {
+            Some(chunk) => plain.extend(chunk),
+            None => return Ok(()),
+        }
+    };
+    requests.lock().unwrap().push(target.clone());
+    let outbound = TcpStream::connect(target.as_str()).await?;
+    let (mut target_r, mut target_w) = outbound.into_split();
+
+    let upload = async move {
+        target_w.write_all(&plain[consumed..]).await?;
+        while let Some(chunk) = opener.open_chunk(&mut r).await? {
+            target_w.write_all(&chunk).await?;
+        }
+        target_w.shutdown().await
+    };
+    let download = async move {
+        let salt: Vec<u8> = (0..key.len()).map(|i| (i * 7) as u8).collect();
+        let mut sealer = MockCipher::new(cipher, &key, &salt);
+        w.write_all(&salt).await?;
+        let mut buf = vec![0; chunk];
+        loop {
+            // oversized chunks are filled up so they do exceed the limit
+            let n = if chunk > 0x3fff {
+                read_full(&mut target_r, &mut buf).await?
+            } else {
+                target_r.read(&mut buf).await?
+            };
+            if n == 0 {
+                return w.shutdown().await;
+            }
+            w.write_all(&sealer.seal_chunk(&buf[..n])?).await?;
+        }
+    };
+    tokio::try_join!(upload, download)?;
+    Ok(())
+}
+
+/// Read until `buf` is full or EOF.
+async fn read_full<R: AsyncRead + Unpin>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]).await? {
+            0 => break,
+            read => n += read,
+        }
+    }
+    Ok(n)
+}
+
+/// Start the forwarder on a free port within the current runtime.
+pub fn start(builder: ForwarderBuilder) -> (SocketAddr, Forwarder<BoxRelay>) {
+    let listen = free_addr();
+    let forwarder = builder
+        .listen(listen.to_string())
+        .build()
+        .expect("build forwarder failed");
+    let running = forwarder.clone();
+    tokio::spawn(async move { running.run().await });
+    (listen, forwarder)
+}
+
+pub fn free_addr() -> SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .unwrap()
+}
+
+/// Connect, retrying while the forwarder is still starting.
+pub async fn connect(addr: SocketAddr) -> TcpStream {
+    for _ in 0..50 {
+        if let Ok(conn) = TcpStream::connect(addr).await {
+            return conn;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("forwarder at {} is not listening", addr);
+}
+
+/// Send `data`, close the write side and return everything received until EOF.
+pub async fn roundtrip(addr: SocketAddr, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut conn = connect(addr).await;
+    let (mut r, mut w) = conn.split();
+    let send = async {
+        w.write_all(data).await?;
+        w.shutdown().await
+    };
+    let mut received = Vec::with_capacity(data.len());
+    let recv = r.read_to_end(&mut received);
+    tokio::try_join!(send, recv)?;
+    Ok(received)
+}
+
+/// Deterministic test payload.
+pub fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 % 251) as u8).collect()
+}
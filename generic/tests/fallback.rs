@@ -0,0 +1,127 @@
+mod common;
+
+use common::{MockConfig, MockSocks5};
+use socks5_forwarder::{
+    Action, Fallback, Forwarder, Matcher, ProxyConfig, Rule, RuleSet, SocksServerConfig,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_socks::tcp::Socks5Stream;
+
+#[tokio::test]
+async fn proxy_then_direct() {
+    let target = common::echo_server().await;
+    let unreachable = common::free_addr();
+    let (listen, forwarder) = common::start(
+        Forwarder::builder()
+            .target(target.to_string())
+            .proxy(ProxyConfig::new(unreachable.to_string()).with_fallback(Fallback::Direct)),
+    );
+    let data = common::payload(64 * 1024);
+    assert_eq!(common::roundtrip(listen, &data).await.unwrap(), data);
+    assert_eq!(forwarder.stats().fallback_to_direct, 1);
+    assert_eq!(forwarder.stats().fallback_to_proxy, 0);
+    assert_eq!(forwarder.config().proxy_fallback, Some(Fallback::Direct));
+
+    // without fallback the client is dropped
+    let (listen, forwarder) = common::start(
+        Forwarder::builder()
+            .target(target.to_string())
+            .proxy(ProxyConfig::new(unreachable.to_string())),
+    );
+    let reply = common::roundtrip(listen, b"hello")
+        .await
+        .unwrap_or_default();
+    assert!(reply.is_empty());
+    assert_eq!(forwarder.stats().fallback_to_direct, 0);
+}
+
+#[tokio::test]
+async fn direct_then_proxy() {
+    let echo = common::echo_server().await;
+    // only reachable through the proxy
+    let hidden = common::free_addr();
+    let proxy = MockSocks5::start(MockConfig {
+        connect_to: Some(echo),
+        ..Default::default()
+    })
+    .await;
+    let (listen, forwarder) = common::start(
+        Forwarder::builder()
+            .socks_server(SocksServerConfig::default())
+            .proxy(ProxyConfig::new(proxy.addr.to_string()).with_fallback(Fallback::Proxy)),
+    );
+
+    for target in [echo, hidden].iter() {
+        let conn = common::connect(listen).await;
+        let mut stream = Socks5Stream::connect_with_socket(conn, *target)
+            .await
+            .unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut reply = [0; 5];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"hello");
+    }
+    // the reachable target was connected directly
+    assert_eq!(proxy.requests(), vec![hidden.to_string()]);
+    assert_eq!(forwarder.stats().fallback_to_proxy, 1);
+    assert_eq!(forwarder.stats().fallback_to_direct, 0);
+}
+
+#[tokio::test]
+async fn failed_handshake_falls_back() {
+    let target = common::count_server().await;
+    let proxy = MockSocks5::start(MockConfig {
+        reply: 2,
+        ..Default::default()
+    })
+    .await;
+    let (listen, forwarder) = common::start(
+        Forwarder::builder()
+            .target(target.to_string())
+            .proxy(ProxyConfig::new(proxy.addr.to_string()).with_fallback(Fallback::Direct)),
+    );
+    let reply = common::roundtrip(listen, &common::payload(1000))
+        .await
+        .unwrap();
+    assert_eq!(reply, b"1000");
+    assert_eq!(proxy.requests(), vec![target.to_string()]);
+    assert_eq!(forwarder.stats().fallback_to_direct, 1);
+}
+
+#[tokio::test]
+async fn rule_fallback() {
+    let target = common::echo_server().await;
+    let unreachable = common::free_addr();
+    let rules = RuleSet::default()
+        .proxy("a", ProxyConfig::new(unreachable.to_string()))
+        .add_rule(
+            Rule::new(
+                Matcher::DomainSuffix("localhost".to_string()),
+                Action::Proxy("a".to_string()),
+            )
+            .with_fallback(Fallback::Direct),
+        )
+        .default_action(Action::Proxy("a".to_string()));
+    let (listen, forwarder) = common::start(
+        Forwarder::builder()
+            .socks_server(SocksServerConfig::default())
+            .rules(rules),
+    );
+
+    // only the rule falls back, the proxy itself does not
+    let conn = common::connect(listen).await;
+    let mut stream = Socks5Stream::connect_with_socket(conn, ("localhost", target.port()))
+        .await
+        .unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    let mut reply = [0; 5];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"hello");
+    assert_eq!(forwarder.stats().fallback_to_direct, 1);
+
+    let conn = common::connect(listen).await;
+    assert!(Socks5Stream::connect_with_socket(conn, target)
+        .await
+        .is_err());
+    assert_eq!(forwarder.stats().fallback_to_direct, 1);
+}
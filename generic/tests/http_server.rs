@@ -0,0 +1,162 @@
+mod common;
+
+use std::net::SocketAddr;
+
+use common::{MockConfig, MockSocks5};
+use socks5_forwarder::{Credential, Forwarder, HttpServerConfig, ProxyConfig, Secret};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Origin answering each request with the request head it received.
+async fn origin() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut conn, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let head = read_head(&mut conn).await;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                    head.len(),
+                    head
+                );
+                let _ = conn.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    addr
+}
+
+async fn read_head(conn: &mut TcpStream) -> String {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        match conn.read_u8().await {
+            Ok(b) => head.push(b),
+            Err(_) => break,
+        }
+    }
+    String::from_utf8(head).unwrap()
+}
+
+fn server(credential: Option<Credential>) -> HttpServerConfig {
+    HttpServerConfig { credential }
+}
+
+#[tokio::test]
+async fn connect_tunnel() {
+    let target = common::echo_server().await;
+    let (listen, _) = common::start(Forwarder::builder().http_server(server(None)));
+
+    let mut conn = common::connect(listen).await;
+    let request = format!("CONNECT localhost:{} HTTP/1.1\r\n\r\n", target.port());
+    conn.write_all(request.as_bytes()).await.unwrap();
+    assert!(read_head(&mut conn).await.starts_with("HTTP/1.1 200"));
+
+    let data = common::payload(64 * 1024);
+    let (mut r, mut w) = conn.split();
+    let mut received = Vec::new();
+    let send = async {
+        w.write_all(&data).await?;
+        w.shutdown().await
+    };
+    tokio::try_join!(send, r.read_to_end(&mut received)).unwrap();
+    assert_eq!(received, data);
+}
+
+#[tokio::test]
+async fn plain_http() {
+    let target = origin().await;
+    let (listen, _) = common::start(Forwarder::builder().http_server(server(None)));
+
+    let mut conn = common::connect(listen).await;
+    let request = format!(
+        "GET http://127.0.0.1:{}/path?q=1 HTTP/1.1\r\nHost: 127.0.0.1:{}\r\nProxy-Connection: keep-alive\r\n\r\n",
+        target.port(),
+        target.port()
+    );
+    conn.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    conn.read_to_string(&mut response).await.unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(
+        response.contains("GET /path?q=1 HTTP/1.1\r\n"),
+        "{}",
+        response
+    );
+    assert!(response.contains("Connection: close\r\n"), "{}", response);
+    assert!(!response.contains("Proxy-Connection"), "{}", response);
+}
+
+#[tokio::test]
+async fn proxy_auth() {
+    let target = origin().await;
+    let (listen, _) = common::start(Forwarder::builder().http_server(server(Some(Credential {
+        username: "alice".to_string(),
+        password: Secret::from("secret".to_string()),
+    }))));
+    let request = |auth: &str| {
+        format!(
+            "GET http://127.0.0.1:{}/ HTTP/1.1\r\n{}\r\n",
+            target.port(),
+            auth
+        )
+    };
+
+    let mut conn = common::connect(listen).await;
+    conn.write_all(request("").as_bytes()).await.unwrap();
+    let head = read_head(&mut conn).await;
+    assert!(head.starts_with("HTTP/1.1 407"), "{}", head);
+    assert!(head.contains("Proxy-Authenticate: Basic"), "{}", head);
+
+    // alice:wrong
+    let mut conn = common::connect(listen).await;
+    let auth = "Proxy-Authorization: Basic YWxpY2U6d3Jvbmc=\r\n";
+    conn.write_all(request(auth).as_bytes()).await.unwrap();
+    assert!(read_head(&mut conn).await.starts_with("HTTP/1.1 407"));
+
+    // alice:secret
+    let mut conn = common::connect(listen).await;
+    let auth = "Proxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n";
+    conn.write_all(request(auth).as_bytes()).await.unwrap();
+    let mut response = String::new();
+    conn.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(!response.contains("Proxy-Authorization"), "{}", response);
+}
+
+#[tokio::test]
+async fn bridge_to_socks5() {
+    let target = common::echo_server().await;
+    let upstream = MockSocks5::start(MockConfig::default()).await;
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .http_server(server(None))
+            .proxy(ProxyConfig::new(upstream.addr.to_string())),
+    );
+
+    let mut conn = common::connect(listen).await;
+    let destination = format!("localhost:{}", target.port());
+    let request = format!(
+        "CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\nearly",
+        destination, destination
+    );
+    conn.write_all(request.as_bytes()).await.unwrap();
+    assert!(read_head(&mut conn).await.starts_with("HTTP/1.1 200"));
+    // bytes sent right after the request head are not lost
+    let mut early = [0; 5];
+    conn.read_exact(&mut early).await.unwrap();
+    assert_eq!(&early, b"early");
+    assert_eq!(upstream.requests(), vec![destination]);
+}
+
+#[tokio::test]
+async fn bad_gateway() {
+    let target = common::free_addr();
+    let (listen, _) = common::start(Forwarder::builder().http_server(server(None)));
+
+    let mut conn = common::connect(listen).await;
+    let request = format!("CONNECT {} HTTP/1.1\r\n\r\n", target);
+    conn.write_all(request.as_bytes()).await.unwrap();
+    assert!(read_head(&mut conn).await.starts_with("HTTP/1.1 502"));
+}
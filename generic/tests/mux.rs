@@ -0,0 +1,165 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use socks5_forwarder::{Forwarder, ForwarderBuilder, MuxConfig, Secret, SocksServerConfig};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_socks::tcp::Socks5Stream;
+
+fn secret(s: &str) -> Secret {
+    Secret::from(s.to_string())
+}
+
+fn mux(peer: SocketAddr, connections: usize) -> MuxConfig {
+    MuxConfig {
+        peer: peer.to_string(),
+        connections,
+        secret: secret("shared"),
+    }
+}
+
+fn mux_server() -> ForwarderBuilder {
+    Forwarder::builder().mux_server(secret("shared"))
+}
+
+#[tokio::test]
+async fn forward_over_mux() {
+    let target = common::count_server().await;
+    let (peer, server) = common::start(mux_server());
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .target(target.to_string())
+            .mux(mux(peer, 2)),
+    );
+
+    // larger than the stream window, so flow control is exercised
+    let data = common::payload(3 * 1024 * 1024);
+    let replies =
+        futures::future::join_all((0..16).map(|_| common::roundtrip(listen, &data))).await;
+    for reply in replies {
+        assert_eq!(reply.unwrap(), data.len().to_string().as_bytes());
+    }
+    // streams share the pooled sessions
+    assert_eq!(server.connections().len(), 2);
+}
+
+#[tokio::test]
+async fn socks_over_mux() {
+    let target = common::echo_server().await;
+    let (peer, _) = common::start(mux_server());
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .socks_server(SocksServerConfig::default())
+            .mux(mux(peer, 1)),
+    );
+
+    let conn = common::connect(listen).await;
+    let mut stream =
+        Socks5Stream::connect_with_socket(conn, format!("localhost:{}", target.port()))
+            .await
+            .unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    let mut reply = [0; 5];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"hello");
+
+    // the stream is reset when the target can not be connected
+    let unreachable = common::free_addr();
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .target(unreachable.to_string())
+            .mux(mux(peer, 1)),
+    );
+    let reply = common::roundtrip(listen, b"hello")
+        .await
+        .unwrap_or_default();
+    assert!(reply.is_empty());
+}
+
+#[tokio::test]
+async fn stalled_stream_does_not_block_session() {
+    let echo = common::echo_server().await;
+    // accepts but never reads
+    let stalled = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let (peer, _) = common::start(mux_server());
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .socks_server(SocksServerConfig::default())
+            .mux(mux(peer, 1)),
+    );
+
+    let conn = common::connect(listen).await;
+    let mut stream = Socks5Stream::connect_with_socket(conn, stalled.local_addr().unwrap())
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        let data = common::payload(16 * 1024 * 1024);
+        stream.write_all(&data).await
+    });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let conn = common::connect(listen).await;
+    let mut stream = Socks5Stream::connect_with_socket(conn, echo).await.unwrap();
+    let data = common::payload(256 * 1024);
+    let (mut r, mut w) = tokio::io::split(&mut stream);
+    let mut reply = vec![0; data.len()];
+    tokio::time::timeout(Duration::from_secs(5), async {
+        tokio::try_join!(w.write_all(&data), r.read_exact(&mut reply))
+    })
+    .await
+    .expect("session is blocked by the stalled stream")
+    .unwrap();
+    assert_eq!(reply, data);
+    drop(stalled);
+}
+
+#[tokio::test]
+async fn wrong_secret_is_rejected() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let (peer, _) = common::start(mux_server());
+    let guessed = MuxConfig {
+        secret: secret("guessed"),
+        ..mux(peer, 1)
+    };
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .target(target_addr.to_string())
+            .mux(guessed.clone()),
+    );
+    let reply = common::roundtrip(listen, b"hello")
+        .await
+        .unwrap_or_default();
+    assert!(reply.is_empty());
+
+    // frames sent without the handshake are not served either
+    let mut conn = common::connect(peer).await;
+    let open = target_addr.to_string();
+    let mut frames = vec![2, 0, 0, 0, 1];
+    frames.extend_from_slice(&(open.len() as u32).to_be_bytes());
+    frames.extend_from_slice(open.as_bytes());
+    frames.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0, 0, 64]);
+    frames.extend_from_slice(&[0x5a; 64]);
+    conn.write_all(&frames).await.unwrap();
+    let mut received = Vec::new();
+    let _ = conn.read_to_end(&mut received).await;
+    // the nonce and the denied status
+    assert_eq!(received.len(), 33);
+    assert_eq!(received[32], 1);
+    assert!(
+        tokio::time::timeout(Duration::from_millis(500), target.accept())
+            .await
+            .is_err()
+    );
+
+    let report = Forwarder::builder()
+        .listen(common::free_addr().to_string())
+        .target(target_addr.to_string())
+        .mux(guessed)
+        .check(Default::default())
+        .await;
+    assert!(!report.passed());
+    assert_eq!(report.steps.last().unwrap().name, "mux handshake");
+}
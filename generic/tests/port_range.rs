@@ -0,0 +1,108 @@
+mod common;
+
+use std::net::{IpAddr, SocketAddr};
+
+use socks5_forwarder::Forwarder;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Bind `n` consecutive ports on `ip`, retrying until a free range is found.
+fn bind_range(ip: &str, n: u16) -> Vec<std::net::TcpListener> {
+    let ip: IpAddr = ip.parse().unwrap();
+    loop {
+        let base = common::free_addr().port();
+        if base.checked_add(n).is_none() {
+            continue;
+        }
+        let listeners: Result<Vec<_>, _> = (base..base + n)
+            .map(|port| std::net::TcpListener::bind(SocketAddr::new(ip, port)))
+            .collect();
+        if let Ok(listeners) = listeners {
+            return listeners;
+        }
+    }
+}
+
+/// Targets which reply with the port they listen on and close.
+fn port_servers(listeners: Vec<std::net::TcpListener>) -> (u16, u16) {
+    let ports: Vec<_> = listeners
+        .iter()
+        .map(|l| l.local_addr().unwrap().port())
+        .collect();
+    for listener in listeners {
+        listener.set_nonblocking(true).unwrap();
+        let listener = TcpListener::from_std(listener).unwrap();
+        tokio::spawn(async move {
+            let port = listener.local_addr().unwrap().port();
+            while let Ok((mut conn, _)) = listener.accept().await {
+                let _ = conn.write_all(port.to_string().as_bytes()).await;
+            }
+        });
+    }
+    (ports[0], ports[ports.len() - 1])
+}
+
+/// Reserve a listen range for the forwarder.
+fn listen_range(ip: &str, n: u16) -> (u16, u16) {
+    let listeners = bind_range(ip, n);
+    let start = listeners[0].local_addr().unwrap().port();
+    (start, start + n - 1)
+}
+
+async fn reply_of(addr: SocketAddr) -> String {
+    let mut conn = common::connect(addr).await;
+    let mut reply = String::new();
+    conn.read_to_string(&mut reply).await.unwrap();
+    reply
+}
+
+#[tokio::test]
+async fn range_to_range() {
+    let (target_start, target_end) = port_servers(bind_range("127.0.0.1", 3));
+    let (listen_start, listen_end) = listen_range("127.0.0.1", 3);
+    let forwarder = Forwarder::builder()
+        .listen(format!("127.0.0.1:{}-{}", listen_start, listen_end))
+        .target(format!("127.0.0.1:{}-{}", target_start, target_end))
+        .build()
+        .unwrap();
+    let running = forwarder.clone();
+    tokio::spawn(async move { running.run().await });
+
+    for i in 0..3 {
+        let addr = SocketAddr::from(([127, 0, 0, 1], listen_start + i));
+        assert_eq!(reply_of(addr).await, (target_start + i).to_string());
+    }
+}
+
+#[tokio::test]
+async fn range_to_same_ports() {
+    // the target listens on the same ports of another loopback address
+    let (start, end) = port_servers(bind_range("127.0.0.2", 3));
+    let forwarder = Forwarder::builder()
+        .listen(format!("127.0.0.1:{}-{}", start, end))
+        .target("127.0.0.2")
+        .build()
+        .unwrap();
+    let running = forwarder.clone();
+    tokio::spawn(async move { running.run().await });
+
+    for port in start..=end {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        assert_eq!(reply_of(addr).await, port.to_string());
+    }
+}
+
+#[tokio::test]
+async fn range_length_mismatch() {
+    let result = Forwarder::builder()
+        .listen("127.0.0.1:10000-10002")
+        .target("127.0.0.1:20000-20001")
+        .build();
+    assert!(result.is_err());
+    let result = Forwarder::builder()
+        .listen("127.0.0.1:10000-10002")
+        .listen("127.0.0.1:10010")
+        .target("127.0.0.1")
+        .build();
+    assert!(result.is_err());
+}
@@ -0,0 +1,282 @@
+mod common;
+
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use common::{MockConfig, MockSocks5};
+use socks5_forwarder::{
+    DnsConfig, Forwarder, ProxyConfig, RelayMode, ResolveMode, RetryPolicy, Secret,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+/// The forwarder dropped the client without relaying anything.
+fn closed(result: std::io::Result<Vec<u8>>) -> bool {
+    match result {
+        Ok(data) => data.is_empty(),
+        Err(_) => true,
+    }
+}
+
+#[tokio::test]
+async fn direct_integrity() {
+    let target = common::echo_server().await;
+    for mode in [RelayMode::Userspace, RelayMode::Splice].iter() {
+        let (listen, _) =
+            common::start(Forwarder::builder().target(target.to_string()).mode(*mode));
+        let data = common::payload(1024 * 1024);
+        assert_eq!(
+            common::roundtrip(listen, &data).await.unwrap(),
+            data,
+            "{}",
+            mode
+        );
+    }
+}
+
+#[tokio::test]
+async fn half_close() {
+    let target = common::count_server().await;
+    for mode in [RelayMode::Userspace, RelayMode::Splice].iter() {
+        let (listen, _) =
+            common::start(Forwarder::builder().target(target.to_string()).mode(*mode));
+        // the target only answers after it sees our EOF
+        let received = common::roundtrip(listen, &common::payload(100_000))
+            .await
+            .unwrap();
+        assert_eq!(received, b"100000", "{}", mode);
+    }
+}
+
+#[tokio::test]
+async fn splice_target_closes_first() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = target.local_addr().unwrap();
+    let (received_tx, received_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let (mut conn, _) = target.accept().await.unwrap();
+        conn.write_all(&common::payload(100_000)).await.unwrap();
+        conn.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        conn.read_to_end(&mut received).await.unwrap();
+        let _ = received_tx.send(received);
+    });
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .target(addr.to_string())
+            .mode(RelayMode::Splice),
+    );
+
+    // the client keeps sending after the target is done
+    let mut conn = common::connect(listen).await;
+    let mut response = Vec::new();
+    conn.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, common::payload(100_000));
+    let data = common::payload(50_000);
+    conn.write_all(&data).await.unwrap();
+    conn.shutdown().await.unwrap();
+    assert_eq!(received_rx.await.unwrap(), data);
+}
+
+#[tokio::test]
+async fn proxy_no_auth() {
+    let target = common::echo_server().await;
+    let proxy = MockSocks5::start(MockConfig::default()).await;
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .target(format!("localhost:{}", target.port()))
+            .proxy(ProxyConfig::new(proxy.addr.to_string())),
+    );
+    let data = common::payload(64 * 1024);
+    assert_eq!(common::roundtrip(listen, &data).await.unwrap(), data);
+    // resolved by the proxy by default
+    assert_eq!(
+        proxy.requests(),
+        vec![format!("localhost:{}", target.port())]
+    );
+}
+
+#[tokio::test]
+async fn proxy_resolve_local() {
+    let target = common::echo_server().await;
+    let proxy = MockSocks5::start(MockConfig::default()).await;
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .target(format!("localhost:{}", target.port()))
+            .proxy(ProxyConfig::new(proxy.addr.to_string()))
+            .resolve(ResolveMode::Local),
+    );
+    let data = common::payload(1024);
+    assert_eq!(common::roundtrip(listen, &data).await.unwrap(), data);
+    // localhost may resolve to ::1 too, where the target does not listen
+    let requests = proxy.requests();
+    assert!(
+        requests.iter().all(|r| !r.starts_with("localhost")),
+        "{:?}",
+        requests
+    );
+    assert_eq!(requests.last(), Some(&target.to_string()));
+}
+
+#[tokio::test]
+async fn proxy_local_resolve_tries_each_address() {
+    let target = common::echo_server().await;
+    // nothing listens on the first address
+    let dns = common::dns_server(vec![Ipv4Addr::new(127, 0, 0, 3), Ipv4Addr::LOCALHOST]).await;
+    let proxy = MockSocks5::start(MockConfig::default()).await;
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .target(format!("multi.test:{}", target.port()))
+            .proxy(ProxyConfig::new(proxy.addr.to_string()))
+            .resolve(ResolveMode::Local)
+            .dns(DnsConfig {
+                nameservers: vec![dns],
+                ..Default::default()
+            }),
+    );
+    let data = common::payload(1024);
+    assert_eq!(common::roundtrip(listen, &data).await.unwrap(), data);
+    assert_eq!(
+        proxy.requests(),
+        vec![format!("127.0.0.3:{}", target.port()), target.to_string()]
+    );
+}
+
+#[tokio::test]
+async fn proxy_password_auth() {
+    let target = common::echo_server().await;
+    let proxy = MockSocks5::start(MockConfig {
+        credential: Some(("user".to_string(), "p@ss".to_string())),
+        ..Default::default()
+    })
+    .await;
+    let url = format!("socks5://user:p%40ss@{}", proxy.addr);
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .target(target.to_string())
+            .proxy(url.parse().unwrap()),
+    );
+    let data = common::payload(64 * 1024);
+    assert_eq!(common::roundtrip(listen, &data).await.unwrap(), data);
+}
+
+#[tokio::test]
+async fn proxy_auth_failure() {
+    let target = common::echo_server().await;
+    let proxy = MockSocks5::start(MockConfig {
+        credential: Some(("user".to_string(), "pass".to_string())),
+        ..Default::default()
+    })
+    .await;
+    for proxy_config in [
+        ProxyConfig::new(proxy.addr.to_string())
+            .with_credential("user", Secret::from("wrong".to_string())),
+        ProxyConfig::new(proxy.addr.to_string()),
+    ]
+    .iter()
+    {
+        let (listen, _) = common::start(
+            Forwarder::builder()
+                .target(target.to_string())
+                .proxy(proxy_config.clone()),
+        );
+        assert!(closed(common::roundtrip(listen, b"hello").await));
+    }
+    assert!(proxy.requests().is_empty());
+}
+
+#[tokio::test]
+async fn proxy_failure_reply() {
+    let target = common::echo_server().await;
+    // connection refused
+    let proxy = MockSocks5::start(MockConfig {
+        reply: 5,
+        ..Default::default()
+    })
+    .await;
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .target(target.to_string())
+            .proxy(ProxyConfig::new(proxy.addr.to_string())),
+    );
+    assert!(closed(common::roundtrip(listen, b"hello").await));
+    assert_eq!(proxy.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn proxy_failure_retried() {
+    let target = common::echo_server().await;
+    // general failure twice, then connect
+    let proxy = MockSocks5::start(MockConfig {
+        reply: 1,
+        fail_first: Some(2),
+        ..Default::default()
+    })
+    .await;
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .target(target.to_string())
+            .proxy(ProxyConfig::new(proxy.addr.to_string()))
+            .retry(RetryPolicy {
+                attempts: 3,
+                initial_backoff: Duration::from_millis(10),
+                ..Default::default()
+            }),
+    );
+    let data = common::payload(1024);
+    assert_eq!(common::roundtrip(listen, &data).await.unwrap(), data);
+    assert_eq!(proxy.requests().len(), 3);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn large_transfer() {
+    let target = common::echo_server().await;
+    let proxy = MockSocks5::start(MockConfig::default()).await;
+    for mode in [RelayMode::Userspace, RelayMode::Splice].iter() {
+        let (listen, _) = common::start(
+            Forwarder::builder()
+                .target(target.to_string())
+                .proxy(ProxyConfig::new(proxy.addr.to_string()))
+                .mode(*mode),
+        );
+        let data = common::payload(64 * 1024 * 1024);
+        let received = common::roundtrip(listen, &data).await.unwrap();
+        assert!(received == data, "{}: corrupted transfer", mode);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_connections() {
+    let target = common::echo_server().await;
+    let proxy = MockSocks5::start(MockConfig::default()).await;
+    let (direct, _) = common::start(Forwarder::builder().target(target.to_string()));
+    let (proxied, _) = common::start(
+        Forwarder::builder()
+            .target(target.to_string())
+            .proxy(ProxyConfig::new(proxy.addr.to_string())),
+    );
+
+    let clients = (0..100).map(|i| {
+        let listen = if i % 2 == 0 { direct } else { proxied };
+        tokio::spawn(async move {
+            let data = common::payload(64 * 1024 + i);
+            assert_eq!(common::roundtrip(listen, &data).await.unwrap(), data);
+        })
+    });
+    for client in futures::future::join_all(clients).await {
+        client.unwrap();
+    }
+    assert_eq!(proxy.requests().len(), 50);
+}
+
+#[tokio::test]
+async fn shutdown_stops_accepting() {
+    let target = common::echo_server().await;
+    let (listen, forwarder) = common::start(Forwarder::builder().target(target.to_string()));
+    assert_eq!(common::roundtrip(listen, b"ping").await.unwrap(), b"ping");
+
+    forwarder.shutdown();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(tokio::net::TcpStream::connect(listen).await.is_err());
+}
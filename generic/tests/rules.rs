@@ -0,0 +1,245 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::path::Path;
+
+use common::{MockConfig, MockSocks5};
+use socks5_forwarder::{
+    Action, Fallback, Forwarder, HttpServerConfig, Matcher, ProxyConfig, ResolveMode, Rule,
+    RuleSet, SocksServerConfig,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_socks::tcp::Socks5Stream;
+use tokio_socks::TargetAddr;
+
+/// Send a few bytes through the socks server and read them back.
+async fn socks_echo(listen: SocketAddr, target: TargetAddr<'_>) -> anyhow::Result<()> {
+    let conn = common::connect(listen).await;
+    let mut stream = Socks5Stream::connect_with_socket(conn, target).await?;
+    stream.write_all(b"hello").await?;
+    let mut reply = [0; 5];
+    stream.read_exact(&mut reply).await?;
+    assert_eq!(&reply, b"hello");
+    Ok(())
+}
+
+#[tokio::test]
+async fn socks_rules() {
+    let echo = common::echo_server().await;
+    let proxy = MockSocks5::start(MockConfig {
+        connect_to: Some(echo),
+        ..Default::default()
+    })
+    .await;
+    let blocked = common::free_addr();
+    let rules = format!(
+        "# the proxy reaches the example domains\n\
+         proxy a socks5://{}\n\
+         domain-suffix example.test a\n\
+         port {} reject\n\
+         cidr 127.0.0.0/8 direct # localhost too, once resolved\n\
+         final reject\n",
+        proxy.addr,
+        blocked.port()
+    );
+    let rules = RuleSet::parse(&rules, Path::new(".")).unwrap();
+    let (listen, forwarder) = common::start(
+        Forwarder::builder()
+            .socks_server(SocksServerConfig::default())
+            .rules(rules),
+    );
+
+    socks_echo(listen, TargetAddr::Domain("www.Example.test".into(), 80))
+        .await
+        .unwrap();
+    socks_echo(listen, TargetAddr::Ip(echo)).await.unwrap();
+    socks_echo(listen, TargetAddr::Domain("localhost".into(), echo.port()))
+        .await
+        .unwrap();
+    assert!(socks_echo(listen, TargetAddr::Ip(blocked)).await.is_err());
+    assert!(
+        socks_echo(listen, TargetAddr::Domain("example.org".into(), 80))
+            .await
+            .is_err()
+    );
+    // only the matching domain went through the proxy
+    assert_eq!(proxy.requests(), vec!["www.Example.test:80".to_string()]);
+    assert_eq!(forwarder.config().rules, Some(3));
+}
+
+#[tokio::test]
+async fn http_rules() {
+    let echo = common::echo_server().await;
+    let proxy = MockSocks5::start(MockConfig::default()).await;
+    let rules = RuleSet::default()
+        .proxy("a", ProxyConfig::new(proxy.addr.to_string()))
+        .rule(Matcher::DomainKeyword("ads".to_string()), Action::Reject)
+        .rule(
+            Matcher::Client(vec!["127.0.0.1".parse().unwrap()]),
+            Action::Proxy("a".to_string()),
+        );
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .http_server(HttpServerConfig::default())
+            .rules(rules),
+    );
+
+    let mut conn = common::connect(listen).await;
+    conn.write_all(b"CONNECT ads.example.test:443 HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    conn.read_to_end(&mut response).await.unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 403"));
+
+    let mut conn = common::connect(listen).await;
+    let request = format!("CONNECT localhost:{} HTTP/1.1\r\n\r\n", echo.port());
+    conn.write_all(request.as_bytes()).await.unwrap();
+    let mut response = vec![0; 39];
+    conn.read_exact(&mut response).await.unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 200"));
+    conn.write_all(b"hello").await.unwrap();
+    let mut reply = [0; 5];
+    conn.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"hello");
+    assert_eq!(proxy.requests(), vec![format!("localhost:{}", echo.port())]);
+}
+
+#[tokio::test]
+async fn rule_options() {
+    let echo = common::echo_server().await;
+    let proxy = MockSocks5::start(MockConfig::default()).await;
+    let rules = RuleSet::default()
+        .proxy("a", ProxyConfig::new(proxy.addr.to_string()))
+        .add_rule(
+            Rule::new(
+                Matcher::DomainSuffix("localhost".to_string()),
+                Action::Proxy("a".to_string()),
+            )
+            .with_resolve(ResolveMode::Local),
+        )
+        .default_action(Action::Proxy("a".to_string()));
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .socks_server(SocksServerConfig::default())
+            .rules(rules),
+    );
+
+    // resolved here for the rule, by the proxy otherwise
+    let localhost = TargetAddr::Domain("localhost".into(), echo.port());
+    socks_echo(listen, localhost).await.unwrap();
+    let requests = proxy.requests();
+    assert!(
+        requests.iter().all(|r| !r.starts_with("localhost")),
+        "{:?}",
+        requests
+    );
+    assert_eq!(requests.last(), Some(&echo.to_string()));
+    let other = TargetAddr::Domain("loopback.test".into(), echo.port());
+    assert!(socks_echo(listen, other).await.is_err());
+    assert_eq!(
+        proxy.requests().last(),
+        Some(&format!("loopback.test:{}", echo.port()))
+    );
+
+    let dir = Path::new(".");
+    let options = "proxy a socks5://127.0.0.1:1080 resolve=local\n\
+                   port 22 a resolve=remote\n\
+                   port 23 a fallback=direct resolve=local\n";
+    let rules = RuleSet::parse(options, dir).unwrap();
+    assert_eq!(rules.rules()[0].resolve, Some(ResolveMode::Remote));
+    assert_eq!(rules.rules()[0].fallback, None);
+    assert_eq!(rules.rules()[1].fallback, Some(Fallback::Direct));
+    assert_eq!(rules.rules()[1].resolve, Some(ResolveMode::Local));
+    // options only go with proxies
+    assert!(RuleSet::parse("port 22 direct resolve=local\n", dir).is_err());
+    assert!(RuleSet::parse("port 22 reject fallback=direct\n", dir).is_err());
+    assert!(RuleSet::parse("proxy a 127.0.0.1:1080\nport 22 a dns=1\n", dir).is_err());
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn rule_bind() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    // only the source of the connection matters, no handshake is answered
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let rules = format!(
+        "proxy a socks5://{} bind=127.0.0.3\n\
+         port {} direct bind=127.0.0.2\n\
+         port 81 a\n\
+         port 82 a bind=127.0.0.4 fwmark=0\n",
+        proxy.local_addr().unwrap(),
+        target_addr.port()
+    );
+    let rules = RuleSet::parse(&rules, Path::new(".")).unwrap();
+    assert_eq!(rules.rules()[2].bind.mark, Some(0));
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .socks_server(SocksServerConfig::default())
+            .rules(rules),
+    );
+    let request = |target: TargetAddr<'static>| {
+        tokio::spawn(async move {
+            let conn = common::connect(listen).await;
+            let _ = Socks5Stream::connect_with_socket(conn, target).await;
+        })
+    };
+
+    request(TargetAddr::Ip(target_addr));
+    let (_, peer) = target.accept().await.unwrap();
+    assert_eq!(peer.ip().to_string(), "127.0.0.2");
+    // the proxy declaration binds its connections
+    request(TargetAddr::Domain("example.test".into(), 81));
+    let (_, peer) = proxy.accept().await.unwrap();
+    assert_eq!(peer.ip().to_string(), "127.0.0.3");
+    // and a rule overrides it
+    request(TargetAddr::Domain("example.test".into(), 82));
+    let (_, peer) = proxy.accept().await.unwrap();
+    assert_eq!(peer.ip().to_string(), "127.0.0.4");
+
+    let dir = Path::new(".");
+    let parsed = RuleSet::parse("port 22 direct interface=eth1\n", dir).unwrap();
+    assert_eq!(parsed.rules()[0].bind.interface.as_deref(), Some("eth1"));
+    assert!(RuleSet::parse("port 22 reject bind=127.0.0.2\n", dir).is_err());
+    assert!(RuleSet::parse("port 22 direct bind=localhost\n", dir).is_err());
+}
+
+#[test]
+fn load_rules() {
+    let dir = std::env::temp_dir().join(format!("forwarder-rules-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("lan.txt"),
+        "# private\n10.0.0.0/8\n192.168.0.0/16\n",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("rules.txt"),
+        "proxy a socks5://127.0.0.1:1080 fallback=direct\ncidr-file lan.txt direct\nfinal a\n",
+    )
+    .unwrap();
+    let rules = RuleSet::from_file(dir.join("rules.txt")).unwrap();
+    match &rules.rules()[0].matcher {
+        Matcher::Cidr(networks) => assert_eq!(networks.len(), 2),
+        other => panic!("unexpected matcher {:?}", other),
+    }
+
+    // actions must refer to declared proxies
+    let undeclared = RuleSet::parse("port 22 b\n", &dir).unwrap_err();
+    assert!(undeclared.to_string().contains("not declared"));
+    let invalid = RuleSet::parse("proxy a socks5://127.0.0.1:1080\nport 9-1 a\n", &dir);
+    assert!(invalid.unwrap_err().to_string().contains("line 2"));
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // rules need a server mode
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let _guard = runtime.enter();
+    let built = Forwarder::builder()
+        .listen("127.0.0.1:0")
+        .target("127.0.0.1:80")
+        .rules(rules)
+        .build();
+    assert!(built.is_err());
+}
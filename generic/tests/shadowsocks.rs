@@ -0,0 +1,96 @@
+mod common;
+
+use common::MockShadowsocks;
+use socks5_forwarder::{Cipher, Forwarder, ProxyConfig, Secret, SocksServerConfig};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_socks::tcp::Socks5Stream;
+
+#[tokio::test]
+async fn forward_via_shadowsocks() {
+    let target = common::echo_server().await;
+    for (name, cipher) in [
+        ("chacha20-ietf-poly1305", Cipher::Chacha20IetfPoly1305),
+        ("aes-256-gcm", Cipher::Aes256Gcm),
+    ]
+    .iter()
+    {
+        let server = MockShadowsocks::start(name, "secret").await;
+        let (listen, forwarder) = common::start(
+            Forwarder::builder()
+                .target(format!("localhost:{}", target.port()))
+                .proxy(ProxyConfig::shadowsocks(
+                    server.addr.to_string(),
+                    *cipher,
+                    Secret::from("secret".to_string()),
+                )),
+        );
+        // several chunks each way
+        let data = common::payload(1024 * 1024);
+        assert_eq!(
+            common::roundtrip(listen, &data).await.unwrap(),
+            data,
+            "{}",
+            name
+        );
+        assert_eq!(
+            server.requests(),
+            vec![format!("localhost:{}", target.port())]
+        );
+        assert_eq!(forwarder.config().proxy_protocol, Some("shadowsocks"));
+    }
+}
+
+#[tokio::test]
+async fn socks_server_via_shadowsocks() {
+    let target = common::count_server().await;
+    let server = MockShadowsocks::start("chacha20-ietf-poly1305", "p@ss:word").await;
+    // SIP002 url with base64 userinfo
+    let userinfo = base64::encode_config("chacha20-ietf-poly1305:p@ss:word", base64::URL_SAFE);
+    let proxy = format!("ss://{}@{}", userinfo, server.addr)
+        .parse()
+        .unwrap();
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .socks_server(SocksServerConfig::default())
+            .proxy(proxy),
+    );
+
+    let conn = common::connect(listen).await;
+    let mut stream = Socks5Stream::connect_with_socket(conn, target)
+        .await
+        .unwrap();
+    stream.write_all(&common::payload(100_000)).await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await.unwrap();
+    assert_eq!(reply, b"100000");
+    assert_eq!(server.requests(), vec![target.to_string()]);
+}
+
+#[tokio::test]
+async fn wrong_password_is_dropped() {
+    let target = common::echo_server().await;
+    let server = MockShadowsocks::start("aes-256-gcm", "secret").await;
+    let proxy = format!("ss://aes-256-gcm:guessed@{}", server.addr)
+        .parse()
+        .unwrap();
+    let (listen, _) = common::start(Forwarder::builder().target(target.to_string()).proxy(proxy));
+    let reply = common::roundtrip(listen, b"hello")
+        .await
+        .unwrap_or_default();
+    assert!(reply.is_empty());
+    assert!(server.requests().is_empty());
+}
+
+#[tokio::test]
+async fn oversized_chunk_is_rejected() {
+    let target = common::echo_server().await;
+    let server = MockShadowsocks::with_chunk("aes-256-gcm", "secret", 0x4000).await;
+    let proxy = format!("ss://aes-256-gcm:secret@{}", server.addr)
+        .parse()
+        .unwrap();
+    let (listen, _) = common::start(Forwarder::builder().target(target.to_string()).proxy(proxy));
+    let data = common::payload(0x4000);
+    let reply = common::roundtrip(listen, &data).await.unwrap_or_default();
+    assert!(reply.is_empty());
+}
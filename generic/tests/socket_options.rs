@@ -0,0 +1,137 @@
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::mem::ManuallyDrop;
+use std::net::SocketAddr;
+use std::os::unix::io::FromRawFd;
+use std::time::Duration;
+
+use socket2::Socket;
+use socks5_forwarder::{Forwarder, Keepalive, ListenOptions, SocketOptions};
+use tokio::net::TcpListener;
+
+/// Sockets of this process, the forwarder included, matching `pred`.
+fn sockets(pred: impl Fn(&Socket) -> bool) -> Vec<ManuallyDrop<Socket>> {
+    std::fs::read_dir("/proc/self/fd")
+        .unwrap()
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        // borrowed, the owner closes it
+        .map(|fd| ManuallyDrop::new(unsafe { Socket::from_raw_fd(fd) }))
+        .filter(|socket| pred(socket))
+        .collect()
+}
+
+/// The connected socket between `local` and `peer`, waiting for the forwarder to set it up.
+async fn connected(local: SocketAddr, peer: SocketAddr) -> ManuallyDrop<Socket> {
+    for _ in 0..50 {
+        let mut found = sockets(|s| {
+            let addr = |a: std::io::Result<socket2::SockAddr>| a.ok().and_then(|a| a.as_socket());
+            addr(s.local_addr()) == Some(local) && addr(s.peer_addr()) == Some(peer)
+        });
+        if let Some(socket) = found.pop() {
+            return socket;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("no socket from {} to {}", local, peer);
+}
+
+/// Listening sockets bound to `addr`.
+fn listening(addr: SocketAddr) -> Vec<ManuallyDrop<Socket>> {
+    sockets(|s| {
+        s.local_addr().ok().and_then(|a| a.as_socket()) == Some(addr) && s.peer_addr().is_err()
+    })
+}
+
+#[tokio::test]
+async fn applied_options() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let accepted = SocketOptions {
+        nodelay: Some(true),
+        keepalive: Some(Keepalive {
+            time: Duration::from_secs(30),
+            interval: Some(Duration::from_secs(5)),
+            retries: Some(3),
+        }),
+        send_buffer_size: Some(64 * 1024),
+        recv_buffer_size: Some(96 * 1024),
+        ..Default::default()
+    };
+    let outbound = SocketOptions {
+        nodelay: Some(true),
+        keepalive: Some(Keepalive::new(Duration::from_secs(20))),
+        send_buffer_size: Some(48 * 1024),
+        ..Default::default()
+    };
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .target(target.local_addr().unwrap().to_string())
+            .listen_options(ListenOptions {
+                socket: accepted,
+                ..Default::default()
+            })
+            .outbound_options(outbound),
+    );
+
+    let client = common::connect(listen).await;
+    let (_, from) = target.accept().await.unwrap();
+
+    let socket = connected(listen, client.local_addr().unwrap()).await;
+    assert!(socket.nodelay().unwrap());
+    assert!(socket.keepalive().unwrap());
+    assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+    assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(5));
+    assert_eq!(socket.keepalive_retries().unwrap(), 3);
+    // linux doubles the requested sizes for bookkeeping
+    assert!(socket.send_buffer_size().unwrap() >= 64 * 1024);
+    assert!(socket.recv_buffer_size().unwrap() >= 96 * 1024);
+
+    let socket = connected(from, target.local_addr().unwrap()).await;
+    assert!(socket.nodelay().unwrap());
+    assert!(socket.keepalive().unwrap());
+    assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(20));
+    assert_eq!(
+        socket.keepalive_interval().unwrap(),
+        Duration::from_secs(20)
+    );
+    assert!(socket.send_buffer_size().unwrap() >= 48 * 1024);
+}
+
+#[tokio::test]
+async fn reuse_port() {
+    let listen = common::free_addr();
+    let target = common::echo_server().await;
+    let forwarder = |reuse_port| {
+        Forwarder::builder()
+            .listen(listen.to_string())
+            .target(target.to_string())
+            .listen_options(ListenOptions {
+                reuse_port,
+                ..Default::default()
+            })
+            .build()
+            .unwrap()
+    };
+
+    let first = forwarder(true);
+    let running = first.clone();
+    tokio::spawn(async move { running.run().await });
+    drop(common::connect(listen).await);
+    // without the option the port is taken
+    assert!(forwarder(false).run().await.is_err());
+
+    let second = forwarder(true);
+    let running = second.clone();
+    tokio::spawn(async move { running.run().await });
+    for _ in 0..50 {
+        if listening(listen).len() == 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let listeners = listening(listen);
+    assert_eq!(listeners.len(), 2);
+    assert!(listeners.iter().all(|s| s.reuse_port().unwrap()));
+    assert_eq!(common::roundtrip(listen, b"hello").await.unwrap(), b"hello");
+}
@@ -0,0 +1,146 @@
+mod common;
+
+use common::{MockConfig, MockSocks5};
+use socks5_forwarder::{Credential, Forwarder, ProxyConfig, Secret, SocksServerConfig};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_socks::tcp::Socks5Stream;
+
+fn credential(username: &str, password: &str) -> Credential {
+    Credential {
+        username: username.to_string(),
+        password: Secret::from(password.to_string()),
+    }
+}
+
+async fn echo_through(stream: &mut (impl AsyncReadExt + AsyncWriteExt + Unpin), data: &[u8]) {
+    stream.write_all(data).await.unwrap();
+    let mut received = vec![0; data.len()];
+    stream.read_exact(&mut received).await.unwrap();
+    assert_eq!(received, data);
+}
+
+#[tokio::test]
+async fn socks5_no_auth() {
+    let target = common::echo_server().await;
+    let (listen, _) =
+        common::start(Forwarder::builder().socks_server(SocksServerConfig::default()));
+    let conn = common::connect(listen).await;
+
+    let mut stream =
+        Socks5Stream::connect_with_socket(conn, format!("localhost:{}", target.port()))
+            .await
+            .unwrap();
+    echo_through(&mut stream, &common::payload(64 * 1024)).await;
+}
+
+#[tokio::test]
+async fn socks5_auth() {
+    let target = common::echo_server().await;
+    let (listen, _) = common::start(Forwarder::builder().socks_server(SocksServerConfig {
+        credential: Some(credential("alice", "secret")),
+        ..Default::default()
+    }));
+
+    let conn = common::connect(listen).await;
+    let mut stream =
+        Socks5Stream::connect_with_password_and_socket(conn, target, "alice", "secret")
+            .await
+            .unwrap();
+    echo_through(&mut stream, b"hello").await;
+
+    let conn = common::connect(listen).await;
+    assert!(
+        Socks5Stream::connect_with_password_and_socket(conn, target, "alice", "wrong")
+            .await
+            .is_err()
+    );
+    let conn = common::connect(listen).await;
+    assert!(Socks5Stream::connect_with_socket(conn, target)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn socks5_via_upstream() {
+    let target = common::echo_server().await;
+    let upstream = MockSocks5::start(MockConfig {
+        credential: Some(("upstream".to_string(), "pass".to_string())),
+        ..Default::default()
+    })
+    .await;
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .socks_server(SocksServerConfig {
+                credential: Some(credential("client", "other")),
+                ..Default::default()
+            })
+            .proxy(
+                ProxyConfig::new(upstream.addr.to_string())
+                    .with_credential("upstream", Secret::from("pass".to_string())),
+            ),
+    );
+
+    let conn = common::connect(listen).await;
+    let destination = format!("localhost:{}", target.port());
+    let mut stream = Socks5Stream::connect_with_password_and_socket(
+        conn,
+        destination.as_str(),
+        "client",
+        "other",
+    )
+    .await
+    .unwrap();
+    echo_through(&mut stream, &common::payload(1024)).await;
+    assert_eq!(upstream.requests(), vec![destination]);
+}
+
+#[tokio::test]
+async fn socks5_connect_refused() {
+    // nothing listens there
+    let target = common::free_addr();
+    let (listen, _) =
+        common::start(Forwarder::builder().socks_server(SocksServerConfig::default()));
+    let conn = common::connect(listen).await;
+    match Socks5Stream::connect_with_socket(conn, target).await {
+        Err(tokio_socks::Error::ConnectionRefused) => (),
+        other => panic!("unexpected result {:?}", other.map(|_| ())),
+    }
+}
+
+#[tokio::test]
+async fn socks4a() {
+    let target = common::echo_server().await;
+    let (listen, _) = common::start(Forwarder::builder().socks_server(SocksServerConfig {
+        socks4: true,
+        ..Default::default()
+    }));
+
+    let mut conn = common::connect(listen).await;
+    let mut request = vec![4, 1];
+    request.extend_from_slice(&target.port().to_be_bytes());
+    request.extend_from_slice(&[0, 0, 0, 1]);
+    request.extend_from_slice(b"user\0localhost\0");
+    conn.write_all(&request).await.unwrap();
+    let mut reply = [0; 8];
+    conn.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], 90);
+    echo_through(&mut conn, b"hello").await;
+}
+
+#[tokio::test]
+async fn socks4_refused_with_auth() {
+    let target = common::echo_server().await;
+    let (listen, _) = common::start(Forwarder::builder().socks_server(SocksServerConfig {
+        credential: Some(credential("alice", "secret")),
+        socks4: true,
+    }));
+
+    let mut conn = common::connect(listen).await;
+    let mut request = vec![4, 1];
+    request.extend_from_slice(&target.port().to_be_bytes());
+    request.extend_from_slice(&[127, 0, 0, 1, 0]);
+    conn.write_all(&request).await.unwrap();
+    let mut reply = [0; 8];
+    conn.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], 91);
+}
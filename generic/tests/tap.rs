@@ -0,0 +1,107 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use socks5_forwarder::{Forwarder, TapConfig, TapDirection, TapSink};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+/// Tap sink sending what each mirror connection carried once it is closed.
+async fn sink() -> (SocketAddr, mpsc::UnboundedReceiver<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut conn, _)) = listener.accept().await {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut received = Vec::new();
+                conn.read_to_end(&mut received).await.unwrap();
+                let _ = tx.send(received);
+            });
+        }
+    });
+    (addr, rx)
+}
+
+async fn next(rx: &mut mpsc::UnboundedReceiver<Vec<u8>>) -> Vec<u8> {
+    tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("no mirrored stream")
+        .unwrap()
+}
+
+#[tokio::test]
+async fn mirror_to_tcp() {
+    let target = common::count_server().await;
+    let (sink, mut streams) = sink().await;
+    let (listen, _) = common::start(
+        Forwarder::builder()
+            .target(target.to_string())
+            .tap(TapConfig::new(TapSink::Tcp(sink.to_string()))),
+    );
+
+    let data = common::payload(100 * 1024);
+    let reply = common::roundtrip(listen, &data).await.unwrap();
+    assert_eq!(reply, b"102400");
+
+    let mut mirrored = vec![next(&mut streams).await, next(&mut streams).await];
+    mirrored.sort_by_key(|m| m.len());
+    assert_eq!(mirrored, vec![reply, data]);
+}
+
+#[tokio::test]
+async fn slow_sink_does_not_slow_relay() {
+    let target = common::count_server().await;
+    // accepts mirror connections but never reads them
+    let sink = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut tap = TapConfig::new(TapSink::Tcp(sink.local_addr().unwrap().to_string()));
+    tap.direction = TapDirection::ClientToTarget;
+    tap.buffer = 64 * 1024;
+    let (listen, _) = common::start(Forwarder::builder().target(target.to_string()).tap(tap));
+
+    let data = common::payload(32 * 1024 * 1024);
+    let reply = tokio::time::timeout(Duration::from_secs(10), common::roundtrip(listen, &data))
+        .await
+        .expect("relay is blocked by the tap")
+        .unwrap();
+    assert_eq!(reply, data.len().to_string().as_bytes());
+    drop(sink);
+}
+
+#[tokio::test]
+async fn mirror_to_dir_for_selected_clients() {
+    let target = common::echo_server().await;
+    let dir = std::env::temp_dir().join(format!("forwarder-tap-{}", std::process::id()));
+    let files = || std::fs::read_dir(&dir).unwrap().count();
+
+    let mut tap = TapConfig::new(TapSink::Dir(dir.clone()));
+    tap.clients = vec!["127.0.0.2".parse().unwrap()];
+    let (listen, _) = common::start(Forwarder::builder().target(target.to_string()).tap(tap));
+    common::roundtrip(listen, b"not tapped").await.unwrap();
+    assert_eq!(files(), 0);
+
+    let mut tap = TapConfig::new(TapSink::Dir(dir.clone()));
+    tap.clients = vec!["127.0.0.1".parse().unwrap()];
+    tap.direction = TapDirection::TargetToClient;
+    let (listen, _) = common::start(Forwarder::builder().target(target.to_string()).tap(tap));
+    common::roundtrip(listen, b"tapped").await.unwrap();
+    // the writer task finishes shortly after the relay
+    let mut content = Vec::new();
+    for _ in 0..50 {
+        if let Some(entry) = std::fs::read_dir(&dir).unwrap().next() {
+            let path = entry.unwrap().path();
+            assert!(path.to_string_lossy().ends_with("-target-to-client.bin"));
+            content = std::fs::read(path).unwrap();
+            if !content.is_empty() {
+                break;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(files(), 1);
+    assert_eq!(content, b"tapped");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
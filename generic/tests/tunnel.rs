@@ -0,0 +1,165 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use socks5_forwarder::{
+    Agent, AgentConfig, ConnectOptions, Forwarder, RelayMode, Secret, TunnelServerConfig,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+fn tunnel_server(tunnel: SocketAddr) -> socks5_forwarder::ForwarderBuilder {
+    Forwarder::builder().tunnel_server(TunnelServerConfig {
+        listen: tunnel.to_string(),
+        secret: Secret::from("shared".to_string()),
+    })
+}
+
+fn agent(tunnel: SocketAddr, target: SocketAddr, secret: &str) -> Agent {
+    Agent::new(
+        AgentConfig {
+            server: tunnel.to_string(),
+            target: target.to_string(),
+            secret: Secret::from(secret.to_string()),
+        },
+        ConnectOptions::default(),
+        RelayMode::Auto,
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+async fn relay_through_agent() {
+    let target = common::count_server().await;
+    let tunnel = common::free_addr();
+    let (listen, forwarder) = common::start(tunnel_server(tunnel));
+    let agent = agent(tunnel, target, "shared");
+    let running = agent.clone();
+    tokio::spawn(async move { running.run().await });
+
+    // clients are closed until the agent is connected
+    let data = common::payload(1024 * 1024);
+    let mut reply = Vec::new();
+    for _ in 0..50 {
+        reply = common::roundtrip(listen, &data).await.unwrap_or_default();
+        if !reply.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(reply, data.len().to_string().as_bytes());
+
+    let replies =
+        futures::future::join_all((1..=8).map(|i| common::roundtrip(listen, &data[..i * 1000])))
+            .await;
+    for (i, reply) in replies.into_iter().enumerate() {
+        assert_eq!(reply.unwrap(), ((i + 1) * 1000).to_string().as_bytes());
+    }
+    assert_eq!(
+        forwarder.config().tunnel_listen.as_deref(),
+        Some(tunnel.to_string().as_str())
+    );
+    agent.shutdown();
+}
+
+#[tokio::test]
+async fn unreachable_target_closes_client() {
+    // answers until dropped, to tell when the agent is connected
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let accepting = tokio::spawn(async move {
+        while let Ok((mut conn, _)) = target.accept().await {
+            let _ = conn.write_all(b"ok").await;
+        }
+    });
+    let tunnel = common::free_addr();
+    let (listen, _) = common::start(tunnel_server(tunnel));
+    let agent = agent(tunnel, target_addr, "shared");
+    let running = agent.clone();
+    tokio::spawn(async move { running.run().await });
+    let mut reply = Vec::new();
+    for _ in 0..50 {
+        reply = common::roundtrip(listen, b"hello")
+            .await
+            .unwrap_or_default();
+        if !reply.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(reply, b"ok");
+    accepting.abort();
+    let _ = accepting.await;
+
+    // the client is closed at once instead of waiting for the open timeout
+    let start = Instant::now();
+    let reply = tokio::time::timeout(Duration::from_secs(5), common::roundtrip(listen, b"hello"))
+        .await
+        .expect("client waits for the open timeout");
+    assert!(reply.unwrap_or_default().is_empty());
+    assert!(start.elapsed() < Duration::from_secs(5));
+    agent.shutdown();
+}
+
+#[tokio::test]
+async fn wrong_secret_is_denied() {
+    let target = common::echo_server().await;
+    let tunnel = common::free_addr();
+    let (listen, _) = common::start(tunnel_server(tunnel));
+    let agent = agent(tunnel, target, "guessed");
+
+    let denied = tokio::time::timeout(Duration::from_secs(5), agent.run())
+        .await
+        .expect("agent keeps retrying a denied secret");
+    assert!(denied.unwrap_err().to_string().contains("denied"));
+    let reply = common::roundtrip(listen, b"hello")
+        .await
+        .unwrap_or_default();
+    assert!(reply.is_empty());
+}
+
+#[tokio::test]
+async fn impostor_server_is_rejected() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let impostor = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let agent = agent(
+        impostor.local_addr().unwrap(),
+        target.local_addr().unwrap(),
+        "shared",
+    );
+    let running = agent.clone();
+    tokio::spawn(async move { running.run().await });
+
+    // accept whatever the agent answers, without knowing the secret
+    let (mut conn, _) = impostor.accept().await.unwrap();
+    conn.write_all(&[0x11; 32]).await.unwrap();
+    let mut answer = [0; 10 + 32 + 32];
+    conn.read_exact(&mut answer).await.unwrap();
+    let mut reply = vec![0];
+    reply.extend_from_slice(&[0x22; 32]);
+    // then ask for a stream right away
+    reply.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 1]);
+    let _ = conn.write_all(&reply).await;
+
+    let mut rest = Vec::new();
+    let _ = conn.read_to_end(&mut rest).await;
+    assert!(rest.is_empty());
+    assert!(
+        tokio::time::timeout(Duration::from_millis(500), target.accept())
+            .await
+            .is_err()
+    );
+    agent.shutdown();
+}
+
+#[test]
+fn tunnel_server_takes_no_target() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let _guard = runtime.enter();
+    let built = tunnel_server(common::free_addr())
+        .listen("127.0.0.1:0")
+        .target("127.0.0.1:80")
+        .build();
+    assert!(built.is_err());
+}
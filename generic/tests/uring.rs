@@ -0,0 +1,79 @@
+#![cfg(all(target_os = "linux", feature = "io-uring"))]
+
+mod common;
+
+use std::time::Duration;
+
+use socks5_forwarder::{Forwarder, ListenOptions, SocketOptions};
+
+#[test]
+fn uring_listen_options() {
+    let builder = |options: ListenOptions| {
+        Forwarder::builder()
+            .listen(common::free_addr().to_string())
+            .target("127.0.0.1:80")
+            .listen_options(options)
+            .build_uring()
+    };
+
+    assert!(builder(ListenOptions::default()).is_ok());
+    // listener settings tokio-uring can not apply are refused
+    let backlog = builder(ListenOptions {
+        backlog: 16,
+        ..Default::default()
+    });
+    assert!(backlog.is_err());
+    let buffers = builder(ListenOptions {
+        socket: SocketOptions {
+            recv_buffer_size: Some(1 << 20),
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    assert!(buffers.is_err());
+    let fastopen = builder(ListenOptions {
+        fastopen_queue: Some(16),
+        ..Default::default()
+    });
+    assert!(fastopen.is_err());
+}
+
+#[tokio::test]
+async fn uring_roundtrip() {
+    let echo = common::echo_server().await;
+    let count = common::count_server().await;
+    let listen = common::free_addr();
+    let count_listen = common::free_addr();
+    let start = |listen: std::net::SocketAddr, target: std::net::SocketAddr| {
+        let forwarder = Forwarder::builder()
+            .listen(listen.to_string())
+            .target(target.to_string())
+            .build_uring()
+            .unwrap();
+        let running = forwarder.clone();
+        // run blocks and must stay out of the test runtime
+        let handle = std::thread::spawn(move || running.run(2));
+        (forwarder, handle)
+    };
+    let (forwarder, handle) = start(listen, echo);
+    let (count_forwarder, count_handle) = start(count_listen, count);
+
+    let data = common::payload(1024 * 1024);
+    assert_eq!(common::roundtrip(listen, &data).await.unwrap(), data);
+    // the target only answers after it sees our EOF
+    let received = common::roundtrip(count_listen, &common::payload(100_000))
+        .await
+        .unwrap();
+    assert_eq!(received, b"100000");
+
+    for (forwarder, handle) in [(forwarder, handle), (count_forwarder, count_handle)] {
+        forwarder.shutdown();
+        let stopped = tokio::time::timeout(
+            Duration::from_secs(5),
+            tokio::task::spawn_blocking(move || handle.join().unwrap()),
+        )
+        .await
+        .expect("run returns after shutdown");
+        assert!(stopped.unwrap().is_ok());
+    }
+}